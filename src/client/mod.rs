@@ -55,6 +55,7 @@ pub async fn handle_client_connection(
                 &mut buf,
                 &mut client_state,
                 &mut local_state,
+                &mut local_replicas_state,
                 &client_addr,
             ).await 
         } else if client_state.is_replica() {
@@ -83,6 +84,12 @@ pub async fn handle_client_connection(
             }
             Err(e) => {
                 eprintln!("Client error from {}: {}", client_addr, e);
+                if let Err(e) = local_state.clear_subscriptions(&mut client_state, &client_addr) {
+                    eprintln!("Failed to clear subscriptions of {}: {}", client_addr, e);
+                }
+                if let Err(e) = local_state.unwatch(&mut client_state) {
+                    eprintln!("Failed to clear watched keys of {}: {}", client_addr, e);
+                }
                 return Err(e);
             }
         }
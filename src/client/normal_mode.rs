@@ -3,7 +3,7 @@ use std::sync::Arc;
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
 use crate::{error::{RedisError, RedisResult}, protocol::{ClientState, RedisState, RedisValue, ReplicasState}};
 use crate::utils::parse_resp;
-use crate::commands::execute_commands;
+use crate::commands::{execute_commands, execute_transaction, validate_command};
 
 pub async fn handle_normal_mode(
     stream: &mut TcpStream,
@@ -17,19 +17,31 @@ pub async fn handle_normal_mode(
         Ok(0) => Err(RedisError::ConnectionClosed),
         Ok(n) => {
             let commands = parse_resp(&buf[..n])?;
-            if client_state.is_multi_queue_mode() {
-                handle_multi_mode(stream, client_state, local_state, local_replicas_state, addr, commands).await?;
-            } else {
-                handle_non_multi_mode(stream, client_state, local_state, local_replicas_state, addr, commands).await?;
-            }
-            
-            Ok(())
+            dispatch_commands(stream, client_state, local_state, local_replicas_state, addr, commands).await
         }
 
         Err(e) => Err(RedisError::from(e)),
     }
 }
 
+// Also used by RESP3 connections with subscriptions, which can run any command
+pub(super) async fn dispatch_commands(
+    stream: &mut TcpStream,
+    client_state: &mut ClientState<Arc<str>, Arc<str>>,
+    local_state: &mut RedisState<Arc<str>, RedisValue>,
+    local_replicas_state: &mut ReplicasState,
+    addr: &Arc<str>,
+    commands: Vec<Arc<str>>,
+) -> RedisResult<()> {
+    if client_state.is_multi_queue_mode() {
+        handle_multi_mode(stream, client_state, local_state, local_replicas_state, addr, commands).await?;
+    } else {
+        handle_non_multi_mode(stream, client_state, local_state, local_replicas_state, addr, commands).await?;
+    }
+
+    Ok(())
+}
+
 async fn handle_multi_mode(
     stream: &mut TcpStream,
     client_state: &mut ClientState<Arc<str>, Arc<str>>,
@@ -38,40 +50,51 @@ async fn handle_multi_mode(
     addr: &Arc<str>,
     commands: Vec<Arc<str>>,
 ) -> RedisResult<()> {
-    match commands[0].as_ref() {
+    match commands[0].to_uppercase().as_str() {
         "EXEC" => {
-            let mut responses = Vec::new();
             match client_state.commands_len() {
+                // a command was rejected while queueing, nothing runs
+                _ if client_state.has_queue_error() => {
+                    stream.write_all(b"-EXECABORT Transaction discarded because of previous errors.\r\n").await?
+                }
+                // a WATCHed key changed since WATCH, the transaction is aborted
+                _ if client_state.is_watch_dirty() => stream.write_all(b"*-1\r\n").await?,
                 0 => stream.write_all(b"*0\r\n").await?,
                 _ => {
-                    while let Some(queued_command) = client_state.pop_command() {
-                        let response = execute_commands(
-                            stream,
-                            false,
-                            local_state,
-                            client_state,
-                            local_replicas_state,
-                            addr,
-                            &queued_command
-                        ).await?;
-                        responses.push(response);
-                    }
-
-                    let responses_array = format!("*{}\r\n{}", responses.len(), responses.join(""));
+                    let responses_array = execute_transaction(stream, local_state, client_state, local_replicas_state, addr).await?;
                     stream.write_all(responses_array.as_bytes()).await?;
                 },
             }
 
+            client_state.clear_commands();
             client_state.set_multi_queue_mode(false);
+            local_state.unwatch(client_state)?;
         },
         "DISCARD" => {
             client_state.clear_commands();
             client_state.set_multi_queue_mode(false);
+            local_state.unwatch(client_state)?;
             stream.write_all(b"+OK\r\n").await?
         }
-        _ => {
-            client_state.push_command(commands);
-            stream.write_all(b"+QUEUED\r\n").await?
+        // these act on the connection right away instead of being queued
+        "QUIT" | "RESET" => {
+            execute_commands(stream, true, local_state, client_state, local_replicas_state, addr, &commands).await?;
+        }
+        // refused inside MULTI, and like any rejected command it makes EXEC abort
+        "WATCH" => {
+            client_state.set_queue_error();
+            execute_commands(stream, true, local_state, client_state, local_replicas_state, addr, &commands).await?;
+        }
+        "MULTI" => stream.write_all(b"-ERR MULTI calls can not be nested\r\n").await?,
+        _ => match validate_command(&commands) {
+            Some(error) => {
+                client_state.set_queue_error();
+                stream.write_all(error.as_bytes()).await?
+            }
+            None => {
+                client_state.push_command(commands);
+                stream.write_all(b"+QUEUED\r\n").await?
+            }
         },
     }
 
@@ -86,7 +109,7 @@ async fn handle_non_multi_mode(
     addr: &Arc<str>,
     commands: Vec<Arc<str>>,
 ) -> RedisResult<()> {
-    match commands[0].as_ref() {
+    match commands[0].to_uppercase().as_str() {
         "EXEC" => stream.write_all(b"-ERR EXEC without MULTI\r\n").await?,
         "DISCARD" => stream.write_all(b"-ERR DISCARD without MULTI\r\n").await?,
 
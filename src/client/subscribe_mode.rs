@@ -1,23 +1,36 @@
 use std::sync::Arc;
 
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
-use crate::{error::{RedisError, RedisResult}, protocol::{ClientState, RedisState, RedisValue}, utils::encode_resp_array_str};
+use crate::{error::{RedisError, RedisResult}, protocol::{ClientState, RedisState, RedisValue, ReplicasState, encoded_frame_len}, utils::encode_resp_array_str};
 use crate::utils::{encode_resp_array_arc_with_prefix, parse_resp};
+use super::normal_mode::dispatch_commands;
 
 pub async fn handle_subscribe_mode(
     stream: &mut TcpStream,
     buf: &mut [u8; 512],
     client_state: &mut ClientState<Arc<str>, Arc<str>>,
     local_state: &mut RedisState<Arc<str>, RedisValue>,
+    local_replicas_state: &mut ReplicasState,
     addr: &Arc<str>,
 ) -> RedisResult<()> {
-    let message_literal_arc = Arc::from("message");
-    if let Some(receiver) = client_state.get_sub_receiver_mut() {
+    let output = client_state.get_sub_output();
+    let resp3 = client_state.is_resp3();
+    if let (Some(receiver), Some(output)) = (client_state.get_sub_receiver_mut(), output) {
         tokio::select! {
             msg = receiver.recv() => {
-                if let Some((channel_name_arc, message)) = msg {
-                    let response = encode_resp_array_arc_with_prefix(&[message_literal_arc, channel_name_arc], &message);
-                    stream.write_all(response.as_bytes()).await?;
+                if output.is_over_limit() {
+                    return Err(RedisError::OutputBufferLimit);
+                }
+                if let Some((kind, frame)) = msg {
+                    let mut response = encode_resp_array_arc_with_prefix(&[Arc::clone(&kind)], &frame);
+                    if resp3 {
+                        response.replace_range(..1, ">");
+                    }
+                    tokio::select! {
+                        written = stream.write_all(response.as_bytes()) => written?,
+                        _ = output.over_limit_reached() => return Err(RedisError::OutputBufferLimit),
+                    }
+                    output.release(encoded_frame_len(&kind, &frame));
                 }
                 Ok(())
             },
@@ -27,23 +40,54 @@ pub async fn handle_subscribe_mode(
                     Ok(0) => Err(RedisError::ConnectionClosed),
                     Ok(n) => {
                         let commands = parse_resp(&buf[..n])?;
+                        // RESP3 tells replies and messages apart, so nothing is restricted there
+                        if resp3 {
+                            return dispatch_commands(stream, client_state, local_state, local_replicas_state, addr, commands).await;
+                        }
                         match commands[0].to_uppercase().as_str() {
                             "SUBSCRIBE" => {
                                 let response = local_state.subscribe(client_state, addr, &commands)?;
-                                local_state.handle_subscriber(client_state, &commands).await?;
                                 stream.write_all(response.as_bytes()).await?;
                             }
                             "PING" => {
-                                let response = encode_resp_array_str(&["pong", ""]);
+                                let message = commands.get(1).map_or("", |message| message.as_ref());
+                                let response = encode_resp_array_str(&["pong", message]);
                                 stream.write_all(response.as_bytes()).await?;
                             },
                             "UNSUBSCRIBE" => {
                                 let response = local_state.unsubscribe(client_state, addr, &commands)?;
                                 stream.write_all(response.as_bytes()).await?;
                             }
+                            "PSUBSCRIBE" => {
+                                let response = local_state.psubscribe(client_state, addr, &commands)?;
+                                stream.write_all(response.as_bytes()).await?;
+                            }
+                            "PUNSUBSCRIBE" => {
+                                let response = local_state.punsubscribe(client_state, addr, &commands)?;
+                                stream.write_all(response.as_bytes()).await?;
+                            }
+                            "SSUBSCRIBE" => {
+                                let response = local_state.ssubscribe(client_state, addr, &commands)?;
+                                stream.write_all(response.as_bytes()).await?;
+                            }
+                            "SUNSUBSCRIBE" => {
+                                let response = local_state.sunsubscribe(client_state, addr, &commands)?;
+                                stream.write_all(response.as_bytes()).await?;
+                            }
+                            "QUIT" => {
+                                stream.write_all(b"+OK\r\n").await?;
+                                return Err(RedisError::ConnectionClosed);
+                            }
+                            "RESET" => {
+                                let response = local_state.reset(client_state, addr)?;
+                                stream.write_all(response.as_bytes()).await?;
+                            }
 
                             _ => {
-                                let response = format!("-ERR Can't execute '{}' in subscribed mode\r\n", commands[0].to_lowercase());
+                                let response = format!(
+                                    "-ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n",
+                                    commands[0].to_lowercase()
+                                );
                                 stream.write_all(response.as_bytes()).await?;
                             }
                         }
@@ -1,3 +1,4 @@
+use std::pin::pin;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::{io::AsyncWriteExt, net::TcpStream, sync::mpsc};
@@ -5,6 +6,9 @@ use base64::{Engine as _, engine::general_purpose};
 use crate::error::{RedisResult, RedisError};
 use crate::protocol::{ClientState, RedisState, RedisValue, ReplicasState};
 use crate::utils::{EMPTY_RDB_FILE, encode_resp_array_arc, encode_resp_array_str, encode_resp_array_str_to_arc};
+use crate::commands::scripting;
+use crate::rdb;
+use crate::commands::table::{command_flags, validate_command, BLOCKING, WRITE};
 
 pub async fn execute_commands(
     stream: &mut TcpStream,
@@ -17,7 +21,7 @@ pub async fn execute_commands(
 ) -> RedisResult<String>{
     // Check authentication - only AUTH command is allowed without authentication
     let cmd = commands[0].to_uppercase();
-    if !client_state.is_authenticated() && cmd != "AUTH" {
+    if !client_state.is_authenticated() && cmd != "AUTH" && cmd != "HELLO" {
         let response = "-NOAUTH Authentication required.\r\n".to_string();
         if write_to_stream {
             stream.write_all(response.as_bytes()).await?;
@@ -25,6 +29,188 @@ pub async fn execute_commands(
         return Ok(response);
     }
 
+    // unknown commands and wrong argument counts never reach the handlers, which index their arguments freely
+    if let Some(response) = validate_command(commands) {
+        if write_to_stream {
+            stream.write_all(response.as_bytes()).await?;
+        }
+        return Ok(response);
+    }
+
+    // past busy-reply-threshold a running script only lets SCRIPT KILL, FUNCTION KILL and SHUTDOWN NOSAVE through
+    let is_kill = (cmd == "SCRIPT" || cmd == "FUNCTION") && commands.get(1).is_some_and(|sub| sub.eq_ignore_ascii_case("KILL"));
+    let is_shutdown_nosave = cmd == "SHUTDOWN" && commands.get(1).is_some_and(|option| option.eq_ignore_ascii_case("NOSAVE"));
+    let busy = if is_kill || is_shutdown_nosave { None } else { local_state.script_state().busy_reply()? };
+    if let Some(response) = busy {
+        if write_to_stream {
+            stream.write_all(response.as_bytes()).await?;
+        }
+        return Ok(response);
+    }
+
+    // blocking commands lock per step (see RedisState::keyspace_step), holding the
+    // lock through their wait would keep EXEC, and everyone queued behind it, waiting.
+    // SCRIPT and FUNCTION only touch the script state, and KILL has to get past a running script,
+    // as does SHUTDOWN NOSAVE.
+    let keyspace_lock = Arc::clone(local_state.keyspace_lock());
+    let is_script = matches!(cmd.as_str(), "EVAL" | "EVALSHA" | "EVAL_RO" | "EVALSHA_RO" | "FCALL" | "FCALL_RO");
+    let guards = match cmd.as_str() {
+        "BLPOP" | "BZPOPMIN" | "BZPOPMAX" | "BZMPOP" | "XREAD" | "XREADGROUP" | "WAIT" | "PSYNC" | "SCRIPT" | "FUNCTION" | "SHUTDOWN" => Ok((None, None)),
+        // scripts, like EXEC, hold the keyspace exclusively until they finish
+        _ if is_script => wait_for_keyspace(local_state, keyspace_lock.write()).await?.map(|guard| (None, Some(guard))),
+        _ => wait_for_keyspace(local_state, keyspace_lock.read()).await?.map(|guard| (Some(guard), None)),
+    };
+    let (shared_guard, exclusive_guard) = match guards {
+        Ok(guards) => guards,
+        Err(response) => {
+            if write_to_stream {
+                stream.write_all(response.as_bytes()).await?;
+            }
+            return Ok(response);
+        }
+    };
+    let response = run_command(stream, write_to_stream, local_state, client_state, replicas_state, client_addr, commands).await?;
+
+    // propagated before the guard goes, a client this command woke replicates after it
+    let effects = client_state.take_effects();
+    if local_state.server_state().replication_mode() && write_to_stream {
+        match effects.as_slice() {
+            [] if is_write_command(commands) => propagate_to_replicas(replicas_state, commands).await?,
+            [] => {}
+            [effect] => propagate_to_replicas(replicas_state, effect).await?,
+            _ => {
+                propagate_to_replicas(replicas_state, &[Arc::from("MULTI")]).await?;
+                for command in &effects {
+                    propagate_to_replicas(replicas_state, command).await?;
+                }
+                propagate_to_replicas(replicas_state, &[Arc::from("EXEC")]).await?;
+            }
+        }
+    }
+    drop(shared_guard);
+    drop(exclusive_guard);
+
+    if write_to_stream && cmd != "PSYNC" {
+        stream.write_all(response.as_bytes()).await?;
+    }
+
+    if cmd == "QUIT" && write_to_stream {
+        return Err(RedisError::ConnectionClosed);
+    }
+
+    Ok(response)
+}
+
+// How often a command queued on the keyspace looks at whether a script went busy
+const BUSY_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+// Waits for a keyspace guard, or gives up with the -BUSY reply once the script
+// holding the keyspace runs past busy-reply-threshold while we wait
+async fn wait_for_keyspace<G>(local_state: &RedisState<Arc<str>, RedisValue>, acquire: impl Future<Output = G>) -> RedisResult<Result<G, String>> {
+    let mut acquire = pin!(acquire);
+    loop {
+        if let Ok(guard) = tokio::time::timeout(BUSY_CHECK_INTERVAL, &mut acquire).await {
+            return Ok(Ok(guard));
+        }
+        if let Some(response) = local_state.script_state().busy_reply()? {
+            return Ok(Err(response));
+        }
+    }
+}
+
+/// Runs the queued MULTI commands back to back while holding the keyspace
+/// exclusively, returning the array of their replies.
+pub async fn execute_transaction(
+    stream: &mut TcpStream,
+    local_state: &mut RedisState<Arc<str>, RedisValue>,
+    client_state: &mut ClientState<Arc<str>, Arc<str>>,
+    replicas_state: &mut ReplicasState,
+    client_addr: &Arc<str>,
+) -> RedisResult<String> {
+    let keyspace_lock = Arc::clone(local_state.keyspace_lock());
+    let _keyspace_guard = keyspace_lock.write().await;
+
+    client_state.set_executing(true);
+    let mut responses = Vec::new();
+    let mut writes = Vec::new();
+    while let Some(queued_command) = client_state.pop_command() {
+        let response = run_command(stream, false, local_state, client_state, replicas_state, client_addr, &queued_command).await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                client_state.set_executing(false);
+                return Err(e);
+            }
+        };
+        responses.push(response);
+        // scripts and blocking commands inside the transaction are replicated through what they wrote
+        let effects = client_state.take_effects();
+        if !effects.is_empty() {
+            writes.extend(effects);
+        } else if is_write_command(&queued_command) {
+            writes.push(queued_command);
+        }
+    }
+    client_state.set_executing(false);
+
+    // replicas get the writes wrapped in MULTI/EXEC so they apply them as one batch too
+    if local_state.server_state().replication_mode() && !writes.is_empty() {
+        propagate_to_replicas(replicas_state, &[Arc::from("MULTI")]).await?;
+        for command in &writes {
+            propagate_to_replicas(replicas_state, command).await?;
+        }
+        propagate_to_replicas(replicas_state, &[Arc::from("EXEC")]).await?;
+    }
+
+    Ok(format!("*{}\r\n{}", responses.len(), responses.join("")))
+}
+
+// Blocking writes are left out, a replica running one verbatim could wait forever,
+// they replicate through their effects instead.
+// FUNCTION changes the libraries replicas keep too, except for its read subcommands,
+// and GEORADIUS only writes with STORE or STOREDIST.
+pub(super) fn is_write_command(commands: &[Arc<str>]) -> bool {
+    let cmd = commands[0].to_uppercase();
+    let options = match cmd.as_str() {
+        "FUNCTION" => return commands.get(1).is_some_and(|sub| ["LOAD", "DELETE", "FLUSH", "RESTORE"].iter().any(|name| sub.eq_ignore_ascii_case(name))),
+        "GEORADIUS" => commands.get(6..),
+        "GEORADIUSBYMEMBER" => commands.get(5..),
+        _ => None,
+    };
+    if let Some(options) = options {
+        return options.iter().any(|option| option.eq_ignore_ascii_case("STORE") || option.eq_ignore_ascii_case("STOREDIST"));
+    }
+    command_flags(&commands[0]).is_some_and(|flags| flags & WRITE != 0 && flags & BLOCKING == 0)
+}
+
+async fn propagate_to_replicas(replicas_state: &mut ReplicasState, commands: &[Arc<str>]) -> RedisResult<()> {
+    let encoded: Arc<str> = Arc::from(encode_resp_array_arc(commands));
+    replicas_state.increment_master_write_offset(encoded.len());
+    let senders = {
+        let replica_senders_guard = replicas_state.replica_senders().lock()
+            .map_err(|_| RedisError::Other("Failed to acquire replica senders lock".to_string()))?;
+        replica_senders_guard.values().cloned().collect::<Vec<_>>()
+    };
+
+    for sender in senders{
+        sender.send(Arc::clone(&encoded)).await
+            .map_err(|e| RedisError::Other(format!("Failed to send to replica: {}", e)))?;
+    };
+    Ok(())
+}
+
+pub(super) async fn run_command(
+    stream: &mut TcpStream,
+    write_to_stream: bool,
+    local_state: &mut RedisState<Arc<str>, RedisValue>,
+    client_state: &mut ClientState<Arc<str>, Arc<str>>,
+    replicas_state: &mut ReplicasState,
+    client_addr: &Arc<str>,
+    commands: &Vec<Arc<str>>
+) -> RedisResult<String> {
+    let cmd = commands[0].to_uppercase();
+    // nothing may block inside EXEC, the transaction holds the keyspace
+    let can_block = !client_state.is_executing();
     let response = match cmd.as_str() {
         "PING" => format!("+PONG\r\n"),
         "ECHO" => format!("${}\r\n{}\r\n", &commands[1].len(), &commands[1]), // fix multiple arg will fail like hello world. check to use .join("")
@@ -48,8 +234,13 @@ pub async fn execute_commands(
             let num_connected_replicas = replicas_state.num_connected_replicas();
             if write_to_stream {
                 stream.write_all(full_sync_response.as_bytes()).await?;
-                let rdb_bytes = general_purpose::STANDARD.decode(EMPTY_RDB_FILE)
-                    .map_err(|e| RedisError::Other(format!("Failed to decode RDB: {}", e)))?;
+                // the dataset is not synced, but function libraries are
+                let functions = local_state.script_state().libraries()?.iter().map(|library| Arc::clone(&library.code)).collect::<Vec<_>>();
+                let rdb_bytes = match functions.is_empty() {
+                    true => general_purpose::STANDARD.decode(EMPTY_RDB_FILE)
+                        .map_err(|e| RedisError::Other(format!("Failed to decode RDB: {}", e)))?,
+                    false => rdb::encode_rdb(&functions, &[]),
+                };
                 let rdb_message = [format!("${}\r\n", rdb_bytes.len()).into_bytes(), rdb_bytes].concat();
                 stream.write_all(&rdb_message).await?;
             }
@@ -76,6 +267,8 @@ pub async fn execute_commands(
                 .map_err(|_| RedisError::Other("Invalid number of replicas".to_string()))?;
             let timeout_ms = commands[2].parse::<u64>()
                 .map_err(|_| RedisError::Other("Invalid timeout value".to_string()))?;
+            // inside EXEC it only reports the replicas that already acknowledged
+            let timeout_ms = if can_block { timeout_ms } else { 0 };
             
             let master_write_offset = replicas_state.get_master_write_offset();
             let get_ack_request = encode_resp_array_str_to_arc(&["REPLCONF", "GETACK", "*"]);
@@ -137,61 +330,100 @@ pub async fn execute_commands(
         "LPUSH" => local_state.lpush(&commands)?,
         "LLEN" => local_state.llen(&commands)?,
         "LPOP" => local_state.lpop(&commands)?,
-        "BLPOP" => local_state.blpop(&commands).await?,
+        "BLPOP" => local_state.blpop(client_state, commands, can_block).await?,
         "LRANGE" => local_state.lrange(&commands[1], &commands[2], &commands[3])?,
         "TYPE" => local_state.type_command(&commands)?,
         "XADD" => local_state.xadd(&commands)?,
+        "XLEN" => local_state.xlen(commands)?,
+        "XDEL" => local_state.xdel(commands)?,
+        "XTRIM" => local_state.xtrim(commands)?,
+        "XSETID" => local_state.xsetid(commands)?,
         "XRANGE" => local_state.xrange(&commands)?,
-        "XREAD" => local_state.xread(&commands).await?,
-        "SUBSCRIBE" => {
-            let count_response = local_state.subscribe(client_state, &client_addr,  &commands)?;
-            local_state.handle_subscriber(client_state, &commands).await?;
-            count_response
-        }
+        "XREVRANGE" => local_state.xrevrange(commands)?,
+        "XREAD" => local_state.xread(commands, can_block).await?,
+        "XGROUP" => local_state.xgroup(commands)?,
+        "XREADGROUP" => local_state.xreadgroup(client_state, commands, can_block).await?,
+        "XACK" => local_state.xack(commands)?,
+        "XPENDING" => local_state.xpending(commands)?,
+        "XCLAIM" => local_state.xclaim(commands)?,
+        "XAUTOCLAIM" => local_state.xautoclaim(commands)?,
+        "XINFO" => local_state.xinfo(commands)?,
+        "SUBSCRIBE" => local_state.subscribe(client_state, client_addr, commands)?,
+        "UNSUBSCRIBE" => local_state.unsubscribe(client_state, client_addr, commands)?,
+        "PUNSUBSCRIBE" => local_state.punsubscribe(client_state, client_addr, commands)?,
+        "SSUBSCRIBE" => local_state.ssubscribe(client_state, client_addr, commands)?,
+        "SUNSUBSCRIBE" => local_state.sunsubscribe(client_state, client_addr, commands)?,
+        "PSUBSCRIBE" => local_state.psubscribe(client_state, client_addr, commands)?,
         "PUBLISH" => local_state.publish(&commands)?,
+        "SPUBLISH" => local_state.spublish(commands)?,
+        "PUBSUB" => local_state.pubsub(commands)?,
         "INCR" => local_state.incr(&commands)?,
         "MULTI" => local_state.multi(client_state)?,
+        "WATCH" => local_state.watch(client_state, commands)?,
+        "UNWATCH" => local_state.unwatch(client_state)?,
+        "FLUSHALL" | "FLUSHDB" => local_state.flushall(commands)?,
         "INFO" => local_state.info(&commands)?,
-        "ZADD" => local_state.zadd(&commands)?,
+        "ZADD" => local_state.zadd(commands)?,
+        "ZINCRBY" => local_state.zincrby(commands)?,
         "ZRANK" => local_state.zrank(&commands)?,
+        "ZREVRANK" => local_state.zrevrank(commands)?,
         "ZRANGE" => local_state.zrange(&commands)?,
+        "ZREVRANGE" => local_state.zrevrange(commands)?,
+        "ZRANGEBYSCORE" => local_state.zrangebyscore(commands)?,
+        "ZREVRANGEBYSCORE" => local_state.zrevrangebyscore(commands)?,
+        "ZRANGEBYLEX" => local_state.zrangebylex(commands)?,
+        "ZREVRANGEBYLEX" => local_state.zrevrangebylex(commands)?,
+        "ZRANGESTORE" => local_state.zrangestore(commands)?,
         "ZCARD" => local_state.zcard(&commands)?,
         "ZSCORE" => local_state.zscore(&commands)?,
-        "ZREM" => local_state.zrem(&commands)?,
-        "GEOADD" => local_state.geoadd(&commands)?,
+        "ZMSCORE" => local_state.zmscore(commands)?,
+        "ZREM" => local_state.zrem(commands)?,
+        "ZCOUNT" => local_state.zcount(commands)?,
+        "ZLEXCOUNT" => local_state.zlexcount(commands)?,
+        "ZRANDMEMBER" => local_state.zrandmember(commands)?,
+        "ZREMRANGEBYRANK" => local_state.zremrangebyrank(commands)?,
+        "ZREMRANGEBYSCORE" => local_state.zremrangebyscore(commands)?,
+        "ZREMRANGEBYLEX" => local_state.zremrangebylex(commands)?,
+        "ZUNIONSTORE" => local_state.zunionstore(commands)?,
+        "ZINTERSTORE" => local_state.zinterstore(commands)?,
+        "ZDIFFSTORE" => local_state.zdiffstore(commands)?,
+        "ZUNION" => local_state.zunion(commands)?,
+        "ZINTER" => local_state.zinter(commands)?,
+        "ZDIFF" => local_state.zdiff(commands)?,
+        "ZINTERCARD" => local_state.zintercard(commands)?,
+        "ZPOPMIN" => local_state.zpopmin(commands)?,
+        "ZPOPMAX" => local_state.zpopmax(commands)?,
+        "ZMPOP" => local_state.zmpop(commands)?,
+        "BZPOPMIN" => local_state.bzpopmin(client_state, commands, can_block).await?,
+        "BZPOPMAX" => local_state.bzpopmax(client_state, commands, can_block).await?,
+        "BZMPOP" => local_state.bzmpop(client_state, commands, can_block).await?,
+        "GEOADD" => local_state.geoadd(commands)?,
         "GEOPOS" => local_state.geopos(&commands)?,
-        "GEODIST" => local_state.geodist(&commands)?,
-        "GEOSEARCH" => local_state.geosearch(&commands)?,
+        "GEODIST" => local_state.geodist(commands)?,
+        "GEOHASH" => local_state.geohash(commands)?,
+        "GEOSEARCH" => local_state.geosearch(commands)?,
+        "GEOSEARCHSTORE" => local_state.geosearchstore(commands)?,
+        "GEORADIUS" => local_state.georadius(commands)?,
+        "GEORADIUSBYMEMBER" => local_state.georadiusbymember(commands)?,
         "ACL" => local_state.acl(&commands)?,
         "AUTH" => local_state.auth(client_state, &commands)?,
+        "HELLO" => local_state.hello(client_state, commands)?,
+        "QUIT" => "+OK\r\n".to_string(),
+        "RESET" => local_state.reset(client_state, client_addr)?,
         "CONFIG" => local_state.config(&commands)?,
         "KEYS" => local_state.keys(&commands)?,
+        "EVAL" | "EVALSHA" | "EVAL_RO" | "EVALSHA_RO" => tokio::task::block_in_place(|| {
+            scripting::eval(stream, local_state, client_state, replicas_state, client_addr, commands)
+        })?,
+        "SCRIPT" => scripting::script(local_state, commands)?,
+        "FCALL" | "FCALL_RO" => tokio::task::block_in_place(|| {
+            scripting::fcall(stream, local_state, client_state, replicas_state, client_addr, commands)
+        })?,
+        "FUNCTION" => scripting::function(local_state, commands)?,
+        "SAVE" => local_state.save()?,
+        "SHUTDOWN" => local_state.shutdown(commands).await?,
         _ => format!("$-1\r\n"), //todo fix
     };
 
-    if write_to_stream && commands[0].to_uppercase().as_str() != "PSYNC" {
-        stream.write_all(response.as_bytes()).await?;
-    }
-
-    let is_write_command = matches!(
-        commands[0].to_uppercase().as_str(),
-        "SET" | "DEL" | "RPUSH" | "LPUSH" | "LPOP" | "XADD" | "INCR"
-    );
-
-    if local_state.server_state().replication_mode() && is_write_command && write_to_stream {
-        let encoded: Arc<str> = Arc::from(encode_resp_array_arc(commands));
-        replicas_state.increment_master_write_offset(encoded.len());
-        let senders = {
-            let replica_senders_guard = replicas_state.replica_senders().lock()
-                .map_err(|_| RedisError::Other("Failed to acquire replica senders lock".to_string()))?;
-            replica_senders_guard.values().cloned().collect::<Vec<_>>()
-        };
-
-        for sender in senders{
-            sender.send(Arc::clone(&encoded)).await
-                .map_err(|e| RedisError::Other(format!("Failed to send to replica: {}", e)))?;
-        };
-    }
-
     Ok(response)
-}
\ No newline at end of file
+}
@@ -1,3 +1,7 @@
 mod handler;
+mod scripting;
+mod table;
 
-pub use handler::execute_commands;
+pub use handler::{execute_commands, execute_transaction};
+pub use scripting::load_libraries;
+pub use table::validate_command;
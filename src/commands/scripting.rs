@@ -0,0 +1,726 @@
+use std::{cell::RefCell, future::Future, pin::pin, sync::Arc, task::{Context, Poll, Waker}, time::{Duration, Instant}};
+use base64::{Engine as _, engine::general_purpose};
+use mlua::{Function, HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value, Variadic};
+use sha1::{Digest, Sha1};
+use tokio::net::TcpStream;
+use crate::commands::handler::{is_write_command, run_command};
+use crate::commands::table::{command_flags, validate_command, NOSCRIPT, WRITE};
+use crate::error::RedisResult;
+use crate::protocol::{ClientState, FunctionLibrary, LibraryFunction, RedisState, RedisValue, ReplicasState, RestorePolicy, RunningScript};
+use crate::rdb::{decode_functions, encode_functions};
+use crate::utils::glob_match;
+
+// redis.call is redis.pcall raising the error reply instead of returning it
+const REDIS_CALL: &str = r#"
+redis.call = function(...)
+    local reply = redis.pcall(...)
+    if type(reply) == 'table' and reply.err then
+        error(reply)
+    end
+    return reply
+end
+"#;
+
+// Scripts can read globals but neither create them nor touch missing ones
+const PROTECT_GLOBALS: &str = r#"
+setmetatable(_G, {
+    __index = function(_, name)
+        error("Script attempted to access nonexistent global variable '" .. tostring(name) .. "'", 2)
+    end,
+    __newindex = function(_, name)
+        error("Attempt to modify a readonly table", 2)
+    end,
+})
+"#;
+
+// How often, in VM instructions, a running script checks for SCRIPT KILL
+const KILL_CHECK_INSTRUCTIONS: u32 = 10_000;
+
+// Loading a library only registers functions, so it gets a short fixed budget
+const LIBRARY_LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Registry table redis.register_function fills while a library loads
+const REGISTERED_FUNCTIONS: &str = "registered_functions";
+
+const FUNCTION_FLAGS: [&str; 5] = ["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+// The connection a script runs on behalf of, shared by redis.call and redis.pcall
+struct ScriptCaller<'a> {
+    stream: &'a mut TcpStream,
+    local_state: &'a mut RedisState<Arc<str>, RedisValue>,
+    client_state: &'a mut ClientState<Arc<str>, Arc<str>>,
+    replicas_state: &'a mut ReplicasState,
+    client_addr: &'a Arc<str>,
+    read_only: bool,
+}
+
+// What a `#!lua` first line asks for, scripts without one may write
+#[derive(Default)]
+struct ScriptFlags {
+    no_writes: bool,
+}
+
+/// EVAL, EVALSHA and their _RO variants. The caller holds the keyspace exclusively.
+pub(super) fn eval(
+    stream: &mut TcpStream,
+    local_state: &mut RedisState<Arc<str>, RedisValue>,
+    client_state: &mut ClientState<Arc<str>, Arc<str>>,
+    replicas_state: &mut ReplicasState,
+    client_addr: &Arc<str>,
+    commands: &[Arc<str>],
+) -> RedisResult<String> {
+    let cmd = commands[0].to_uppercase();
+    let body = match cmd.as_str() {
+        "EVALSHA" | "EVALSHA_RO" => match local_state.script_state().get_script(&commands[1].to_lowercase())? {
+            Some(body) => body,
+            None => return Ok("-NOSCRIPT No matching script. Please use EVAL.\r\n".to_string()),
+        },
+        _ => Arc::clone(&commands[1]),
+    };
+    let (keys, args) = match split_keys(&commands[2..]) {
+        Ok(split) => split,
+        Err(reply) => return Ok(reply),
+    };
+    let (flags, code) = match parse_shebang(&body) {
+        Ok(parsed) => parsed,
+        Err(reply) => return Ok(reply),
+    };
+
+    let sha: Arc<str> = Arc::from(sha1hex(body.as_bytes()));
+    let lua = new_vm()?;
+    let function = match lua.load(code).set_name("@user_script").into_function() {
+        Ok(function) => function,
+        Err(e) => return Ok(compile_error(&e)),
+    };
+    local_state.script_state().cache_script(Arc::clone(&sha), body)?;
+
+    let caller = ScriptCaller {
+        stream,
+        local_state,
+        client_state,
+        replicas_state,
+        client_addr,
+        read_only: flags.no_writes || cmd.ends_with("_RO"),
+    };
+    run_script(&lua, function, caller, keys, args, ScriptKind::Eval(&sha))
+}
+
+/// FCALL and FCALL_RO, running a library function in a fresh VM with the library
+/// loaded again. The caller holds the keyspace exclusively.
+pub(super) fn fcall(
+    stream: &mut TcpStream,
+    local_state: &mut RedisState<Arc<str>, RedisValue>,
+    client_state: &mut ClientState<Arc<str>, Arc<str>>,
+    replicas_state: &mut ReplicasState,
+    client_addr: &Arc<str>,
+    commands: &[Arc<str>],
+) -> RedisResult<String> {
+    let Some((library, function)) = local_state.script_state().find_function(&commands[1])? else {
+        return Ok("-ERR Function not found\r\n".to_string());
+    };
+    let (keys, args) = match split_keys(&commands[2..]) {
+        Ok(split) => split,
+        Err(reply) => return Ok(reply),
+    };
+    let read_only = commands[0].eq_ignore_ascii_case("FCALL_RO");
+    if read_only && !function.has_flag("no-writes") {
+        return Ok("-ERR Can not execute a script with write flag using *_ro command.\r\n".to_string());
+    }
+
+    let lua = match run_library(&library.code) {
+        Ok((lua, _)) => lua,
+        Err(reply) => return Ok(reply),
+    };
+    let callback: Function = lua.named_registry_value::<Table>(REGISTERED_FUNCTIONS)?
+        .get::<_, Table>(function.name.as_ref())?
+        .get("callback")?;
+    let caller = ScriptCaller {
+        stream,
+        local_state,
+        client_state,
+        replicas_state,
+        client_addr,
+        read_only: read_only || function.has_flag("no-writes"),
+    };
+    run_script(&lua, callback, caller, keys, args, ScriptKind::Function(&function.name))
+}
+
+/// SCRIPT LOAD, EXISTS, FLUSH and KILL.
+pub(super) fn script(local_state: &RedisState<Arc<str>, RedisValue>, commands: &[Arc<str>]) -> RedisResult<String> {
+    let scripts = local_state.script_state();
+    match commands[1].to_uppercase().as_str() {
+        "LOAD" if commands.len() == 3 => {
+            let code = match parse_shebang(&commands[2]) {
+                Ok((_, code)) => code,
+                Err(reply) => return Ok(reply),
+            };
+            if let Err(e) = new_vm()?.load(code).set_name("@user_script").into_function() {
+                return Ok(compile_error(&e));
+            }
+            let sha = sha1hex(commands[2].as_bytes());
+            scripts.cache_script(Arc::from(sha.as_str()), Arc::clone(&commands[2]))?;
+            Ok(format!("${}\r\n{}\r\n", sha.len(), sha))
+        }
+        "EXISTS" if commands.len() > 2 => {
+            let mut response = format!("*{}\r\n", commands.len() - 2);
+            for sha in &commands[2..] {
+                let exists = scripts.get_script(&sha.to_lowercase())?.is_some();
+                response.push_str(if exists { ":1\r\n" } else { ":0\r\n" });
+            }
+            Ok(response)
+        }
+        "FLUSH" if commands.len() <= 3 => {
+            if commands.len() == 3 && !commands[2].eq_ignore_ascii_case("ASYNC") && !commands[2].eq_ignore_ascii_case("SYNC") {
+                return Ok("-ERR SCRIPT FLUSH only support SYNC|ASYNC option\r\n".to_string());
+            }
+            scripts.flush_scripts()?;
+            Ok("+OK\r\n".to_string())
+        }
+        "KILL" if commands.len() == 2 => scripts.kill(false),
+        _ => Ok(format!("-ERR unknown subcommand or wrong number of arguments for '{}'. Try SCRIPT HELP.\r\n", commands[1])),
+    }
+}
+
+/// FUNCTION LOAD, DELETE, FLUSH, LIST, DUMP, RESTORE and KILL.
+pub(super) fn function(local_state: &RedisState<Arc<str>, RedisValue>, commands: &[Arc<str>]) -> RedisResult<String> {
+    let scripts = local_state.script_state();
+    match commands[1].to_uppercase().as_str() {
+        "LOAD" if commands.len() == 3 || commands.len() == 4 => {
+            let policy = match commands.len() {
+                4 if commands[2].eq_ignore_ascii_case("REPLACE") => RestorePolicy::Replace,
+                4 => return Ok(format!("-ERR Unknown option given: {}\r\n", commands[2])),
+                _ => RestorePolicy::Append,
+            };
+            let library = match run_library(&commands[commands.len() - 1]) {
+                Ok((_, library)) => library,
+                Err(reply) => return Ok(reply),
+            };
+            let name = Arc::clone(&library.name);
+            match scripts.add_libraries(vec![library], policy)? {
+                Some(reply) => Ok(reply),
+                None => Ok(format!("${}\r\n{}\r\n", name.len(), name)),
+            }
+        }
+        "DELETE" if commands.len() == 3 => match scripts.delete_library(&commands[2])? {
+            true => Ok("+OK\r\n".to_string()),
+            false => Ok("-ERR Library not found\r\n".to_string()),
+        },
+        "FLUSH" if commands.len() <= 3 => {
+            if commands.len() == 3 && !commands[2].eq_ignore_ascii_case("ASYNC") && !commands[2].eq_ignore_ascii_case("SYNC") {
+                return Ok("-ERR FUNCTION FLUSH only supports SYNC|ASYNC option\r\n".to_string());
+            }
+            scripts.flush_libraries()?;
+            Ok("+OK\r\n".to_string())
+        }
+        "LIST" => {
+            let mut pattern = None;
+            let mut with_code = false;
+            let mut options = commands[2..].iter();
+            while let Some(option) = options.next() {
+                match option.to_uppercase().as_str() {
+                    "WITHCODE" => with_code = true,
+                    "LIBRARYNAME" => match options.next() {
+                        Some(name) => pattern = Some(name),
+                        None => return Ok("-ERR library name argument was not given\r\n".to_string()),
+                    },
+                    _ => return Ok(format!("-ERR Unknown argument {}\r\n", option)),
+                }
+            }
+            let libraries = scripts.libraries()?.into_iter()
+                .filter(|library| pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), library.name.as_bytes(), false)))
+                .collect::<Vec<_>>();
+            let mut response = format!("*{}\r\n", libraries.len());
+            for library in libraries {
+                response.push_str(&list_library(&library, with_code));
+            }
+            Ok(response)
+        }
+        "DUMP" if commands.len() == 2 => {
+            let functions = scripts.libraries()?.iter().map(|library| Arc::clone(&library.code)).collect::<Vec<_>>();
+            let payload = general_purpose::STANDARD.encode(encode_functions(&functions));
+            Ok(format!("${}\r\n{}\r\n", payload.len(), payload))
+        }
+        "RESTORE" if commands.len() == 3 || commands.len() == 4 => {
+            let policy = match commands.get(3).map(|policy| policy.to_uppercase()).as_deref() {
+                None | Some("APPEND") => RestorePolicy::Append,
+                Some("REPLACE") => RestorePolicy::Replace,
+                Some("FLUSH") => RestorePolicy::Flush,
+                Some(_) => return Ok("-ERR Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.\r\n".to_string()),
+            };
+            let payload = general_purpose::STANDARD.decode(commands[2].as_bytes()).ok();
+            let Some(codes) = payload.and_then(|payload| decode_functions(&payload).ok()) else {
+                return Ok("-ERR payload version or checksum are wrong\r\n".to_string());
+            };
+            load_libraries(local_state, &codes, policy).map(|error| error.unwrap_or_else(|| "+OK\r\n".to_string()))
+        }
+        "KILL" if commands.len() == 2 => scripts.kill(true),
+        _ => Ok(format!("-ERR unknown subcommand or wrong number of arguments for '{}'. Try FUNCTION HELP.\r\n", commands[1])),
+    }
+}
+
+/// Loads library code read from an RDB file or a FUNCTION RESTORE payload,
+/// returning the error reply if any library fails or clashes.
+pub fn load_libraries(local_state: &RedisState<Arc<str>, RedisValue>, codes: &[Arc<str>], policy: RestorePolicy) -> RedisResult<Option<String>> {
+    let mut libraries = Vec::with_capacity(codes.len());
+    for code in codes {
+        match run_library(code) {
+            Ok((_, library)) => libraries.push(library),
+            Err(reply) => return Ok(Some(reply)),
+        }
+    }
+    local_state.script_state().add_libraries(libraries, policy)
+}
+
+// One FUNCTION LIST entry
+fn list_library(library: &FunctionLibrary, with_code: bool) -> String {
+    let mut response = format!("*{}\r\n", if with_code { 8 } else { 6 });
+    response.push_str(&format!("$12\r\nlibrary_name\r\n${}\r\n{}\r\n", library.name.len(), library.name));
+    response.push_str("$6\r\nengine\r\n$3\r\nLUA\r\n");
+    response.push_str(&format!("$9\r\nfunctions\r\n*{}\r\n", library.functions.len()));
+    for function in &library.functions {
+        response.push_str(&format!("*6\r\n$4\r\nname\r\n${}\r\n{}\r\n$11\r\ndescription\r\n", function.name.len(), function.name));
+        match &function.description {
+            Some(description) => response.push_str(&format!("${}\r\n{}\r\n", description.len(), description)),
+            None => response.push_str("$-1\r\n"),
+        }
+        response.push_str(&format!("$5\r\nflags\r\n*{}\r\n", function.flags.len()));
+        for flag in &function.flags {
+            response.push_str(&format!("${}\r\n{}\r\n", flag.len(), flag));
+        }
+    }
+    if with_code {
+        response.push_str(&format!("$12\r\nlibrary_code\r\n${}\r\n{}\r\n", library.code.len(), library.code));
+    }
+    response
+}
+
+// What run_script runs, an EVAL script by SHA1 or a library function by name
+enum ScriptKind<'a> {
+    Eval(&'a str),
+    Function(&'a str),
+}
+
+// Runs a compiled script, with KEYS and ARGV as globals for EVAL and as the
+// arguments of a function, turning its result into a reply
+fn run_script(lua: &Lua, function: Function, caller: ScriptCaller, keys: &[Arc<str>], args: &[Arc<str>], kind: ScriptKind) -> RedisResult<String> {
+    let is_function = matches!(kind, ScriptKind::Function(_));
+    let killed_message = match is_function {
+        true => "Script killed by user with FUNCTION KILL...",
+        false => "Script killed by user with SCRIPT KILL...",
+    };
+    let running = caller.local_state.script_state().start_running(is_function)?;
+    let hook_running = Arc::clone(&running);
+    lua.set_hook(HookTriggers::new().every_nth_instruction(KILL_CHECK_INSTRUCTIONS), move |_, _| {
+        // raised again on every check, so a pcall inside the script cannot swallow it
+        match hook_running.is_killed() {
+            true => Err(mlua::Error::runtime(killed_message)),
+            false => Ok(()),
+        }
+    });
+
+    // commands run from the script must never block
+    let was_executing = caller.client_state.is_executing();
+    caller.client_state.set_executing(true);
+    let caller = RefCell::new(caller);
+    let result = lua.scope(|scope| {
+        let redis: Table = lua.globals().get("redis")?;
+        redis.set("pcall", scope.create_function(|lua, args: MultiValue| redis_pcall(lua, &caller, &running, args))?)?;
+        let keys = keys.iter().map(|key| key.as_ref()).collect::<Vec<_>>();
+        let args = args.iter().map(|arg| arg.as_ref()).collect::<Vec<_>>();
+
+        let pcall: Function = lua.globals().get("pcall")?;
+        let results = match kind {
+            ScriptKind::Eval(_) => {
+                lua.globals().set("KEYS", keys)?;
+                lua.globals().set("ARGV", args)?;
+                lua.load(PROTECT_GLOBALS).exec()?;
+                pcall.call::<_, MultiValue>(function)?
+            }
+            ScriptKind::Function(_) => pcall.call::<_, MultiValue>((function, keys, args))?,
+        };
+        let mut results = results.into_iter();
+        let ok = matches!(results.next(), Some(Value::Boolean(true)));
+        let value = results.next().unwrap_or(Value::Nil);
+        match (ok, &kind) {
+            (true, _) => to_resp(&value),
+            (false, ScriptKind::Eval(sha)) => Ok(error_to_resp(&value, &format!("script: {}", sha))),
+            (false, ScriptKind::Function(name)) => Ok(error_to_resp(&value, &format!("script: {}, on @user_function.", name))),
+        }
+    });
+
+    let caller = caller.into_inner();
+    caller.client_state.set_executing(was_executing);
+    caller.local_state.script_state().finish_running()?;
+    if running.is_killed() {
+        return Ok(format!("-ERR {}\r\n", killed_message));
+    }
+    Ok(result?)
+}
+
+// redis.pcall, runs one command as the calling client and returns its reply as Lua values
+fn redis_pcall<'lua>(lua: &'lua Lua, caller: &RefCell<ScriptCaller>, running: &RunningScript, args: MultiValue<'lua>) -> mlua::Result<Value<'lua>> {
+    let mut command = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg {
+            Value::String(arg) => command.push(Arc::from(arg.to_str()?)),
+            Value::Integer(arg) => command.push(Arc::from(arg.to_string())),
+            Value::Number(arg) => command.push(Arc::from(format_number(arg))),
+            _ => return reply_table(lua, "err", "ERR Lua redis lib command arguments must be strings or integers"),
+        }
+    }
+    if command.is_empty() {
+        return reply_table(lua, "err", "ERR Please specify at least one argument for this redis lib call");
+    }
+
+    let Some(flags) = command_flags(&command[0]) else {
+        return reply_table(lua, "err", "ERR Unknown Redis command called from script");
+    };
+    if flags & NOSCRIPT != 0 {
+        return reply_table(lua, "err", "ERR This Redis command is not allowed from script");
+    }
+    if validate_command(&command).is_some() {
+        return reply_table(lua, "err", "ERR Wrong number of args calling Redis command from script");
+    }
+
+    let mut caller = caller.borrow_mut();
+    let caller = &mut *caller;
+    if flags & WRITE != 0 && caller.read_only {
+        return reply_table(lua, "err", "ERR Write commands are not allowed from read-only scripts.");
+    }
+    let reply = match poll_once(run_command(caller.stream, false, caller.local_state, caller.client_state, caller.replicas_state, caller.client_addr, &command)) {
+        Some(Ok(reply)) => reply,
+        Some(Err(e)) => return reply_table(lua, "err", &format!("ERR {}", e)),
+        None => return reply_table(lua, "err", "ERR This Redis command is not allowed from script"),
+    };
+
+    // blocking commands push what they did themselves, replicas could wait on them forever
+    if flags & WRITE != 0 && !reply.starts_with('-') {
+        running.set_written();
+        if is_write_command(&command) {
+            caller.client_state.push_effect(command);
+        }
+    }
+    parse_reply(lua, reply.as_bytes(), &mut 0)
+}
+
+// A fresh interpreter with the redis library, so scripts never see each other's state
+fn new_vm() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::default())?;
+    let globals = lua.globals();
+    for unsafe_function in ["loadfile", "dofile"] {
+        globals.raw_set(unsafe_function, Value::Nil)?;
+    }
+
+    let redis = lua.create_table()?;
+    redis.set("sha1hex", lua.create_function(|_, data: mlua::String| Ok(sha1hex(data.as_bytes())))?)?;
+    redis.set("error_reply", lua.create_function(|lua, message: String| reply_table(lua, "err", &message))?)?;
+    redis.set("status_reply", lua.create_function(|lua, message: String| reply_table(lua, "ok", &message))?)?;
+    redis.set("log", lua.create_function(|_, (level, message): (i64, Variadic<String>)| {
+        if !(0..=3).contains(&level) {
+            return Err(mlua::Error::runtime("Invalid log level"));
+        }
+        println!("[script] {}", message.join(" "));
+        Ok(())
+    })?)?;
+    for (name, level) in [("LOG_DEBUG", 0), ("LOG_VERBOSE", 1), ("LOG_NOTICE", 2), ("LOG_WARNING", 3)] {
+        redis.set(name, level)?;
+    }
+    globals.set("redis", redis)?;
+    lua.load(REDIS_CALL).exec()?;
+    drop(globals);
+    Ok(lua)
+}
+
+// The KEYS and ARGV of a script call
+type KeysAndArgs<'a> = (&'a [Arc<str>], &'a [Arc<str>]);
+
+// Splits `numkeys key... arg...`, or the error reply for a bad numkeys
+fn split_keys(args: &[Arc<str>]) -> Result<KeysAndArgs<'_>, String> {
+    let numkeys = args[0].parse::<i64>().map_err(|_| "-ERR value is not an integer or out of range\r\n".to_string())?;
+    if numkeys < 0 {
+        return Err("-ERR Number of keys can't be negative\r\n".to_string());
+    }
+    if numkeys as usize > args.len() - 1 {
+        return Err("-ERR Number of keys can't be greater than number of args\r\n".to_string());
+    }
+    Ok(args[1..].split_at(numkeys as usize))
+}
+
+// Strips a `#!lua flags=...` first line, keeping the newline so line numbers still match
+fn parse_shebang(body: &str) -> Result<(ScriptFlags, &str), String> {
+    let mut flags = ScriptFlags::default();
+    if !body.starts_with("#!") {
+        return Ok((flags, body));
+    }
+    let line_end = body.find('\n').unwrap_or(body.len());
+    let mut parts = body[2..line_end].split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if engine != "lua" {
+        return Err(format!("-ERR Unexpected engine in script shebang: {}\r\n", engine));
+    }
+    for part in parts {
+        let Some(names) = part.strip_prefix("flags=") else {
+            return Err(format!("-ERR Unknown lua shebang option: {}\r\n", part));
+        };
+        for name in names.split(',').filter(|name| !name.is_empty()) {
+            match name {
+                "no-writes" => flags.no_writes = true,
+                "allow-oom" | "allow-stale" | "no-cluster" | "allow-cross-slot-keys" => {}
+                _ => return Err(format!("-ERR Unexpected flag in script shebang: {}\r\n", name)),
+            }
+        }
+    }
+    Ok((flags, &body[line_end..]))
+}
+
+// Reads the `#!lua name=<library>` first line every library starts with,
+// returning the name and the code with the newline kept
+fn parse_library_metadata(code: &str) -> Result<(Arc<str>, &str), String> {
+    let Some(metadata) = code.strip_prefix("#!") else {
+        return Err("-ERR Missing library metadata\r\n".to_string());
+    };
+    let line_end = metadata.find('\n').unwrap_or(metadata.len());
+    let mut parts = metadata[..line_end].split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("-ERR Engine '{}' not found\r\n", engine));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value),
+            None => return Err(format!("-ERR Invalid metadata value given: {}\r\n", part)),
+        }
+    }
+    let Some(name) = name else {
+        return Err("-ERR Library name was not given\r\n".to_string());
+    };
+    if !is_valid_name(name) {
+        return Err("-ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long\r\n".to_string());
+    }
+    Ok((Arc::from(name), &metadata[line_end..]))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Runs library code in a fresh VM, collecting what it registered. The VM keeps
+// the callbacks for FCALL; errors come back as the reply to send.
+fn run_library(code: &Arc<str>) -> Result<(Lua, FunctionLibrary), String> {
+    let (name, body) = parse_library_metadata(code)?;
+    let internal_error = |e: mlua::Error| format!("-ERR {}\r\n", single_line(&error_message(&e)));
+    let lua = new_vm().map_err(internal_error)?;
+    let functions = {
+        let function = lua.load(body).set_name("@user_function").into_function()
+            .map_err(|e| format!("-ERR Error compiling function: {}\r\n", single_line(&error_message(&e))))?;
+
+        let registered = lua.create_table().map_err(internal_error)?;
+        lua.set_named_registry_value(REGISTERED_FUNCTIONS, registered.clone()).map_err(internal_error)?;
+        let redis: Table = lua.globals().get("redis").map_err(internal_error)?;
+        redis.set("register_function", lua.create_function(register_function).map_err(internal_error)?).map_err(internal_error)?;
+        let started = Instant::now();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(KILL_CHECK_INSTRUCTIONS), move |_, _| {
+            match started.elapsed() > LIBRARY_LOAD_TIMEOUT {
+                true => Err(mlua::Error::runtime("FUNCTION LOAD timeout")),
+                false => Ok(()),
+            }
+        });
+        lua.load(PROTECT_GLOBALS).exec().map_err(internal_error)?;
+        function.call::<_, ()>(())
+            .map_err(|e| format!("-ERR Error registering functions: {}\r\n", single_line(&error_message(&e))))?;
+        lua.remove_hook();
+        // functions only register while their library loads
+        redis.set("register_function", Value::Nil).map_err(internal_error)?;
+
+        let mut functions = Vec::new();
+        for pair in registered.clone().pairs::<String, Table>() {
+            let (name, entry) = pair.map_err(internal_error)?;
+            let description = entry.get::<_, Option<String>>("description").map_err(internal_error)?;
+            let flags = entry.get::<_, Vec<String>>("flags").map_err(internal_error)?;
+            functions.push(LibraryFunction {
+                name: Arc::from(name),
+                description: description.map(Arc::from),
+                flags: flags.into_iter().map(Arc::from).collect(),
+            });
+        }
+        if functions.is_empty() {
+            return Err("-ERR No functions registered\r\n".to_string());
+        }
+        functions.sort_by(|a, b| a.name.cmp(&b.name));
+        functions
+    };
+    Ok((lua, FunctionLibrary { name, code: Arc::clone(code), functions }))
+}
+
+// redis.register_function, either (name, callback) or a table with
+// function_name, callback, flags and description
+fn register_function<'lua>(lua: &'lua Lua, args: MultiValue<'lua>) -> mlua::Result<()> {
+    let mut args = args.into_iter();
+    let (name, callback, flags, description) = match (args.next(), args.next(), args.next()) {
+        (Some(Value::String(name)), Some(Value::Function(callback)), None) => (name.to_str()?.to_string(), callback, Vec::new(), None),
+        (Some(Value::Table(options)), None, None) => {
+            let (mut name, mut callback, mut flags, mut description) = (None, None, Vec::new(), None);
+            for pair in options.pairs::<String, Value>() {
+                match pair? {
+                    (key, Value::String(value)) if key == "function_name" => name = Some(value.to_str()?.to_string()),
+                    (key, Value::Function(value)) if key == "callback" => callback = Some(value),
+                    (key, Value::Table(value)) if key == "flags" => flags = value.sequence_values::<String>().collect::<mlua::Result<Vec<_>>>()?,
+                    (key, Value::String(value)) if key == "description" => description = Some(value.to_str()?.to_string()),
+                    _ => return Err(mlua::Error::runtime("unknown argument given to redis.register_function")),
+                }
+            }
+            let name = name.ok_or_else(|| mlua::Error::runtime("redis.register_function must get a function name argument"))?;
+            let callback = callback.ok_or_else(|| mlua::Error::runtime("redis.register_function must get a callback argument"))?;
+            (name, callback, flags, description)
+        }
+        _ => return Err(mlua::Error::runtime("wrong number of arguments to redis.register_function")),
+    };
+
+    if !is_valid_name(&name) {
+        return Err(mlua::Error::runtime("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"));
+    }
+    if let Some(flag) = flags.iter().find(|flag| !FUNCTION_FLAGS.contains(&flag.as_str())) {
+        return Err(mlua::Error::runtime(format!("unknown flag given: {}", flag)));
+    }
+    let registered: Table = lua.named_registry_value(REGISTERED_FUNCTIONS)?;
+    if registered.contains_key(name.as_str())? {
+        return Err(mlua::Error::runtime("Function already exists in the library"));
+    }
+    let entry = lua.create_table()?;
+    entry.set("callback", callback)?;
+    entry.set("flags", flags)?;
+    entry.set("description", description)?;
+    registered.set(name, entry)
+}
+
+// Converts a command reply into what redis.call returns: status and error replies
+// become {ok=...} and {err=...}, nil becomes false
+fn parse_reply<'lua>(lua: &'lua Lua, reply: &[u8], pos: &mut usize) -> mlua::Result<Value<'lua>> {
+    let line_end = reply[*pos..].windows(2).position(|window| window == b"\r\n")
+        .map(|offset| *pos + offset)
+        .ok_or_else(|| mlua::Error::runtime("ERR Protocol error in command reply"))?;
+    let kind = reply[*pos];
+    let line = std::str::from_utf8(&reply[*pos + 1..line_end]).map_err(mlua::Error::external)?;
+    *pos = line_end + 2;
+
+    match kind {
+        b'+' => reply_table(lua, "ok", line),
+        b'-' => reply_table(lua, "err", line),
+        b':' => Ok(Value::Integer(line.parse().map_err(mlua::Error::external)?)),
+        b'$' => {
+            let Ok(len) = line.parse::<usize>() else {
+                return Ok(Value::Boolean(false));
+            };
+            let data = lua.create_string(&reply[*pos..*pos + len])?;
+            *pos += len + 2;
+            Ok(Value::String(data))
+        }
+        b'*' => {
+            let Ok(len) = line.parse::<usize>() else {
+                return Ok(Value::Boolean(false));
+            };
+            let table = lua.create_table_with_capacity(len, 0)?;
+            for i in 1..=len {
+                table.raw_set(i, parse_reply(lua, reply, pos)?)?;
+            }
+            Ok(Value::Table(table))
+        }
+        _ => Err(mlua::Error::runtime("ERR Unsupported reply type in script")),
+    }
+}
+
+// Converts what a script returned into a reply
+fn to_resp(value: &Value) -> mlua::Result<String> {
+    let reply = match value {
+        Value::Boolean(true) => ":1\r\n".to_string(),
+        Value::Integer(n) => format!(":{}\r\n", n),
+        Value::Number(n) => format!(":{}\r\n", *n as i64),
+        Value::String(s) => {
+            let s = String::from_utf8_lossy(s.as_bytes());
+            format!("${}\r\n{}\r\n", s.len(), s)
+        }
+        Value::Table(table) => {
+            if let Value::String(err) = table.raw_get::<_, Value>("err")? {
+                return Ok(format!("-{}\r\n", single_line(&err.to_string_lossy())));
+            }
+            if let Value::String(ok) = table.raw_get::<_, Value>("ok")? {
+                return Ok(format!("+{}\r\n", single_line(&ok.to_string_lossy())));
+            }
+            // like Redis, the array stops at the first nil
+            let mut items = Vec::new();
+            loop {
+                let item = table.raw_get::<_, Value>(items.len() + 1)?;
+                if item.is_nil() {
+                    break;
+                }
+                items.push(to_resp(&item)?);
+            }
+            format!("*{}\r\n{}", items.len(), items.join(""))
+        }
+        _ => "$-1\r\n".to_string(),
+    };
+    Ok(reply)
+}
+
+// `origin` names what failed, like `script: <sha>`
+fn error_to_resp(error: &Value, origin: &str) -> String {
+    match error {
+        Value::Table(table) => match table.raw_get::<_, Value>("err") {
+            Ok(Value::String(err)) => format!("-{}\r\n", single_line(&err.to_string_lossy())),
+            _ => format!("-ERR Error running script {}\r\n", origin),
+        },
+        Value::String(message) => format!("-ERR {} {}\r\n", single_line(&message.to_string_lossy()), origin),
+        Value::Error(e) => format!("-ERR {} {}\r\n", single_line(&e.to_string()), origin),
+        _ => format!("-ERR Error running script {}\r\n", origin),
+    }
+}
+
+fn compile_error(error: &mlua::Error) -> String {
+    format!("-ERR Error compiling script (new function): {}\r\n", single_line(&error_message(error)))
+}
+
+// The message of a Lua error, without mlua's callback wrapping and traceback
+fn error_message(error: &mlua::Error) -> String {
+    let message = match error {
+        mlua::Error::SyntaxError { message, .. } | mlua::Error::RuntimeError(message) => message.clone(),
+        mlua::Error::CallbackError { cause, .. } => return error_message(cause),
+        _ => error.to_string(),
+    };
+    match message.find("\nstack traceback:") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+fn reply_table<'lua>(lua: &'lua Lua, field: &str, message: &str) -> mlua::Result<Value<'lua>> {
+    let table = lua.create_table()?;
+    table.raw_set(field, message)?;
+    Ok(Value::Table(table))
+}
+
+// Runs a command future to completion; inside a script nothing waits, so Pending
+// only comes from a command that would have blocked
+fn poll_once<F: Future>(future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => Some(output),
+        Poll::Pending => None,
+    }
+}
+
+fn sha1hex(data: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(data))
+}
+
+fn format_number(n: f64) -> String {
+    match n.fract() == 0.0 && n.abs() < 1e17 {
+        true => (n as i64).to_string(),
+        false => n.to_string(),
+    }
+}
+
+// Error and status replies have to fit on one line
+fn single_line(message: &str) -> String {
+    message.replace(['\r', '\n'], " ")
+}
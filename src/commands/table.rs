@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+pub const WRITE: u8 = 1 << 0; // modifies the keyspace
+pub const NOSCRIPT: u8 = 1 << 1; // refused from redis.call
+pub const BLOCKING: u8 = 1 << 2; // may wait, replicas must not run it verbatim
+
+// Arity of every supported command, counting the name itself, and its flags. A
+// negative arity means at least that many arguments, the same convention as COMMAND INFO.
+const COMMANDS: &[(&str, i32, u8)] = &[
+    ("PING", -1, 0), ("ECHO", 2, 0), ("REPLCONF", -1, NOSCRIPT), ("PSYNC", -3, NOSCRIPT), ("WAIT", 3, NOSCRIPT),
+    ("SET", -3, WRITE), ("GET", 2, 0), ("INCR", 2, WRITE), ("TYPE", 2, 0), ("KEYS", 2, 0),
+    ("RPUSH", -3, WRITE), ("LPUSH", -3, WRITE), ("LLEN", 2, 0), ("LPOP", -2, WRITE),
+    ("BLPOP", -3, WRITE | BLOCKING), ("LRANGE", 4, 0),
+    ("XADD", -5, WRITE), ("XLEN", 2, 0), ("XDEL", -3, WRITE), ("XTRIM", -4, WRITE), ("XSETID", -3, WRITE),
+    ("XRANGE", -4, 0), ("XREVRANGE", -4, 0), ("XREAD", -4, BLOCKING), ("XGROUP", -2, WRITE),
+    ("XREADGROUP", -7, WRITE | BLOCKING), ("XACK", -4, WRITE), ("XPENDING", -3, 0),
+    ("XCLAIM", -6, WRITE), ("XAUTOCLAIM", -6, WRITE), ("XINFO", -2, 0),
+    ("SUBSCRIBE", -2, NOSCRIPT), ("UNSUBSCRIBE", -1, NOSCRIPT), ("PSUBSCRIBE", -2, NOSCRIPT),
+    ("PUNSUBSCRIBE", -1, NOSCRIPT), ("SSUBSCRIBE", -2, NOSCRIPT), ("SUNSUBSCRIBE", -1, NOSCRIPT),
+    ("PUBLISH", 3, 0), ("SPUBLISH", 3, 0), ("PUBSUB", -2, 0),
+    ("MULTI", 1, NOSCRIPT), ("EXEC", 1, NOSCRIPT), ("DISCARD", 1, NOSCRIPT), ("WATCH", -2, NOSCRIPT), ("UNWATCH", 1, NOSCRIPT),
+    ("FLUSHALL", -1, WRITE), ("FLUSHDB", -1, WRITE), ("INFO", -1, 0), ("CONFIG", -2, NOSCRIPT),
+    ("ZADD", -4, WRITE), ("ZINCRBY", 4, WRITE), ("ZRANK", -3, 0), ("ZREVRANK", -3, 0), ("ZRANGE", -4, 0), ("ZREVRANGE", -4, 0),
+    ("ZRANGEBYSCORE", -4, 0), ("ZREVRANGEBYSCORE", -4, 0), ("ZRANGEBYLEX", -4, 0), ("ZREVRANGEBYLEX", -4, 0),
+    ("ZRANGESTORE", -5, WRITE), ("ZCARD", 2, 0), ("ZSCORE", 3, 0), ("ZMSCORE", -3, 0), ("ZREM", -3, WRITE),
+    ("ZCOUNT", 4, 0), ("ZLEXCOUNT", 4, 0), ("ZRANDMEMBER", -2, 0), ("ZREMRANGEBYRANK", 4, WRITE),
+    ("ZREMRANGEBYSCORE", 4, WRITE), ("ZREMRANGEBYLEX", 4, WRITE), ("ZUNIONSTORE", -4, WRITE), ("ZINTERSTORE", -4, WRITE),
+    ("ZDIFFSTORE", -4, WRITE), ("ZUNION", -3, 0), ("ZINTER", -3, 0), ("ZDIFF", -3, 0), ("ZINTERCARD", -3, 0),
+    ("ZPOPMIN", -2, WRITE), ("ZPOPMAX", -2, WRITE), ("ZMPOP", -4, WRITE),
+    ("BZPOPMIN", -3, WRITE | BLOCKING), ("BZPOPMAX", -3, WRITE | BLOCKING), ("BZMPOP", -5, WRITE | BLOCKING),
+    ("GEOADD", -5, WRITE), ("GEOPOS", -2, 0), ("GEODIST", -4, 0), ("GEOHASH", -2, 0), ("GEOSEARCH", -7, 0),
+    ("GEOSEARCHSTORE", -8, WRITE), ("GEORADIUS", -6, WRITE), ("GEORADIUSBYMEMBER", -5, WRITE),
+    ("ACL", -2, NOSCRIPT), ("AUTH", -2, NOSCRIPT), ("HELLO", -1, NOSCRIPT), ("QUIT", -1, NOSCRIPT), ("RESET", 1, NOSCRIPT),
+    ("EVAL", -3, NOSCRIPT), ("EVALSHA", -3, NOSCRIPT), ("EVAL_RO", -3, NOSCRIPT), ("EVALSHA_RO", -3, NOSCRIPT),
+    ("SCRIPT", -2, NOSCRIPT), ("FUNCTION", -2, NOSCRIPT), ("FCALL", -3, NOSCRIPT), ("FCALL_RO", -3, NOSCRIPT),
+    ("SAVE", 1, NOSCRIPT), ("SHUTDOWN", -1, NOSCRIPT),
+];
+
+/// The flags of a command by name, None for a command the server does not know.
+pub fn command_flags(name: &str) -> Option<u8> {
+    COMMANDS.iter().find(|(command, _, _)| command.eq_ignore_ascii_case(name)).map(|(_, _, flags)| *flags)
+}
+
+/// Checks the command name and argument count, returning the error reply when a
+/// command would be rejected before running. MULTI uses it to flag the transaction.
+pub fn validate_command(commands: &[Arc<str>]) -> Option<String> {
+    let Some((_, arity, _)) = COMMANDS.iter().find(|(command, _, _)| command.eq_ignore_ascii_case(&commands[0])) else {
+        let args = commands[1..].iter().map(|arg| format!("'{}' ", arg)).collect::<String>();
+        return Some(format!("-ERR unknown command '{}', with args beginning with: {}\r\n", commands[0], args));
+    };
+
+    let len = commands.len() as i32;
+    if (*arity >= 0 && len != *arity) || (*arity < 0 && len < -arity) {
+        return Some(format!("-ERR wrong number of arguments for '{}' command\r\n", commands[0].to_lowercase()));
+    }
+    None
+}
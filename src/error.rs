@@ -9,14 +9,15 @@ pub enum RedisError {
     ParseFloat(String),
     InvalidCommand(String),
     InvalidRespFormat(String),
-    InvalidStreamId(String),
     WrongType(String),
     KeyNotFound(String),
     LockPoisoned(String),
     ChannelSend(String),
     ConnectionClosed,
+    OutputBufferLimit,
     TooManyWaiters,
     Base64Decode(String),
+    Script(String),
     Other(String),
 }
 
@@ -29,14 +30,15 @@ impl fmt::Display for RedisError {
             RedisError::ParseFloat(e) => write!(f, "Parse float error: {}", e),
             RedisError::InvalidCommand(msg) => write!(f, "Invalid command: {}", msg),
             RedisError::InvalidRespFormat(msg) => write!(f, "Invalid RESP format: {}", msg),
-            RedisError::InvalidStreamId(msg) => write!(f, "Invalid stream ID: {}", msg),
             RedisError::WrongType(msg) => write!(f, "Wrong type: {}", msg),
             RedisError::KeyNotFound(msg) => write!(f, "Key not found: {}", msg),
             RedisError::LockPoisoned(msg) => write!(f, "Lock poisoned: {}", msg),
             RedisError::ChannelSend(msg) => write!(f, "Channel send error: {}", msg),
             RedisError::ConnectionClosed => write!(f, "Connection closed"),
+            RedisError::OutputBufferLimit => write!(f, "Output buffer limit reached"),
             RedisError::TooManyWaiters => write!(f, "ERR_TOO_MANY_WAITERS"),
             RedisError::Base64Decode(e) => write!(f, "Base64 decode error: {}", e),
+            RedisError::Script(e) => write!(f, "Script error: {}", e),
             RedisError::Other(msg) => write!(f, "{}", msg),
         }
     }
@@ -74,6 +76,12 @@ impl From<base64::DecodeError> for RedisError {
     }
 }
 
+impl From<mlua::Error> for RedisError {
+    fn from(err: mlua::Error) -> Self {
+        RedisError::Script(err.to_string())
+    }
+}
+
 impl<T> From<std::sync::PoisonError<T>> for RedisError {
     fn from(err: std::sync::PoisonError<T>) -> Self {
         RedisError::LockPoisoned(err.to_string())
@@ -1,6 +1,6 @@
-use std::{env, sync::{Arc, atomic::AtomicUsize}};
+use std::{env, sync::{Arc, atomic::AtomicUsize}, time::Duration};
 use tokio::net::TcpListener;
-use crate::{protocol::{RedisState, ReplicasState, replication}, utils::ServerConfig};
+use crate::{protocol::{RedisState, ReplicasState, RestorePolicy, replication}, utils::ServerConfig};
 mod protocol;
 mod utils;
 mod commands;
@@ -27,8 +27,13 @@ async fn main() {
     // Load RDB file if dir and dbfilename are specified
     if let (Some(dir), Some(dbfilename)) = (&config.dir, &config.dbfilename) {
         match rdb::load_rdb_file(dir, dbfilename) {
-            Ok(data) => {
-                state.load_rdb_data(data);
+            Ok(contents) => {
+                state.load_rdb_data(contents.keys);
+                match commands::load_libraries(&state, &contents.functions, RestorePolicy::Flush) {
+                    Ok(None) => {}
+                    Ok(Some(error)) => eprintln!("Failed to load functions from RDB file: {}", error.trim_end()),
+                    Err(e) => eprintln!("Failed to load functions from RDB file: {}", e),
+                }
             }
             Err(e) => {
                 eprintln!("Failed to load RDB file: {}", e);
@@ -38,6 +43,19 @@ async fn main() {
 
     replication::configure_server_role(&config, &mut state, replicas_state.clone()).await;
 
+    // Active expiry, so keys with a TTL go away (and notify) without being read again
+    let expire_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            let _keyspace_guard = expire_state.keyspace_lock().read().await;
+            if let Err(e) = expire_state.expire_keys() {
+                eprintln!("Failed to expire keys: {}", e);
+            }
+        }
+    });
+
     loop {
         let (stream, addr) = match listener.accept().await {
             Ok((s, a)) => (s, a),
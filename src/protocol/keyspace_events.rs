@@ -0,0 +1,64 @@
+// Classes of keyspace notifications, selected through `notify-keyspace-events`
+pub const KEYSPACE: u32 = 1 << 0; // K, publish on __keyspace@<db>__:<key>
+pub const KEYEVENT: u32 = 1 << 1; // E, publish on __keyevent@<db>__:<event>
+pub const GENERIC: u32 = 1 << 2;
+pub const STRING: u32 = 1 << 3;
+pub const LIST: u32 = 1 << 4;
+pub const SET: u32 = 1 << 5;
+pub const HASH: u32 = 1 << 6;
+pub const ZSET: u32 = 1 << 7;
+pub const EXPIRED: u32 = 1 << 8;
+pub const EVICTED: u32 = 1 << 9; // accepted for compatibility, there is no maxmemory eviction to fire it
+pub const STREAM: u32 = 1 << 10;
+pub const KEY_MISS: u32 = 1 << 11;
+pub const NEW: u32 = 1 << 12;
+
+// What `A` stands for, key misses and new keys have to be asked for explicitly
+const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM;
+
+const CLASS_FLAGS: [(char, u32); 9] = [
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+];
+
+/// Parses a flag string like `KEA` or `Kg$x`, None on an unknown flag.
+pub fn parse_flags(flags: &str) -> Option<u32> {
+    flags.chars().try_fold(0, |parsed, flag| {
+        let class = match flag {
+            'A' => ALL,
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            'm' => KEY_MISS,
+            'n' => NEW,
+            _ => CLASS_FLAGS.iter().find(|(c, _)| *c == flag)?.1,
+        };
+        Some(parsed | class)
+    })
+}
+
+/// The canonical form CONFIG GET reports, classes first then K, E, m and n.
+pub fn flags_to_string(flags: u32) -> String {
+    let mut result = String::new();
+    if flags & ALL == ALL {
+        result.push('A');
+    } else {
+        for (flag, class) in CLASS_FLAGS {
+            if flags & class != 0 {
+                result.push(flag);
+            }
+        }
+    }
+    for (flag, class) in [('K', KEYSPACE), ('E', KEYEVENT), ('m', KEY_MISS), ('n', NEW)] {
+        if flags & class != 0 {
+            result.push(flag);
+        }
+    }
+    result
+}
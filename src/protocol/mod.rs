@@ -1,6 +1,9 @@
 mod value;
 mod state;
+mod skiplist;
+mod stream_nodes;
+mod keyspace_events;
 pub mod replication;
 
 pub use value::{RedisValue, StreamValue};
-pub use state::{RedisState, ClientState, ReplicasState};
+pub use state::{RedisState, ClientState, ReplicasState, RunningScript, FunctionLibrary, LibraryFunction, RestorePolicy, encoded_frame_len};
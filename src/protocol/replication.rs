@@ -1,8 +1,8 @@
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
 use std::{str::from_utf8, sync::Arc};
-use crate::{error::RedisResult, protocol::{ClientState, RedisState, RedisValue, ReplicasState}, utils::encode_resp_array_arc};
+use crate::{error::RedisResult, protocol::{ClientState, RedisState, RedisValue, ReplicasState, RestorePolicy}, rdb, utils::encode_resp_array_arc};
 use crate::utils::{encode_resp_array_str, parse_multiple_resp, parse_rdb_with_trailing_commands, ServerConfig};
-use crate::commands::execute_commands;
+use crate::commands::{execute_commands, execute_transaction, load_libraries};
 
 // Helper function to process commands after RDB file during handshake or after handshake complete
 pub async fn process_commands_from_master(
@@ -20,15 +20,27 @@ pub async fn process_commands_from_master(
             && commands[1].to_uppercase() == "GETACK"
             && commands[2].to_string() == "*";
 
-        let response = execute_commands(
-            master_stream,
-            false,
-            local_state,
-            client_state,
-            local_replicas_state,
-            &port, 
-            &commands
-        ).await?;
+        // a transaction from the master is queued up and applied as one batch
+        let response = match commands[0].to_uppercase().as_str() {
+            "EXEC" if client_state.is_multi_queue_mode() => {
+                let response = execute_transaction(master_stream, local_state, client_state, local_replicas_state, port).await?;
+                client_state.set_multi_queue_mode(false);
+                response
+            }
+            _ if client_state.is_multi_queue_mode() => {
+                client_state.push_command(commands.clone());
+                String::new()
+            }
+            _ => execute_commands(
+                master_stream,
+                false,
+                local_state,
+                client_state,
+                local_replicas_state,
+                port, 
+                &commands
+            ).await?,
+        };
 
         if is_getack {
             master_stream.write_all(response.as_bytes()).await?;
@@ -86,6 +98,7 @@ pub async fn handle_handshake(
             if rdb_end <= buf.len() {
                 *handshake_complete = true;
                 *expecting_rdb = false;
+                load_master_rdb(buf, 0, rdb_end, local_state);
 
                 if rdb_end < buf.len() {
                     process_commands_from_master(
@@ -114,6 +127,7 @@ pub async fn handle_handshake(
     }
 
     if let Ok(rdb_end) = parse_rdb_with_trailing_commands(buf, rdb_start) {
+        load_master_rdb(buf, rdb_start, rdb_end, local_state);
         if rdb_end < buf.len() {
             process_commands_from_master(
                 &port,
@@ -129,6 +143,29 @@ pub async fn handle_handshake(
     Ok(())
 }
 
+// Loads the function libraries the master's RDB carries, replacing ours as a full
+// resync replaces everything. A bad or partly read RDB is logged and skipped.
+fn load_master_rdb(buf: &[u8], rdb_start: usize, rdb_end: usize, local_state: &RedisState<Arc<str>, RedisValue>) {
+    let Some(size_end) = buf[rdb_start..].windows(2).position(|w| w == b"\r\n") else {
+        return;
+    };
+    if rdb_end > buf.len() {
+        return;
+    }
+    let contents = match rdb::parse_rdb(&buf[rdb_start + size_end + 2..rdb_end]) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("Failed to parse RDB from master: {}", e);
+            return;
+        }
+    };
+    match load_libraries(local_state, &contents.functions, RestorePolicy::Flush) {
+        Ok(None) => {}
+        Ok(Some(error)) => eprintln!("Failed to load functions from master: {}", error.trim_end()),
+        Err(e) => eprintln!("Failed to load functions from master: {}", e),
+    }
+}
+
 pub async fn handle_replication_commands(
     buf: &[u8],
     port: &Arc<str>,
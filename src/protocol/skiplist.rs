@@ -0,0 +1,381 @@
+use std::sync::Arc;
+use ordered_float::OrderedFloat;
+
+const MAX_LEVEL: usize = 32;
+
+#[derive(Clone, Copy, Default)]
+struct Level {
+    forward: Option<usize>,
+    span: usize, // number of elements this link jumps over
+}
+
+#[derive(Clone)]
+struct Node {
+    score: OrderedFloat<f64>,
+    member: Arc<str>,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+impl Node {
+    fn is_before(&self, score: OrderedFloat<f64>, member: &str) -> bool {
+        (self.score, self.member.as_ref()) < (score, member)
+    }
+}
+
+// Same layout as the redis zskiplist: every forward link keeps its span so ranks
+// can be accumulated on the way down. Nodes live in an arena and link by index.
+#[derive(Clone)]
+pub struct SkipList {
+    head: Vec<Level>,
+    nodes: Vec<Option<Node>>,
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    seed: u64,
+}
+
+impl SkipList {
+    pub fn new() -> Self {
+        SkipList {
+            head: vec![Level::default(); MAX_LEVEL],
+            nodes: Vec::new(),
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            seed: 0x2545F4914F6CDD1D,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn node(&self, idx: usize) -> &Node {
+        self.nodes[idx].as_ref().expect("skiplist node was freed")
+    }
+
+    fn node_mut(&mut self, idx: usize) -> &mut Node {
+        self.nodes[idx].as_mut().expect("skiplist node was freed")
+    }
+
+    // `None` stands for the head of the list
+    fn levels(&self, at: Option<usize>) -> &[Level] {
+        match at {
+            Some(idx) => &self.node(idx).levels,
+            None => &self.head,
+        }
+    }
+
+    fn levels_mut(&mut self, at: Option<usize>) -> &mut [Level] {
+        match at {
+            Some(idx) => &mut self.node_mut(idx).levels,
+            None => &mut self.head,
+        }
+    }
+
+    fn random_level(&mut self) -> usize {
+        let mut level = 1;
+        while level < MAX_LEVEL {
+            // xorshift64, promote with p = 1/4
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 7;
+            self.seed ^= self.seed << 17;
+            if self.seed & 3 != 0 {
+                break;
+            }
+            level += 1;
+        }
+        level
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = Some(node);
+                idx
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    // Last node before (score, member) on every level
+    fn find_update(&self, score: OrderedFloat<f64>, member: &str) -> [Option<usize>; MAX_LEVEL] {
+        let mut update = [None; MAX_LEVEL];
+        let mut x = None;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.levels(x)[i].forward {
+                if self.node(next).is_before(score, member) {
+                    x = Some(next);
+                } else {
+                    break;
+                }
+            }
+            update[i] = x;
+        }
+        update
+    }
+
+    /// Inserts (score, member), returns false if the pair is already present.
+    pub fn insert(&mut self, score: OrderedFloat<f64>, member: Arc<str>) -> bool {
+        let mut update = [None; MAX_LEVEL];
+        let mut rank = [0usize; MAX_LEVEL];
+        let mut x = None;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.levels(x)[i].forward {
+                if self.node(next).is_before(score, &member) {
+                    rank[i] += self.levels(x)[i].span;
+                    x = Some(next);
+                } else {
+                    break;
+                }
+            }
+            update[i] = x;
+        }
+
+        if let Some(next) = self.levels(x)[0].forward {
+            let node = self.node(next);
+            if node.score == score && node.member == member {
+                return false;
+            }
+        }
+
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = None;
+                self.head[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let idx = self.alloc(Node { score, member, backward: update[0], levels: vec![Level::default(); level] });
+        for i in 0..level {
+            let prev = self.levels(update[i])[i];
+            self.node_mut(idx).levels[i] = Level { forward: prev.forward, span: prev.span - (rank[0] - rank[i]) };
+            self.levels_mut(update[i])[i] = Level { forward: Some(idx), span: rank[0] - rank[i] + 1 };
+        }
+        for (i, at) in update.iter().enumerate().take(self.level).skip(level) {
+            self.levels_mut(*at)[i].span += 1;
+        }
+
+        if let Some(next) = self.node(idx).levels[0].forward {
+            self.node_mut(next).backward = Some(idx);
+        }
+        self.len += 1;
+        true
+    }
+
+    /// Removes (score, member), returns false if the pair was not present.
+    pub fn remove(&mut self, score: OrderedFloat<f64>, member: &str) -> bool {
+        let update = self.find_update(score, member);
+        let idx = match self.levels(update[0])[0].forward {
+            Some(idx) if self.node(idx).score == score && self.node(idx).member.as_ref() == member => idx,
+            _ => return false,
+        };
+
+        for (i, at) in update.iter().enumerate().take(self.level) {
+            let removed = self.levels(Some(idx)).get(i).copied();
+            let prev = &mut self.levels_mut(*at)[i];
+            match removed {
+                Some(removed) if prev.forward == Some(idx) => {
+                    prev.span += removed.span;
+                    prev.span -= 1;
+                    prev.forward = removed.forward;
+                }
+                _ => prev.span -= 1,
+            }
+        }
+
+        let backward = self.node(idx).backward;
+        if let Some(next) = self.node(idx).levels[0].forward {
+            self.node_mut(next).backward = backward;
+        }
+        while self.level > 1 && self.head[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[idx] = None;
+        self.free.push(idx);
+        self.len -= 1;
+        true
+    }
+
+    /// Number of leading elements for which `before` holds. The predicate must be
+    /// monotone over the list order, e.g. `score < min`.
+    pub fn count_while<F: Fn(f64, &str) -> bool>(&self, before: F) -> usize {
+        let mut rank = 0;
+        let mut x = None;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.levels(x)[i].forward {
+                let node = self.node(next);
+                if before(node.score.0, &node.member) {
+                    rank += self.levels(x)[i].span;
+                    x = Some(next);
+                } else {
+                    break;
+                }
+            }
+        }
+        rank
+    }
+
+    /// 0-based rank of a (score, member) pair known to be in the list.
+    pub fn rank(&self, score: f64, member: &str) -> usize {
+        self.count_while(|s, m| (OrderedFloat(s), m) < (OrderedFloat(score), member))
+    }
+
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = None;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.levels(x)[i].forward {
+                let span = self.levels(x)[i].span;
+                if traversed + span <= target {
+                    traversed += span;
+                    x = Some(next);
+                } else {
+                    break;
+                }
+            }
+            if traversed == target {
+                return x;
+            }
+        }
+        None
+    }
+
+    /// Elements with rank in `start..end`, in ascending order.
+    pub fn range(&self, start: usize, end: usize) -> Iter<'_> {
+        let end = end.min(self.len);
+        let next = if start < end { self.node_at(start) } else { None };
+        Iter { list: self, next, remaining: end.saturating_sub(start), rev: false }
+    }
+
+    /// Elements with rank in `start..end`, in descending order.
+    pub fn rev_range(&self, start: usize, end: usize) -> Iter<'_> {
+        let end = end.min(self.len);
+        let next = if start < end { self.node_at(end - 1) } else { None };
+        Iter { list: self, next, remaining: end.saturating_sub(start), rev: true }
+    }
+}
+
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    next: Option<usize>,
+    remaining: usize,
+    rev: bool,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (f64, &'a Arc<str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = self.list.node(self.next?);
+        self.remaining -= 1;
+        self.next = if self.rev { node.backward } else { node.levels[0].forward };
+        Some((node.score.0, &node.member))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    type Model = BTreeSet<(OrderedFloat<f64>, Arc<str>)>;
+
+    // Small LCG so runs are repeatable without pulling in a rand crate
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+    }
+
+    fn collect(iter: Iter<'_>) -> Vec<(f64, Arc<str>)> {
+        iter.map(|(score, member)| (score, Arc::clone(member))).collect()
+    }
+
+    fn model_slice(model: &Model, start: usize, end: usize) -> Vec<(f64, Arc<str>)> {
+        model.iter().skip(start).take(end.saturating_sub(start)).map(|(score, member)| (score.0, Arc::clone(member))).collect()
+    }
+
+    fn check(list: &SkipList, model: &Model, rng: &mut Lcg) {
+        assert_eq!(list.len(), model.len());
+        for (rank, (score, member)) in model.iter().enumerate() {
+            assert_eq!(list.rank(score.0, member), rank);
+        }
+        assert_eq!(collect(list.range(0, usize::MAX)), model_slice(model, 0, model.len()));
+
+        let len = model.len() as u64 + 2;
+        let (start, end) = (rng.below(len) as usize, rng.below(len) as usize);
+        assert_eq!(collect(list.range(start, end)), model_slice(model, start, end));
+        let mut reversed = model_slice(model, start, end.min(model.len()));
+        reversed.reverse();
+        assert_eq!(collect(list.rev_range(start, end)), reversed);
+
+        // a score range is the rank range between two count_while bounds
+        let (min, max) = (rng.below(20) as f64, rng.below(20) as f64);
+        let first = list.count_while(|score, _| score < min);
+        let last = list.count_while(|score, _| score <= max);
+        let expected = model.iter()
+            .filter(|(score, _)| score.0 >= min && score.0 <= max)
+            .map(|(score, member)| (score.0, Arc::clone(member)))
+            .collect::<Vec<_>>();
+        assert_eq!(collect(list.range(first, last)), expected);
+    }
+
+    #[test]
+    fn matches_ordered_set_model() {
+        let mut rng = Lcg(42);
+        let mut list = SkipList::new();
+        let mut model = Model::new();
+        for step in 0..5000 {
+            // few scores and members, so ties and repeated inserts are common
+            let score = OrderedFloat(rng.below(20) as f64);
+            let member: Arc<str> = Arc::from(format!("m{}", rng.below(60)));
+            match rng.below(3) {
+                0 => assert_eq!(list.remove(score, &member), model.remove(&(score, Arc::clone(&member)))),
+                _ => assert_eq!(list.insert(score, Arc::clone(&member)), model.insert((score, member))),
+            }
+            if step % 50 == 0 {
+                check(&list, &model, &mut rng);
+            }
+        }
+        check(&list, &model, &mut rng);
+    }
+
+    #[test]
+    fn removes_down_to_empty() {
+        let mut rng = Lcg(7);
+        let mut list = SkipList::new();
+        let mut model = Model::new();
+        for i in 0..500 {
+            let score = OrderedFloat(rng.below(10) as f64);
+            let member: Arc<str> = Arc::from(format!("m{}", i));
+            list.insert(score, Arc::clone(&member));
+            model.insert((score, member));
+        }
+        while !model.is_empty() {
+            let (score, member) = model.iter().nth(rng.below(model.len() as u64) as usize).cloned().unwrap();
+            assert!(list.remove(score, &member));
+            assert!(!list.remove(score, &member));
+            model.remove(&(score, member));
+            check(&list, &model, &mut rng);
+        }
+        assert_eq!(list.len(), 0);
+        assert_eq!(list.range(0, 10).count(), 0);
+    }
+}
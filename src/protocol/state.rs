@@ -1,11 +1,11 @@
-use std::{collections::{BTreeSet, HashMap, HashSet, VecDeque}, marker::PhantomData, sync::{Arc, Mutex, RwLock}, time::{Duration, Instant}};
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, marker::PhantomData, sync::{Arc, Mutex, RwLock, atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering}}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use indexmap::IndexMap;
 use ordered_float::OrderedFloat;
-use tokio::{sync::mpsc::{self, Receiver, Sender, error::TrySendError}, time::sleep};
+use tokio::{sync::{Notify, RwLock as AsyncRwLock, RwLockReadGuard as AsyncRwLockReadGuard, mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender, error::TrySendError}}, time::sleep};
 use serde_json::{json, Value};
 use sha2::{Sha256, Digest};
 
-use crate::{error::{RedisError, RedisResult}, protocol::{RedisValue, StreamValue, value::redis_value_as_string}, utils::{collect_as_strings, coord_from_str, decode_score_to_coordinates, encode_coordinates_to_score, encode_resp_array_arc, encode_resp_array_str, encode_resp_redis_value_array, encode_resp_ref_array_arc, encode_resp_value_array, haversine_distance, parse_wrapback}};
+use crate::{error::{RedisError, RedisResult}, rdb, protocol::{RedisValue, StreamValue, keyspace_events, skiplist::SkipList, value::{redis_value_as_string, ClaimOptions, GroupEntry, StreamFields, StreamId, TrimOptions, TrimStrategy}}, utils::{collect_as_strings, Coordinates, decode_score_to_coordinates, distance_if_in_box, encode_coordinates_to_score, encode_resp_array_arc, encode_resp_array_str, encode_resp_redis_value_array, encode_resp_ref_array_arc, encode_resp_value_array, geohash_search_ranges, glob_match, geohash_string, haversine_distance, parse_distance_unit, parse_wrapback, random_below}};
 
 #[derive(Clone)]
 pub struct RedisState<K, RedisValue> {
@@ -14,10 +14,22 @@ pub struct RedisState<K, RedisValue> {
     list_state: ListState<K, RedisValue>,
     sorted_set_state: SortedSetState<K>,
     server_state: ServerState<K, RedisValue>,
-    users_state: UserState<K>
+    users_state: UserState<K>,
+    watch_state: WatchState<K>,
+    script_state: ScriptState,
+    // Commands share it, EXEC takes it exclusively so a transaction is never interleaved
+    keyspace_lock: Arc<AsyncRwLock<()>>,
 }
 
 impl<K, RedisValue> RedisState<K, RedisValue> {
+    pub fn keyspace_lock(&self) -> &Arc<AsyncRwLock<()>> {
+        &self.keyspace_lock
+    }
+
+    pub fn script_state(&self) -> &ScriptState {
+        &self.script_state
+    }
+
     pub fn channels_state(&self) -> &ChannelState<K> {
         &self.channels_state
     }
@@ -47,17 +59,208 @@ impl<K, RedisValue> RedisState<K, RedisValue> {
     }
 }
 
+// A pub/sub frame kind and its arguments, as queued for a subscribed client
+type PubSubFrame = (Arc<str>, Arc<Vec<Arc<str>>>);
+// The receiving end of a subscribed client's frame queue
+type FrameReceiver<K, V> = UnboundedReceiver<(K, Arc<Vec<V>>)>;
+// Per channel (or pattern) queues of the subscribed clients
+type Subscribers<K> = Arc<Mutex<HashMap<K, Vec<Subscriber>>>>;
+// Per channel (or pattern) subscriber count and client addresses
+type SubscriptionCounts<K> = Arc<RwLock<HashMap<K, (usize, HashSet<Arc<str>>)>>>;
+
 #[derive(Clone)]
 pub struct ChannelState<K>{
-    channels_map: Arc<RwLock<HashMap<K, (usize, HashSet<Arc<str>>)>>>,
-    subscribers: Arc<Mutex<HashMap<K, Vec<Sender<(Arc<str>, Arc<Vec<Arc<str>>>)>>>>>,
+    channels_map: SubscriptionCounts<K>,
+    subscribers: Subscribers<K>,
+    // same layout as the two above, keyed by glob pattern
+    patterns_map: SubscriptionCounts<K>,
+    pattern_subscribers: Subscribers<K>,
+    // and for shard channels, a namespace of their own
+    shard_channels_map: SubscriptionCounts<K>,
+    shard_subscribers: Subscribers<K>,
+    output_limit: Arc<RwLock<OutputBufferLimit>>,
+    output_limit_disconnections: Arc<AtomicUsize>,
+    keyspace_events: Arc<AtomicU32>, // notify-keyspace-events classes
 }
 
 impl<K> ChannelState<K>{
     fn new() -> Self{
         let channels_map = Arc::new(RwLock::new(HashMap::new()));
         let subscribers = Arc::new(Mutex::new(HashMap::new()));
-        ChannelState { channels_map, subscribers }
+        let patterns_map = Arc::new(RwLock::new(HashMap::new()));
+        let pattern_subscribers = Arc::new(Mutex::new(HashMap::new()));
+        let shard_channels_map = Arc::new(RwLock::new(HashMap::new()));
+        let shard_subscribers = Arc::new(Mutex::new(HashMap::new()));
+        ChannelState {
+            channels_map, subscribers, patterns_map, pattern_subscribers, shard_channels_map, shard_subscribers,
+            output_limit: Arc::new(RwLock::new(OutputBufferLimit::default())),
+            output_limit_disconnections: Arc::new(AtomicUsize::new(0)),
+            keyspace_events: Arc::new(AtomicU32::new(0)),
+        }
+    }
+}
+
+impl ChannelState<Arc<str>>{
+    // Queues the frame for everyone on `name`. Subscribers pushed over the output
+    // buffer limit are dropped here and disconnect once they see the flag.
+    fn deliver(&self, subscribers: &Subscribers<Arc<str>>, name: &Arc<str>, kind: &str, frame: &Arc<Vec<Arc<str>>>) -> RedisResult<()> {
+        let limit = *self.output_limit.read()?;
+        let bytes = encoded_frame_len(kind, frame);
+        let kind: Arc<str> = Arc::from(kind);
+        let mut subs_guard = subscribers.lock()?;
+        if let Some(subs) = subs_guard.get_mut(name){
+            subs.retain(|subscriber| {
+                if subscriber.output.is_over_limit() {
+                    return false;
+                }
+                if !subscriber.output.reserve(bytes, &limit) {
+                    if !subscriber.output.over_limit.swap(true, Ordering::SeqCst) {
+                        subscriber.output.closed.notify_one();
+                        self.output_limit_disconnections.fetch_add(1, Ordering::SeqCst);
+                        eprintln!("Client {} closed for overcoming of output buffer limits (pubsub)", subscriber.client);
+                    }
+                    return false;
+                }
+                subscriber.sender.send((Arc::clone(&kind), Arc::clone(frame))).is_ok()
+            })
+        }
+        Ok(())
+    }
+}
+
+/// `client-output-buffer-limit pubsub <hard> <soft> <soft-seconds>`, sizes in bytes
+/// and 0 turning a limit off.
+#[derive(Clone, Copy)]
+pub struct OutputBufferLimit {
+    hard: usize,
+    soft: usize,
+    soft_seconds: u64,
+}
+
+impl Default for OutputBufferLimit {
+    fn default() -> Self {
+        OutputBufferLimit { hard: 32 * 1024 * 1024, soft: 8 * 1024 * 1024, soft_seconds: 60 }
+    }
+}
+
+// Bytes queued for a subscriber that it hasn't written to its socket yet
+#[derive(Default)]
+pub struct OutputBuffer {
+    pending: AtomicUsize,
+    soft_limit_since: Mutex<Option<Instant>>,
+    over_limit: AtomicBool,
+    closed: Notify,
+}
+
+impl OutputBuffer {
+    // Accounts for a queued frame, false when that takes the client over the limit
+    fn reserve(&self, bytes: usize, limit: &OutputBufferLimit) -> bool {
+        let pending = self.pending.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        if limit.hard > 0 && pending > limit.hard {
+            return false;
+        }
+        let mut soft_limit_since = self.soft_limit_since.lock().unwrap_or_else(|e| e.into_inner());
+        if limit.soft > 0 && pending > limit.soft {
+            let since = *soft_limit_since.get_or_insert_with(Instant::now);
+            since.elapsed() <= Duration::from_secs(limit.soft_seconds)
+        } else {
+            *soft_limit_since = None;
+            true
+        }
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.pending.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    pub fn is_over_limit(&self) -> bool {
+        self.over_limit.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the client went over the limit, even mid-write to a stalled socket.
+    pub async fn over_limit_reached(&self) {
+        if !self.is_over_limit() {
+            self.closed.notified().await;
+        }
+    }
+}
+
+/// A subscribed client's message queue as publishers see it.
+#[derive(Clone)]
+pub struct Subscriber {
+    client: Arc<str>,
+    sender: UnboundedSender<PubSubFrame>,
+    output: Arc<OutputBuffer>,
+}
+
+/// Size of the RESP array a subscriber writes for the frame.
+pub fn encoded_frame_len(kind: &str, frame: &[Arc<str>]) -> usize {
+    let bulk_len = |s: &str| 1 + s.len().to_string().len() + 2 + s.len() + 2;
+    let header = 1 + (frame.len() + 1).to_string().len() + 2;
+    header + bulk_len(kind) + frame.iter().map(|item| bulk_len(item)).sum::<usize>()
+}
+
+// Parses sizes like `8mb`, `100k` or plain bytes, redis style: `k` is 1000 and `kb` 1024
+fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_lowercase();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (digits, unit) = value.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+fn add_subscriber(counts: &SubscriptionCounts<Arc<str>>, subscribers: &Subscribers<Arc<str>>, name: &Arc<str>, client: &Arc<str>, subscriber: &Subscriber) -> RedisResult<()> {
+    {
+        let mut counts_guard = counts.write()?;
+        let (count, client_set) = counts_guard.entry(Arc::clone(name)).or_insert((0, HashSet::new()));
+        *count += 1;
+        client_set.insert(Arc::clone(client));
+    }
+    let mut subs_guard = subscribers.lock()?;
+    subs_guard.entry(Arc::clone(name)).or_insert(Vec::new()).push(subscriber.clone());
+    Ok(())
+}
+
+// Empty entries are removed so they don't linger in PUBSUB output
+fn remove_subscriber(counts: &SubscriptionCounts<Arc<str>>, subscribers: &Subscribers<Arc<str>>, name: &Arc<str>, client: &str, subscriber: Option<&Subscriber>) -> RedisResult<()> {
+    {
+        let mut counts_guard = counts.write()?;
+        if let Some((count, client_set)) = counts_guard.get_mut(name) {
+            *count -= 1;
+            client_set.remove(client);
+            if *count == 0 {
+                counts_guard.remove(name);
+            }
+        }
+    }
+    if let Some(subscriber) = subscriber {
+        let mut subs_guard = subscribers.lock()?;
+        if let Some(subs) = subs_guard.get_mut(name) {
+            subs.retain(|other| !other.sender.same_channel(&subscriber.sender) && !other.sender.is_closed());
+            if subs.is_empty() {
+                subs_guard.remove(name);
+            }
+        }
+    }
+    Ok(())
+}
+
+// `name` is None for an unsubscribe without any subscriptions left
+// RESP3 connections get these as push frames
+fn encode_subscription_reply(push: bool, kind: &str, name: Option<&Arc<str>>, count: usize) -> String {
+    let prefix = if push { '>' } else { '*' };
+    match name {
+        Some(name) => format!("{}3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n", prefix, kind.len(), kind, name.len(), name, count),
+        None => format!("{}3\r\n${}\r\n{}\r\n$-1\r\n:{}\r\n", prefix, kind.len(), kind, count),
     }
 }
 
@@ -176,6 +379,7 @@ impl CurrentUser<Arc<str>>{
 //subscribe and replication state should be optional
 pub struct ClientState<K, V>{
     current_user: CurrentUser<K>,
+    protocol: u8, // RESP version picked with HELLO
     queued_state: QueuedState<K, V>,
     subscription_state: SubscriptionState<K, V>,
     replication_state: ReplicationState<K, V>,
@@ -183,9 +387,11 @@ pub struct ClientState<K, V>{
 
 pub struct SubscriptionState<K, V>{
     subscribe_mode: bool,
-    map: (usize, HashSet<V>),
-    receiver: Option<Receiver<(K, Arc<Vec<V>>)>>,
-    sender: Option<Sender<(K, Arc<Vec<V>>)>>,
+    map: (usize, HashSet<V>), // the count includes patterns
+    patterns: HashSet<V>,
+    shard_channels: HashSet<V>,
+    receiver: Option<FrameReceiver<K, V>>,
+    sender: Option<Subscriber>,
 }
 
 impl<K, V> SubscriptionState<K, V> {
@@ -193,6 +399,8 @@ impl<K, V> SubscriptionState<K, V> {
         SubscriptionState {
             subscribe_mode: false,
             map: (0, HashSet::new()),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
             receiver: None,
             sender: None,
         }
@@ -214,22 +422,34 @@ impl<K, V> SubscriptionState<K, V> {
         &mut self.map
     }
 
-    pub fn get_receiver_mut(&mut self) -> Option<&mut Receiver<(K, Arc<Vec<V>>)>> {
+    pub fn get_patterns(&self) -> &HashSet<V> {
+        &self.patterns
+    }
+
+    pub fn get_patterns_mut(&mut self) -> &mut HashSet<V> {
+        &mut self.patterns
+    }
+
+    pub fn get_shard_channels(&self) -> &HashSet<V> {
+        &self.shard_channels
+    }
+
+    pub fn get_shard_channels_mut(&mut self) -> &mut HashSet<V> {
+        &mut self.shard_channels
+    }
+
+    pub fn get_receiver_mut(&mut self) -> Option<&mut FrameReceiver<K, V>> {
         self.receiver.as_mut()
     }
 
-    pub fn get_sender(&self) -> &Option<Sender<(K, Arc<Vec<V>>)>> {
+    pub fn get_sender(&self) -> &Option<Subscriber> {
         &self.sender
     }
 
-    pub fn set_channel(&mut self, sender: Sender<(K, Arc<Vec<V>>)>, receiver: Receiver<(K, Arc<Vec<V>>)>) {
+    pub fn set_channel(&mut self, sender: Subscriber, receiver: FrameReceiver<K, V>) {
         self.sender = Some(sender);
         self.receiver = Some(receiver);
     }
-
-    pub fn has_receiver(&self) -> bool {
-        self.receiver.is_none()
-    }
 }
 
 pub struct ReplicationState<K, V>{
@@ -237,6 +457,7 @@ pub struct ReplicationState<K, V>{
     replica_id: usize,
     num_bytes_synced: usize,
     receiver: Option<Receiver<V>>,
+    effects: Vec<Vec<V>>, // what the last command wrote, replicated in place of the command
     _phantom: PhantomData<(K, V)>
 }
 
@@ -247,6 +468,7 @@ impl<K, V> ReplicationState<K, V> {
             is_replica: false,
             num_bytes_synced: 0,
             receiver: None,
+            effects: Vec::new(),
             _phantom: PhantomData,
         }
     }
@@ -282,11 +504,23 @@ impl<K, V> ReplicationState<K, V> {
     pub fn set_receiver(&mut self, receiver: Receiver<V>) {
         self.receiver = Some(receiver);
     }
+
+    pub fn push_effect(&mut self, command: Vec<V>) {
+        self.effects.push(command);
+    }
+
+    pub fn take_effects(&mut self) -> Vec<Vec<V>> {
+        std::mem::take(&mut self.effects)
+    }
 }
 
 pub struct QueuedState<K, V>{
     multi_queue_mode: bool,
     queued_commands: VecDeque<Vec<V>>,
+    watched_keys: HashSet<V>,
+    watch_dirty: Arc<AtomicBool>, // set once a watched key is touched
+    queue_error: bool, // a command was rejected while queueing, EXEC aborts
+    executing: bool,
     _phantom: PhantomData<K>,
 }
 
@@ -295,10 +529,22 @@ impl<K, V> QueuedState<K, V> {
         QueuedState {
             multi_queue_mode: false,
             queued_commands: VecDeque::new(),
+            watched_keys: HashSet::new(),
+            watch_dirty: Arc::new(AtomicBool::new(false)),
+            queue_error: false,
+            executing: false,
             _phantom: PhantomData,
         }
     }
 
+    pub fn get_watched_keys_mut(&mut self) -> &mut HashSet<V> {
+        &mut self.watched_keys
+    }
+
+    pub fn watch_dirty(&self) -> &Arc<AtomicBool> {
+        &self.watch_dirty
+    }
+
     pub fn is_multi_queue_mode(&self) -> bool {
         self.multi_queue_mode
     }
@@ -317,17 +563,35 @@ impl<K, V> QueuedState<K, V> {
 
     pub fn clear_commands(&mut self) {
         self.queued_commands.clear();
+        self.queue_error = false;
     }
 
     pub fn commands_len(&self) -> usize {
         self.queued_commands.len()
     }
+
+    pub fn has_queue_error(&self) -> bool {
+        self.queue_error
+    }
+
+    pub fn set_queue_error(&mut self) {
+        self.queue_error = true;
+    }
+
+    pub fn is_executing(&self) -> bool {
+        self.executing
+    }
+
+    pub fn set_executing(&mut self, executing: bool) {
+        self.executing = executing;
+    }
 }
 
 impl ClientState<Arc<str>, Arc<str>>{
     pub fn new(is_authenticated: bool) -> Self{
         ClientState {
             current_user: CurrentUser::new(is_authenticated),
+            protocol: 2,
             queued_state: QueuedState::new(),
             subscription_state: SubscriptionState::new(),
             replication_state: ReplicationState::new(),
@@ -342,6 +606,18 @@ impl ClientState<Arc<str>, Arc<str>>{
         self.current_user.is_authenticated = authenticated;
     }
 
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.protocol = protocol;
+    }
+
+    pub fn is_resp3(&self) -> bool {
+        self.protocol == 3
+    }
+
     pub fn set_replica_id(&mut self, id: usize){
         self.replication_state.set_replica_id(id);
     }
@@ -367,24 +643,60 @@ impl ClientState<Arc<str>, Arc<str>>{
         self.subscription_state.get_map_mut()
     }
 
-    pub fn get_sub_receiver_mut(&mut self) -> Option<&mut Receiver<(Arc<str>, Arc<Vec<Arc<str>>>)>> {
+    pub fn get_pattern_subscriptions(&self) -> &HashSet<Arc<str>> {
+        self.subscription_state.get_patterns()
+    }
+
+    pub fn get_pattern_subscriptions_mut(&mut self) -> &mut HashSet<Arc<str>> {
+        self.subscription_state.get_patterns_mut()
+    }
+
+    pub fn get_shard_subscriptions(&self) -> &HashSet<Arc<str>> {
+        self.subscription_state.get_shard_channels()
+    }
+
+    pub fn get_shard_subscriptions_mut(&mut self) -> &mut HashSet<Arc<str>> {
+        self.subscription_state.get_shard_channels_mut()
+    }
+
+    pub fn get_sub_receiver_mut(&mut self) -> Option<&mut UnboundedReceiver<PubSubFrame>> {
         self.subscription_state.get_receiver_mut()
     }
 
-    pub fn get_sub_sender(&self) -> &Option<Sender<(Arc<str>, Arc<Vec<Arc<str>>>)>> {
+    pub fn get_sub_sender(&self) -> &Option<Subscriber> {
         self.subscription_state.get_sender()
     }
 
+    pub fn get_sub_output(&self) -> Option<Arc<OutputBuffer>> {
+        self.get_sub_sender().as_ref().map(|subscriber| Arc::clone(&subscriber.output))
+    }
+
     pub fn get_replica_receiver_mut(&mut self) -> Option<&mut Receiver<Arc<str>>> {
         self.replication_state.get_receiver_mut()
     }
 
-    pub fn set_channel(&mut self, sender: Sender<(Arc<str>, Arc<Vec<Arc<str>>>)>, receiver: Receiver<(Arc<str>, Arc<Vec<Arc<str>>>)>) {
+    pub fn set_channel(&mut self, sender: Subscriber, receiver: UnboundedReceiver<PubSubFrame>) {
         self.subscription_state.set_channel(sender, receiver);
     }
 
-    pub fn has_receiver(&self) -> bool {
-        self.subscription_state.has_receiver()
+    /// Enters subscribed mode, creating the message queue on first use, and
+    /// returns the handle publishers should use. The queue itself is unbounded,
+    /// its size is kept in check by the pubsub output buffer limit.
+    pub fn ensure_sub_channel(&mut self, client: &Arc<str>) -> Subscriber {
+        self.set_subscribe_mode(true);
+        if let Some(subscriber) = self.get_sub_sender() {
+            return subscriber.clone();
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let subscriber = Subscriber { client: Arc::clone(client), sender, output: Arc::new(OutputBuffer::default()) };
+        self.set_channel(subscriber.clone(), receiver);
+        subscriber
+    }
+
+    pub fn leave_subscribe_mode_if_idle(&mut self) {
+        if self.get_subscriptions().0 == 0 && self.get_shard_subscriptions().is_empty() {
+            self.set_subscribe_mode(false);
+        }
     }
 
     // Delegation methods for QueuedState
@@ -412,6 +724,37 @@ impl ClientState<Arc<str>, Arc<str>>{
         self.queued_state.commands_len()
     }
 
+    pub fn has_queue_error(&self) -> bool {
+        self.queued_state.has_queue_error()
+    }
+
+    /// Flags the open transaction so EXEC replies EXECABORT.
+    pub fn set_queue_error(&mut self) {
+        self.queued_state.set_queue_error();
+    }
+
+    /// Whether the commands run as part of EXEC or a script, where nothing may block.
+    pub fn is_executing(&self) -> bool {
+        self.queued_state.is_executing()
+    }
+
+    pub fn set_executing(&mut self, executing: bool) {
+        self.queued_state.set_executing(executing);
+    }
+
+    pub fn get_watched_keys_mut(&mut self) -> &mut HashSet<Arc<str>> {
+        self.queued_state.get_watched_keys_mut()
+    }
+
+    pub fn watch_dirty(&self) -> &Arc<AtomicBool> {
+        self.queued_state.watch_dirty()
+    }
+
+    /// Whether a key WATCHed by this client changed, which makes EXEC abort.
+    pub fn is_watch_dirty(&self) -> bool {
+        self.watch_dirty().load(Ordering::SeqCst)
+    }
+
     // Delegation methods for ReplicationState
     pub fn is_replica(&self) -> bool {
         self.replication_state.is_replica()
@@ -432,6 +775,15 @@ impl ClientState<Arc<str>, Arc<str>>{
     pub fn set_replica_receiver(&mut self, receiver: Receiver<Arc<str>>){
         self.replication_state.set_receiver(receiver);
     }
+
+    pub fn push_effect(&mut self, command: Vec<Arc<str>>) {
+        self.replication_state.push_effect(command);
+    }
+
+    /// Writes the last command made, which replicas get instead of the command itself.
+    pub fn take_effects(&mut self) -> Vec<Vec<Arc<str>>> {
+        self.replication_state.take_effects()
+    }
 }
 
 #[derive(Clone)]
@@ -451,7 +803,7 @@ impl<K> ListState<K, RedisValue>{
 #[derive(Clone)]
 pub struct MapState<K, RedisValue>{
     map: Arc<RwLock<HashMap<K, RedisValue>>>,
-    waiters: Arc<Mutex<HashMap<K, VecDeque<Sender<(Arc<str>, RedisValue)>>>>>,
+    waiters: Arc<Mutex<HashMap<K, VecDeque<Sender<()>>>>>,
 }
 
 impl<K> MapState<K, RedisValue>{
@@ -462,727 +814,3393 @@ impl<K> MapState<K, RedisValue>{
     }
 }
 
+// Dirty flags of the clients WATCHing each key
 #[derive(Clone)]
-pub struct SortedSetState<K>{
-    set: Arc<RwLock<HashMap<K, SortedSet>>>,
+pub struct WatchState<K>{
+    watchers: Arc<Mutex<HashMap<K, Vec<Arc<AtomicBool>>>>>,
+}
+
+impl<K> WatchState<K>{
+    fn new() -> Self{
+        WatchState { watchers: Arc::new(Mutex::new(HashMap::new())) }
+    }
 }
 
+// EVAL scripts cached by SHA1, FUNCTION libraries by name, and the script or
+// function running right now if any
 #[derive(Clone)]
-pub struct SortedSet{
-    members: HashMap<Arc<str>, f64>,
-    scores: BTreeSet<(OrderedFloat<f64>, Arc<str>)>,
+pub struct ScriptState{
+    scripts: Arc<Mutex<HashMap<Arc<str>, Arc<str>>>>,
+    libraries: Arc<RwLock<BTreeMap<Arc<str>, Arc<FunctionLibrary>>>>,
+    running: Arc<Mutex<Option<Arc<RunningScript>>>>,
+    busy_reply_threshold: Arc<AtomicU64>, // ms before other clients get -BUSY
 }
 
-impl SortedSet{
-    fn new() -> Self{
-        let members = HashMap::new();
-        let scores = BTreeSet::new();
-        SortedSet { members, scores }
-    }
+/// A library loaded with FUNCTION LOAD, the code is kept to run it again and for
+/// FUNCTION DUMP and the RDB file.
+pub struct FunctionLibrary{
+    pub name: Arc<str>,
+    pub code: Arc<str>,
+    pub functions: Vec<LibraryFunction>,
 }
 
-impl<K> SortedSetState<K>{
-    fn new() -> Self{
-        let set = Arc::new(RwLock::new(HashMap::new()));
-        SortedSetState { set }
+#[derive(Clone)]
+pub struct LibraryFunction{
+    pub name: Arc<str>,
+    pub description: Option<Arc<str>>,
+    pub flags: Vec<Arc<str>>,
+}
+
+impl LibraryFunction{
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.as_ref() == flag)
     }
 }
 
-#[derive(Clone)]
-pub struct UserState<K>{
-    users: Arc<RwLock<HashMap<K, AclUser<K>>>>
+/// How loaded libraries meet the existing ones, FUNCTION RESTORE names them.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RestorePolicy{
+    Append,
+    Replace,
+    Flush,
 }
 
-#[derive(Clone)]
-pub struct AclUser<K>{
-    properties: IndexMap<K, RedisValue>,
+pub struct RunningScript{
+    started: Instant,
+    killed: AtomicBool,
+    wrote: AtomicBool,
+    is_function: bool,
 }
 
-impl AclUser<Arc<str>>{
-    fn default() -> Self {
-        let mut properties = IndexMap::new();
-        let mut flags = HashSet::new();
-        flags.insert(Arc::from("nopass"));
-        properties.insert(Arc::from("flags"), RedisValue::Flags(flags));
-        properties.insert(Arc::from("passwords"), RedisValue::Array(Arc::from(vec![])));
-        Self { properties }
+impl RunningScript{
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
     }
 
-    fn update_password(&mut self, password: String) -> RedisResult<()>{
-        if let Some(passwords) = self.properties.get_mut(&Arc::from("passwords")){
-            *passwords = RedisValue::Array(Arc::from(vec![RedisValue::String(Arc::from(password))]))
-        }
-        if let Some(RedisValue::Flags(flags)) = self.properties.get_mut(&Arc::from("flags")){
-            flags.remove(&Arc::from("nopass"));
-        }
-        Ok(())
+    pub fn has_written(&self) -> bool {
+        self.wrote.load(Ordering::SeqCst)
     }
 
-    fn check_password(&self, password: &Arc<str>) -> RedisResult<bool>{
-        let hashed_password = format!("{:x}", Sha256::digest(password.as_bytes()));
-        match self.properties.get(&Arc::from("passwords")){
-            Some(passwords) => passwords.array_contains(hashed_password),
-            None => Err(RedisError::KeyNotFound("passwords".to_string())),
-        }
+    pub fn set_written(&self) {
+        self.wrote.store(true, Ordering::SeqCst);
     }
 }
 
-impl UserState<Arc<str>>{
-    fn new() -> Self {
-        let mut users = HashMap::new();
-        users.insert(Arc::from("default"), AclUser::default());
-        UserState { users: Arc::new(RwLock::new(users)) }
+impl ScriptState{
+    fn new() -> Self{
+        ScriptState {
+            scripts: Arc::new(Mutex::new(HashMap::new())),
+            libraries: Arc::new(RwLock::new(BTreeMap::new())),
+            running: Arc::new(Mutex::new(None)),
+            busy_reply_threshold: Arc::new(AtomicU64::new(5000)),
+        }
     }
 
-    fn getuser(&self, user: &Arc<str>) -> RedisResult<Vec<RedisValue>>{
-        let users = self.users.read()?;
-        match users.get(user){
-            Some(acl_user) => {
-                let properties_vec = acl_user.properties.iter().flat_map(|(k, v)| {
-                    [
-                        RedisValue::String(Arc::clone(k)),
-                        v.clone()
-                    ]
-                }).collect::<Vec<_>>();
-                Ok(properties_vec)
-            },
-            None => Ok(Vec::new()),
-        }
+    pub fn get_script(&self, sha: &str) -> RedisResult<Option<Arc<str>>> {
+        Ok(self.scripts.lock()?.get(sha).cloned())
     }
 
-    fn set_password(&mut self, user: &Arc<str>, password: String) -> RedisResult<()>{
-        let mut users = self.users.write()?;
-        if let Some(acl_user) = users.get_mut(user){
-            acl_user.update_password(password)?;
-        }
+    pub fn cache_script(&self, sha: Arc<str>, body: Arc<str>) -> RedisResult<()> {
+        self.scripts.lock()?.insert(sha, body);
+        Ok(())
+    }
 
+    pub fn flush_scripts(&self) -> RedisResult<()> {
+        self.scripts.lock()?.clear();
         Ok(())
     }
 
-    fn check_password(&self, user: &Arc<str>, password: &Arc<str>) -> RedisResult<bool>{
-        let users = self.users.read()?;
-        match users.get(user){
-            Some(acl_user) => acl_user.check_password(password),
-            None => Err(RedisError::KeyNotFound(user.to_string())),
-        }
+    pub fn libraries(&self) -> RedisResult<Vec<Arc<FunctionLibrary>>> {
+        Ok(self.libraries.read()?.values().cloned().collect())
     }
 
-    pub fn default_user_has_nopass(&self) -> bool {
-        let users = self.users.read().expect("users lock poisoned");
-        match users.get(&Arc::from("default")) {
-            Some(acl_user) => {
-                if let Some(RedisValue::Flags(flags)) = acl_user.properties.get(&Arc::from("flags")) {
-                    flags.contains(&Arc::from("nopass"))
-                } else {
-                    false
-                }
+    /// The library registering a function, and the function itself.
+    pub fn find_function(&self, name: &str) -> RedisResult<Option<(Arc<FunctionLibrary>, LibraryFunction)>> {
+        Ok(self.libraries.read()?.values().find_map(|library| {
+            library.functions.iter().find(|function| function.name.as_ref() == name).map(|function| (Arc::clone(library), function.clone()))
+        }))
+    }
+
+    /// Adds libraries under a policy. On a library or function name clash the
+    /// error reply is returned and nothing changes.
+    pub fn add_libraries(&self, libraries: Vec<FunctionLibrary>, policy: RestorePolicy) -> RedisResult<Option<String>> {
+        let mut current = self.libraries.write()?;
+        let mut merged = if policy == RestorePolicy::Flush { BTreeMap::new() } else { current.clone() };
+        for library in libraries {
+            if policy == RestorePolicy::Append && merged.contains_key(&library.name) {
+                return Ok(Some(format!("-ERR Library '{}' already exists\r\n", library.name)));
+            }
+            merged.insert(Arc::clone(&library.name), Arc::new(library));
+        }
+
+        let mut names = HashSet::new();
+        for function in merged.values().flat_map(|library| library.functions.iter()) {
+            if !names.insert(&function.name) {
+                return Ok(Some(format!("-ERR Function {} already exists\r\n", function.name)));
             }
-            None => false,
         }
+        *current = merged;
+        Ok(None)
     }
-}
 
-impl RedisState<Arc<str>, RedisValue>{
-    pub fn new() -> Self{
-        let channels_state = ChannelState::new();
-        let map_state = MapState::new();
-        let list_state = ListState::new();
-        let server_state = ServerState::new();
-        let sorted_set_state = SortedSetState::new();
-        let users_state = UserState::new();
-        RedisState { channels_state, map_state, list_state, server_state, sorted_set_state, users_state }
+    pub fn delete_library(&self, name: &str) -> RedisResult<bool> {
+        Ok(self.libraries.write()?.remove(name).is_some())
     }
 
-    pub fn load_rdb_data(&mut self, data: HashMap<Arc<str>, RedisValue>) {
-        let mut map_guard = self.map_state().map.write().expect("map lock poisoned");
-        for (key, value) in data {
-            map_guard.insert(key, value);
-        }
+    pub fn flush_libraries(&self) -> RedisResult<()> {
+        self.libraries.write()?.clear();
+        Ok(())
     }
 
-    pub fn psync(&self) -> RedisResult<String> {
-        let repl_id = self.server_state().map().get("master_replid")
-            .ok_or_else(|| RedisError::KeyNotFound("master_replid not found".to_string()))?;
-        let offset = self.server_state().map().get("master_repl_offset")
-            .ok_or_else(|| RedisError::KeyNotFound("master_repl_offset not found".to_string()))?;
-        let fullresync_response = format!("+FULLRESYNC {} {}\r\n", repl_id, offset);
-        Ok(fullresync_response)
+    pub fn busy_reply_threshold(&self) -> u64 {
+        self.busy_reply_threshold.load(Ordering::Relaxed)
     }
 
-    pub fn set(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let key = Arc::clone(&commands[1]);
-        let value = Arc::clone(&commands[2]);
-        match commands.iter().skip(3).next() {
-            Some(str) => {
-                match str.to_uppercase().as_str() {
-                    "PX" => {
-                        let timeout_ms: u64 = commands[4].parse()?;
-                        let timeout = Instant::now() + Duration::from_millis(timeout_ms);
-                        self.map_state().map.write()?.insert(key, RedisValue::StringWithTimeout((value, timeout)));
-                    }
-                    "EX" => {
-                        let timeout_s: u64 = commands[4].parse()?;
-                        let timeout = Instant::now() + Duration::from_secs(timeout_s);
-                        self.map_state().map.write()?.insert(key, RedisValue::StringWithTimeout((value, timeout)));
-                    }
-                    _ => (),
-                }
+    pub fn set_busy_reply_threshold(&self, ms: u64) {
+        self.busy_reply_threshold.store(ms, Ordering::Relaxed);
+    }
+
+    pub fn start_running(&self, is_function: bool) -> RedisResult<Arc<RunningScript>> {
+        let running = Arc::new(RunningScript {
+            started: Instant::now(),
+            killed: AtomicBool::new(false),
+            wrote: AtomicBool::new(false),
+            is_function,
+        });
+        *self.running.lock()? = Some(Arc::clone(&running));
+        Ok(running)
+    }
+
+    pub fn finish_running(&self) -> RedisResult<()> {
+        *self.running.lock()? = None;
+        Ok(())
+    }
+
+    /// The -BUSY reply once a script or function has been running past
+    /// busy-reply-threshold, naming the command that stops it.
+    pub fn busy_reply(&self) -> RedisResult<Option<String>> {
+        let threshold = Duration::from_millis(self.busy_reply_threshold());
+        Ok(self.running.lock()?.as_ref().filter(|running| running.started.elapsed() >= threshold).map(|running| {
+            let kill = if running.is_function { "FUNCTION KILL" } else { "SCRIPT KILL" };
+            format!("-BUSY Redis is busy running a script. You can only call {} or SHUTDOWN NOSAVE.\r\n", kill)
+        }))
+    }
+
+    /// SCRIPT KILL or FUNCTION KILL, refused once the script wrote since stopping
+    /// it would leave the dataset half updated.
+    pub fn kill(&self, is_function: bool) -> RedisResult<String> {
+        match self.running.lock()?.as_ref() {
+            // the other kind of script running is not this command's to stop
+            Some(running) if running.is_function != is_function => Ok("-NOTBUSY No scripts in execution right now.\r\n".to_string()),
+            None => Ok("-NOTBUSY No scripts in execution right now.\r\n".to_string()),
+            Some(running) if running.has_written() => Ok("-UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.\r\n".to_string()),
+            Some(running) => {
+                running.killed.store(true, Ordering::SeqCst);
+                Ok("+OK\r\n".to_string())
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SortedSetState<K>{
+    set: Arc<RwLock<HashMap<K, SortedSet>>>,
+    waiters: Arc<Mutex<HashMap<K, VecDeque<SortedSetWaiter>>>>,
+}
+
+// A client blocked in BZPOPMIN, BZPOPMAX or BZMPOP, queued on each of its keys
+struct SortedSetWaiter{
+    keys: Arc<[Arc<str>]>,
+    max: bool,
+    count: usize,
+    sender: Sender<ZPopped>,
+}
+
+#[derive(Clone)]
+pub struct SortedSet{
+    members: HashMap<Arc<str>, f64>,
+    scores: SkipList,
+}
+
+impl SortedSet{
+    fn new() -> Self{
+        let members = HashMap::new();
+        let scores = SkipList::new();
+        SortedSet { members, scores }
+    }
+
+    // Sets the score of a member, returns true when the member is new
+    fn insert(&mut self, member: &Arc<str>, score: f64) -> bool {
+        match self.members.insert(Arc::clone(member), score) {
+            Some(old_score) => {
+                if old_score != score {
+                    self.scores.remove(OrderedFloat::from(old_score), member);
+                    self.scores.insert(OrderedFloat::from(score), Arc::clone(member));
+                }
+                false
+            }
+            None => {
+                self.scores.insert(OrderedFloat::from(score), Arc::clone(member));
+                true
+            }
+        }
+    }
+
+    fn remove(&mut self, member: &str) -> Option<f64> {
+        let score = self.members.remove(member)?;
+        self.scores.remove(OrderedFloat::from(score), member);
+        Some(score)
+    }
+
+    // ZADD/ZINCRBY update of a single member, Err when the result would be NaN
+    fn add(&mut self, member: &Arc<str>, score: f64, flags: &ZAddFlags) -> Result<ZAddOutcome, ()> {
+        match self.members.get(member).copied() {
+            Some(current) => {
+                if flags.nx {
+                    return Ok(ZAddOutcome::Skipped);
+                }
+                let new_score = if flags.incr { current + score } else { score };
+                if new_score.is_nan() {
+                    return Err(());
+                }
+                if (flags.gt && new_score <= current) || (flags.lt && new_score >= current) {
+                    return Ok(ZAddOutcome::Skipped);
+                }
+                if new_score == current {
+                    return Ok(ZAddOutcome::Unchanged(current));
+                }
+                self.insert(member, new_score);
+                Ok(ZAddOutcome::Updated(new_score))
+            }
+            None => {
+                if flags.xx {
+                    return Ok(ZAddOutcome::Skipped);
+                }
+                self.insert(member, score);
+                Ok(ZAddOutcome::Added(score))
+            }
+        }
+    }
+
+    // Removes up to `count` members from the low end, or the high end when `max` is set
+    fn pop(&mut self, max: bool, count: usize) -> Vec<(f64, Arc<str>)> {
+        let popped = self.slice(0, self.scores.len(), max, 0, Some(count));
+        for (_, member) in &popped {
+            self.remove(member);
+        }
+        popped
+    }
+
+    fn rank(&self, member: &str) -> Option<usize> {
+        self.members.get(member).map(|score| self.scores.rank(*score, member))
+    }
+
+    // Rank window [start, end) of the members whose score lies within min..max
+    fn score_ranks(&self, min: ScoreBound, max: ScoreBound) -> (usize, usize) {
+        let start = match min {
+            ScoreBound::Inclusive(min) => self.scores.count_while(|score, _| score < min),
+            ScoreBound::Exclusive(min) => self.scores.count_while(|score, _| score <= min),
+        };
+        let end = match max {
+            ScoreBound::Inclusive(max) => self.scores.count_while(|score, _| score <= max),
+            ScoreBound::Exclusive(max) => self.scores.count_while(|score, _| score < max),
+        };
+        (start, end.max(start))
+    }
+
+    // Lex ranges assume every member has the same score, like redis does
+    fn lex_ranks(&self, min: &LexBound, max: &LexBound) -> (usize, usize) {
+        let start = match min {
+            LexBound::Min => 0,
+            LexBound::Max => self.scores.len(),
+            LexBound::Inclusive(min) => self.scores.count_while(|_, member| member < min.as_ref()),
+            LexBound::Exclusive(min) => self.scores.count_while(|_, member| member <= min.as_ref()),
+        };
+        let end = match max {
+            LexBound::Min => 0,
+            LexBound::Max => self.scores.len(),
+            LexBound::Inclusive(max) => self.scores.count_while(|_, member| member <= max.as_ref()),
+            LexBound::Exclusive(max) => self.scores.count_while(|_, member| member < max.as_ref()),
+        };
+        (start, end.max(start))
+    }
+
+    // Members in the rank window [start, end), walked backwards when `rev` is set,
+    // after skipping `offset` of them and keeping at most `count`
+    fn slice(&self, start: usize, end: usize, rev: bool, offset: usize, count: Option<usize>) -> Vec<(f64, Arc<str>)> {
+        let len = end.saturating_sub(start);
+        let skip = offset.min(len);
+        let take = count.map_or(len - skip, |count| count.min(len - skip));
+        let iter = if rev {
+            self.scores.rev_range(end - skip - take, end - skip)
+        } else {
+            self.scores.range(start + skip, start + skip + take)
+        };
+        iter.map(|(score, member)| (score, Arc::clone(member))).collect()
+    }
+
+    // Resolves the start/stop arguments of ZRANGE and friends to the matching members
+    fn range_by(&self, start: &str, stop: &str, options: &ZRangeOptions) -> Result<Vec<(f64, Arc<str>)>, String> {
+        let (start_rank, end_rank) = match options.by {
+            ZRangeBy::Rank => {
+                let (start, stop) = match (start.parse::<i64>(), stop.parse::<i64>()) {
+                    (Ok(start), Ok(stop)) => (start, stop),
+                    _ => return Err("value is not an integer or out of range".to_string()),
+                };
+                let len = self.scores.len() as i64;
+                let start = if start < 0 { (len + start).max(0) } else { start };
+                let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+                if start > stop || start >= len {
+                    return Ok(Vec::new());
+                }
+                // REV indexes from the highest score down
+                if options.rev {
+                    ((len - 1 - stop) as usize, (len - start) as usize)
+                } else {
+                    (start as usize, stop as usize + 1)
+                }
+            }
+            ZRangeBy::Score => {
+                let (min, max) = if options.rev { (stop, start) } else { (start, stop) };
+                match (ScoreBound::parse(min), ScoreBound::parse(max)) {
+                    (Some(min), Some(max)) => self.score_ranks(min, max),
+                    _ => return Err("min or max is not a float".to_string()),
+                }
+            }
+            ZRangeBy::Lex => {
+                let (min, max) = if options.rev { (stop, start) } else { (start, stop) };
+                match (LexBound::parse(min), LexBound::parse(max)) {
+                    (Some(min), Some(max)) => self.lex_ranks(&min, &max),
+                    _ => return Err("min or max not valid string range item".to_string()),
+                }
+            }
+        };
+
+        let (offset, count) = options.limit.unwrap_or((0, None));
+        Ok(self.slice(start_rank, end_rank, options.rev, offset, count))
+    }
+}
+
+#[derive(Default)]
+struct ZAddFlags {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    incr: bool,
+}
+
+enum ZAddOutcome {
+    Added(f64),
+    Updated(f64),
+    Unchanged(f64),
+    Skipped,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ZSetOp {
+    Union,
+    Inter,
+    Diff,
+}
+
+#[derive(Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(&self, current: f64, score: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN, redis stores 0 instead
+            Aggregate::Sum => {
+                let sum = current + score;
+                if sum.is_nan() { 0.0 } else { sum }
+            }
+            Aggregate::Min => current.min(score),
+            Aggregate::Max => current.max(score),
+        }
+    }
+}
+
+struct ZSetOpOptions {
+    keys: Vec<Arc<str>>,
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+    withscores: bool,
+}
+
+impl ZSetOpOptions {
+    // Parses `numkeys key [key ...]` followed by the modifiers `op` allows
+    fn parse(args: &[Arc<str>], command: &str, op: ZSetOp, allow_withscores: bool) -> Result<Self, String> {
+        let numkeys = match args.first().map(|numkeys| numkeys.parse::<i64>()) {
+            Some(Ok(numkeys)) => numkeys,
+            Some(Err(_)) => return Err("value is not an integer or out of range".to_string()),
+            None => return Err(format!("wrong number of arguments for '{}' command", command)),
+        };
+        if numkeys <= 0 {
+            return Err(format!("at least 1 input key is needed for '{}' command", command));
+        }
+        let numkeys = numkeys as usize;
+        if args.len() - 1 < numkeys {
+            return Err("syntax error".to_string());
+        }
+
+        let mut options = ZSetOpOptions {
+            keys: args[1..=numkeys].to_vec(),
+            weights: vec![1.0; numkeys],
+            aggregate: Aggregate::Sum,
+            withscores: false,
+        };
+        let mut i = numkeys + 1;
+        while i < args.len() {
+            match args[i].to_uppercase().as_str() {
+                "WEIGHTS" if op != ZSetOp::Diff && i + numkeys < args.len() => {
+                    for (weight, arg) in options.weights.iter_mut().zip(&args[i + 1..=i + numkeys]) {
+                        *weight = parse_score(arg).ok_or_else(|| "weight value is not a float".to_string())?;
+                    }
+                    i += numkeys;
+                }
+                "AGGREGATE" if op != ZSetOp::Diff && i + 1 < args.len() => {
+                    options.aggregate = match args[i + 1].to_uppercase().as_str() {
+                        "SUM" => Aggregate::Sum,
+                        "MIN" => Aggregate::Min,
+                        "MAX" => Aggregate::Max,
+                        _ => return Err("syntax error".to_string()),
+                    };
+                    i += 1;
+                }
+                "WITHSCORES" if allow_withscores => options.withscores = true,
+                _ => return Err("syntax error".to_string()),
+            }
+            i += 1;
+        }
+        Ok(options)
+    }
+}
+
+// Input of the ZUNION/ZINTER/ZDIFF family: plain sets count as a zset with every score at 1
+enum ZSetInput<'a> {
+    Sorted(&'a SortedSet),
+    Set(&'a HashSet<Arc<str>>),
+    Missing,
+}
+
+impl<'a> ZSetInput<'a> {
+    // None when a key holds something that is not a set
+    fn collect(
+        keys: &[Arc<str>],
+        sorted_sets: &'a HashMap<Arc<str>, SortedSet>,
+        map: &'a HashMap<Arc<str>, RedisValue>,
+    ) -> Option<Vec<ZSetInput<'a>>> {
+        keys.iter().map(|key| {
+            if let Some(sorted_set) = sorted_sets.get(key) {
+                return Some(ZSetInput::Sorted(sorted_set));
+            }
+            match map.get(key) {
+                Some(RedisValue::Flags(set)) => Some(ZSetInput::Set(set)),
+                Some(_) => None,
+                None => Some(ZSetInput::Missing),
+            }
+        }).collect()
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ZSetInput::Sorted(sorted_set) => sorted_set.scores.len(),
+            ZSetInput::Set(set) => set.len(),
+            ZSetInput::Missing => 0,
+        }
+    }
+
+    fn score(&self, member: &str) -> Option<f64> {
+        match self {
+            ZSetInput::Sorted(sorted_set) => sorted_set.members.get(member).copied(),
+            ZSetInput::Set(set) => set.contains(member).then_some(1.0),
+            ZSetInput::Missing => None,
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&'a Arc<str>, f64)> + 'a> {
+        match *self {
+            ZSetInput::Sorted(sorted_set) => Box::new(sorted_set.members.iter().map(|(member, score)| (member, *score))),
+            ZSetInput::Set(set) => Box::new(set.iter().map(|member| (member, 1.0))),
+            ZSetInput::Missing => Box::new(std::iter::empty()),
+        }
+    }
+}
+
+fn zset_combine(op: ZSetOp, inputs: &[ZSetInput], weights: &[f64], aggregate: Aggregate) -> SortedSet {
+    // 0 * inf is NaN, redis treats the weighted score as 0
+    let weighted = |score: f64, weight: f64| {
+        let score = score * weight;
+        if score.is_nan() { 0.0 } else { score }
+    };
+
+    let mut result = SortedSet::new();
+    match op {
+        ZSetOp::Union => {
+            let mut scores: HashMap<Arc<str>, f64> = HashMap::new();
+            for (input, weight) in inputs.iter().zip(weights) {
+                for (member, score) in input.iter() {
+                    let score = weighted(score, *weight);
+                    scores.entry(Arc::clone(member))
+                        .and_modify(|current| *current = aggregate.apply(*current, score))
+                        .or_insert(score);
+                }
+            }
+            for (member, score) in scores {
+                result.insert(&member, score);
+            }
+        }
+        ZSetOp::Inter => {
+            let Some(smallest) = inputs.iter().min_by_key(|input| input.len()) else {
+                return result;
+            };
+            for (member, _) in smallest.iter() {
+                let mut combined: Option<f64> = None;
+                for (input, weight) in inputs.iter().zip(weights) {
+                    match input.score(member) {
+                        Some(score) => {
+                            let score = weighted(score, *weight);
+                            combined = Some(combined.map_or(score, |current| aggregate.apply(current, score)));
+                        }
+                        None => {
+                            combined = None;
+                            break;
+                        }
+                    }
+                }
+                if let Some(score) = combined {
+                    result.insert(member, score);
+                }
+            }
+        }
+        ZSetOp::Diff => {
+            if let Some((first, others)) = inputs.split_first() {
+                for (member, score) in first.iter() {
+                    if others.iter().all(|input| input.score(member).is_none()) {
+                        result.insert(member, score);
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+// Key a pop was served from and the (score, member) pairs taken from it
+type ZPopped = (Arc<str>, Vec<(f64, Arc<str>)>);
+
+fn encode_popped(popped: Vec<(f64, Arc<str>)>) -> Vec<Arc<str>> {
+    popped.into_iter()
+        .flat_map(|(score, member)| [member, Arc::from(score.to_string())])
+        .collect()
+}
+
+// ZMPOP/BZMPOP reply: the key followed by its [member, score] pairs
+fn encode_mpopped(key: &Arc<str>, popped: Vec<(f64, Arc<str>)>) -> String {
+    let mut response = format!("*2\r\n${}\r\n{}\r\n*{}\r\n", key.len(), key, popped.len());
+    for (score, member) in popped {
+        response.push_str(&encode_resp_array_arc(&[member, Arc::from(score.to_string())]));
+    }
+    response
+}
+
+// What replicas run for a blocking pop, they can't block on it themselves
+fn zpop_effect(key: &Arc<str>, max: bool, count: usize) -> Vec<Arc<str>> {
+    let command = if max { "ZPOPMAX" } else { "ZPOPMIN" };
+    vec![Arc::from(command), Arc::clone(key), Arc::from(count.to_string())]
+}
+
+// Takes a blocked client out of the queues of all its keys
+fn remove_sorted_set_waiter(waiters: &mut HashMap<Arc<str>, VecDeque<SortedSetWaiter>>, keys: &[Arc<str>], sender: &Sender<ZPopped>) {
+    for key in keys {
+        if let Some(queue) = waiters.get_mut(key) {
+            queue.retain(|waiter| !waiter.sender.same_channel(sender));
+            if queue.is_empty() {
+                waiters.remove(key);
+            }
+        }
+    }
+}
+
+// Blocking timeouts are seconds with decimals, 0 blocks forever
+fn parse_block_timeout(s: &str) -> Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(timeout) if timeout < 0.0 => Err("timeout is negative".to_string()),
+        Ok(timeout) if timeout.is_finite() => Duration::try_from_secs_f64(timeout).map_err(|_| "timeout is out of range".to_string()),
+        _ => Err("timeout is not a float or out of range".to_string()),
+    }
+}
+
+// Parses `numkeys key [key ...] MIN|MAX [COUNT count]`
+fn parse_zmpop(args: &[Arc<str>]) -> Result<(Vec<Arc<str>>, bool, usize), String> {
+    let numkeys = match args.first().map(|numkeys| numkeys.parse::<i64>()) {
+        Some(Ok(numkeys)) if numkeys > 0 => numkeys as usize,
+        Some(_) => return Err("numkeys should be greater than 0".to_string()),
+        None => return Err("syntax error".to_string()),
+    };
+    if args.len() < numkeys + 2 {
+        return Err("syntax error".to_string());
+    }
+    let keys = args[1..=numkeys].to_vec();
+    let max = match args[numkeys + 1].to_uppercase().as_str() {
+        "MIN" => false,
+        "MAX" => true,
+        _ => return Err("syntax error".to_string()),
+    };
+    let count = match &args[numkeys + 2..] {
+        [] => 1,
+        [option, count] if option.to_uppercase() == "COUNT" => match count.parse::<i64>() {
+            Ok(count) if count > 0 => count as usize,
+            _ => return Err("count should be greater than 0".to_string()),
+        },
+        _ => return Err("syntax error".to_string()),
+    };
+    Ok((keys, max, count))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum GeoCommand {
+    Search,
+    SearchStore,
+    Radius,
+}
+
+enum GeoFrom {
+    Member(Arc<str>),
+    LonLat(f64, f64),
+}
+
+#[derive(Clone, Copy)]
+enum GeoShape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum GeoSort {
+    Unsorted,
+    Asc,
+    Desc,
+}
+
+struct GeoSearchOptions {
+    from: Option<GeoFrom>,
+    shape: Option<GeoShape>, // in meters
+    unit: f64,
+    sort: GeoSort,
+    count: Option<usize>,
+    any: bool,
+    withcoord: bool,
+    withdist: bool,
+    withhash: bool,
+    store: Option<(Arc<str>, bool)>, // destination and whether to store distances
+    command: GeoCommand,
+}
+
+struct GeoMatch {
+    member: Arc<str>,
+    distance: f64,
+    score: u64,
+    coordinates: Coordinates,
+}
+
+fn parse_lonlat(lon: &str, lat: &str) -> Result<(f64, f64), String> {
+    let (Ok(lon), Ok(lat)) = (lon.parse::<f64>(), lat.parse::<f64>()) else {
+        return Err("value is not a valid float".to_string());
+    };
+    if !(-180.0..=180.0).contains(&lon) || !(-85.05112878..=85.05112878).contains(&lat) {
+        return Err(format!("invalid longitude,latitude pair {:.6},{:.6}", lon, lat));
+    }
+    Ok((lon, lat))
+}
+
+fn parse_geo_distance(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(value) if value >= 0.0 => Ok(value),
+        Ok(_) => Err("radius cannot be negative".to_string()),
+        Err(_) => Err("need numeric radius".to_string()),
+    }
+}
+
+fn parse_geo_unit(unit: &str) -> Result<f64, String> {
+    parse_distance_unit(unit).ok_or_else(|| "unsupported unit provided. please use M, KM, FT, MI".to_string())
+}
+
+impl GeoSearchOptions {
+    // Parses the modifiers after the key (GEOSEARCH) or after `radius unit` (GEORADIUS)
+    fn parse(args: &[Arc<str>], command: GeoCommand, from: Option<GeoFrom>, shape: Option<GeoShape>, unit: f64) -> Result<Self, String> {
+        let mut options = GeoSearchOptions {
+            from, shape, unit,
+            sort: GeoSort::Unsorted,
+            count: None,
+            any: false,
+            withcoord: false,
+            withdist: false,
+            withhash: false,
+            store: None,
+            command,
+        };
+        let is_search = command != GeoCommand::Radius;
+
+        let mut i = 0;
+        while i < args.len() {
+            let remaining = args.len() - i - 1;
+            match args[i].to_uppercase().as_str() {
+                "FROMMEMBER" if is_search && remaining >= 1 => {
+                    if options.from.is_some() {
+                        return Err("exactly one of FROMMEMBER or FROMLONLAT can be specified for geosearch".to_string());
+                    }
+                    options.from = Some(GeoFrom::Member(Arc::clone(&args[i + 1])));
+                    i += 1;
+                }
+                "FROMLONLAT" if is_search && remaining >= 2 => {
+                    if options.from.is_some() {
+                        return Err("exactly one of FROMMEMBER or FROMLONLAT can be specified for geosearch".to_string());
+                    }
+                    let (lon, lat) = parse_lonlat(&args[i + 1], &args[i + 2])?;
+                    options.from = Some(GeoFrom::LonLat(lon, lat));
+                    i += 2;
+                }
+                "BYRADIUS" if is_search && remaining >= 2 => {
+                    if options.shape.is_some() {
+                        return Err("exactly one of BYRADIUS and BYBOX can be specified for geosearch".to_string());
+                    }
+                    let radius = parse_geo_distance(&args[i + 1])?;
+                    options.unit = parse_geo_unit(&args[i + 2])?;
+                    options.shape = Some(GeoShape::Radius(radius * options.unit));
+                    i += 2;
+                }
+                "BYBOX" if is_search && remaining >= 3 => {
+                    if options.shape.is_some() {
+                        return Err("exactly one of BYRADIUS and BYBOX can be specified for geosearch".to_string());
+                    }
+                    let (Ok(width), Ok(height)) = (parse_geo_distance(&args[i + 1]), parse_geo_distance(&args[i + 2])) else {
+                        return Err("need numeric width and height".to_string());
+                    };
+                    options.unit = parse_geo_unit(&args[i + 3])?;
+                    options.shape = Some(GeoShape::Box(width * options.unit, height * options.unit));
+                    i += 3;
+                }
+                "ASC" => options.sort = GeoSort::Asc,
+                "DESC" => options.sort = GeoSort::Desc,
+                "COUNT" if remaining >= 1 => {
+                    match args[i + 1].parse::<i64>() {
+                        Ok(count) if count > 0 => options.count = Some(count as usize),
+                        Ok(_) => return Err("COUNT must be > 0".to_string()),
+                        Err(_) => return Err("value is not an integer or out of range".to_string()),
+                    }
+                    i += 1;
+                    if args.get(i + 1).is_some_and(|arg| arg.to_uppercase() == "ANY") {
+                        options.any = true;
+                        i += 1;
+                    }
+                }
+                "ANY" => options.any = true,
+                "WITHCOORD" if command != GeoCommand::SearchStore => options.withcoord = true,
+                "WITHDIST" if command != GeoCommand::SearchStore => options.withdist = true,
+                "WITHHASH" if command != GeoCommand::SearchStore => options.withhash = true,
+                "STOREDIST" if command == GeoCommand::SearchStore => options.store = Some((Arc::from(""), true)),
+                "STORE" if command == GeoCommand::Radius && remaining >= 1 => {
+                    options.store = Some((Arc::clone(&args[i + 1]), false));
+                    i += 1;
+                }
+                "STOREDIST" if command == GeoCommand::Radius && remaining >= 1 => {
+                    options.store = Some((Arc::clone(&args[i + 1]), true));
+                    i += 1;
+                }
+                _ => return Err("syntax error".to_string()),
+            }
+            i += 1;
+        }
+
+        if options.from.is_none() {
+            return Err("exactly one of FROMMEMBER or FROMLONLAT can be specified for geosearch".to_string());
+        }
+        if options.shape.is_none() {
+            return Err("exactly one of BYRADIUS and BYBOX can be specified for geosearch".to_string());
+        }
+        if options.any && options.count.is_none() {
+            return Err("the ANY argument requires COUNT argument".to_string());
+        }
+        if command == GeoCommand::Radius && options.store.is_some() && (options.withcoord || options.withdist || options.withhash) {
+            return Err("STORE option in GEORADIUS is not compatible with WITHDIST, WITHHASH and WITHCOORD options".to_string());
+        }
+        // COUNT without ANY has to look at every match to return the closest ones
+        if options.count.is_some() && !options.any && options.sort == GeoSort::Unsorted {
+            options.sort = GeoSort::Asc;
+        }
+        Ok(options)
+    }
+
+    fn encode(&self, matches: &[GeoMatch]) -> String {
+        let extra = [self.withdist, self.withhash, self.withcoord].iter().filter(|with| **with).count();
+        let mut response = format!("*{}\r\n", matches.len());
+        for found in matches {
+            if extra == 0 {
+                response.push_str(&format!("${}\r\n{}\r\n", found.member.len(), found.member));
+                continue;
+            }
+            response.push_str(&format!("*{}\r\n${}\r\n{}\r\n", extra + 1, found.member.len(), found.member));
+            if self.withdist {
+                let distance = format!("{:.4}", found.distance / self.unit);
+                response.push_str(&format!("${}\r\n{}\r\n", distance.len(), distance));
+            }
+            if self.withhash {
+                response.push_str(&format!(":{}\r\n", found.score));
+            }
+            if self.withcoord {
+                let (lon, lat) = (found.coordinates.longitude().to_string(), found.coordinates.latitude().to_string());
+                response.push_str(&encode_resp_array_str(&[&lon, &lat]));
+            }
+        }
+        response
+    }
+}
+
+// Members of the set inside `shape` around `center`, stopping after `limit` hits.
+// Only the geohash cells covering the shape are range-scanned.
+fn geo_matches(sorted_state: &SortedSet, center: &Coordinates, shape: GeoShape, limit: Option<usize>) -> Vec<GeoMatch> {
+    let ranges = match shape {
+        GeoShape::Radius(radius) => geohash_search_ranges(center, radius, radius, radius),
+        GeoShape::Box(width, height) => geohash_search_ranges(center, width / 2.0, height / 2.0, (width / 2.0).hypot(height / 2.0)),
+    };
+
+    let mut matches = Vec::new();
+    for (min, max) in ranges {
+        let (start, end) = sorted_state.score_ranks(ScoreBound::Inclusive(min as f64), ScoreBound::Exclusive(max as f64));
+        for (score, member) in sorted_state.scores.range(start, end) {
+            let coordinates = decode_score_to_coordinates(score as u64);
+            let distance = match shape {
+                GeoShape::Radius(radius) => Some(haversine_distance(center, &coordinates)).filter(|distance| *distance <= radius),
+                GeoShape::Box(width, height) => distance_if_in_box(center, &coordinates, width, height),
+            };
+            if let Some(distance) = distance {
+                matches.push(GeoMatch { member: Arc::clone(member), distance, score: score as u64, coordinates });
+                if limit == Some(matches.len()) {
+                    return matches;
+                }
+            }
+        }
+    }
+    matches
+}
+
+// Scores accept inf/-inf like redis but never NaN
+fn parse_score(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter(|v| !v.is_nan())
+}
+
+#[derive(Clone, Copy)]
+enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+impl ScoreBound {
+    fn parse(s: &str) -> Option<Self> {
+        let (value, exclusive) = match s.strip_prefix('(') {
+            Some(rest) => (rest, true),
+            None => (s, false),
+        };
+        let value = parse_score(value)?;
+        Some(if exclusive { ScoreBound::Exclusive(value) } else { ScoreBound::Inclusive(value) })
+    }
+}
+
+#[derive(Clone)]
+enum LexBound {
+    Min,
+    Max,
+    Inclusive(Arc<str>),
+    Exclusive(Arc<str>),
+}
+
+impl LexBound {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "-" => Some(LexBound::Min),
+            "+" => Some(LexBound::Max),
+            _ => {
+                if let Some(rest) = s.strip_prefix('[') {
+                    Some(LexBound::Inclusive(Arc::from(rest)))
+                } else {
+                    s.strip_prefix('(').map(|rest| LexBound::Exclusive(Arc::from(rest)))
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ZRangeBy {
+    Rank,
+    Score,
+    Lex,
+}
+
+struct ZRangeOptions {
+    by: ZRangeBy,
+    rev: bool,
+    limit: Option<(usize, Option<usize>)>,
+    withscores: bool,
+}
+
+impl ZRangeOptions {
+    // Parses the trailing ZRANGE modifiers. The legacy commands fix `by` and `rev`
+    // up front and only accept LIMIT and WITHSCORES.
+    fn parse(args: &[Arc<str>], by: ZRangeBy, rev: bool, legacy: bool) -> Result<Self, String> {
+        let mut options = ZRangeOptions { by, rev, limit: None, withscores: false };
+        let mut i = 0;
+        while i < args.len() {
+            match args[i].to_uppercase().as_str() {
+                "BYSCORE" if !legacy => options.by = ZRangeBy::Score,
+                "BYLEX" if !legacy => options.by = ZRangeBy::Lex,
+                "REV" if !legacy => options.rev = true,
+                "WITHSCORES" => options.withscores = true,
+                "LIMIT" if i + 2 < args.len() => {
+                    let (offset, count) = match (args[i + 1].parse::<i64>(), args[i + 2].parse::<i64>()) {
+                        (Ok(offset), Ok(count)) => (offset, count),
+                        _ => return Err("value is not an integer or out of range".to_string()),
+                    };
+                    // a negative offset yields nothing, a negative count means all
+                    let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+                    options.limit = Some((offset, usize::try_from(count).ok()));
+                    i += 2;
+                }
+                _ => return Err("syntax error".to_string()),
+            }
+            i += 1;
+        }
+
+        if options.limit.is_some() && options.by == ZRangeBy::Rank {
+            return Err("syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".to_string());
+        }
+        if options.withscores && options.by == ZRangeBy::Lex {
+            return Err("syntax error, WITHSCORES not supported in combination with BYLEX".to_string());
+        }
+        Ok(options)
+    }
+}
+
+impl<K> SortedSetState<K>{
+    fn new() -> Self{
+        let set = Arc::new(RwLock::new(HashMap::new()));
+        let waiters = Arc::new(Mutex::new(HashMap::new()));
+        SortedSetState { set, waiters }
+    }
+}
+
+// Members a ZRANDMEMBER with a negative count may return, the reply is built in memory
+const ZRANDMEMBER_MAX_REPEATED: u64 = 1 << 24;
+
+const INVALID_STREAM_ID: &str = "Invalid stream ID specified as stream command argument";
+
+// `ms-seq` or `ms`, no special IDs
+fn parse_strict_stream_id(id: &str) -> Result<StreamId, String> {
+    StreamId::parse(id, 0).ok_or_else(|| INVALID_STREAM_ID.to_string())
+}
+
+// Interval bound: `-`, `+`, an incomplete ID whose missing sequence is the lowest
+// (start) or highest (end) one, or a `(` prefixed exclusive ID
+fn parse_stream_bound(id: &str, start: bool) -> Result<StreamId, String> {
+    match id {
+        "-" => return Ok(StreamId::MIN),
+        "+" => return Ok(StreamId::MAX),
+        _ => {}
+    }
+    let (exclusive, id) = match id.strip_prefix('(') {
+        Some(id) => (true, id),
+        None => (false, id),
+    };
+    let id = StreamId::parse(id, if start { 0 } else { u64::MAX }).ok_or_else(|| INVALID_STREAM_ID.to_string())?;
+    match (exclusive, start) {
+        (false, _) => Ok(id),
+        (true, true) => id.next().ok_or_else(|| "invalid start ID for the interval".to_string()),
+        (true, false) => id.prev().ok_or_else(|| "invalid end ID for the interval".to_string()),
+    }
+}
+
+fn stream_entry_value(id: StreamId, fields: Option<&StreamFields>) -> Value {
+    match fields {
+        Some(fields) => {
+            let flattened = fields.iter().flat_map(|(k, v)| [k.as_ref(), v.as_ref()]).collect::<Vec<&str>>();
+            json!([id.to_string(), flattened])
+        }
+        None => json!([id.to_string(), null]),
+    }
+}
+
+// XREADGROUP replicates as the claims it made, plus the group's new position for `>` reads
+fn push_read_group_effects(client_state: &mut ClientState<Arc<str>, Arc<str>>, stream: &StreamValue, key: &Arc<str>, group: &str, consumer: &Arc<str>, new_entries: bool, entries: &[GroupEntry]) {
+    // deleted entries served from the history were not delivered again
+    let ids = entries.iter().filter(|(_, fields)| fields.is_some()).map(|(id, _)| *id).collect::<Vec<_>>();
+    let Some((last_id, entries_read, deliveries)) = stream.group_deliveries(group, &ids) else {
+        return;
+    };
+    for delivery in deliveries {
+        client_state.push_effect([
+            "XCLAIM", key, group, consumer, "0", &delivery.id.to_string(),
+            "TIME", &delivery.delivery_time.to_string(), "RETRYCOUNT", &delivery.delivery_count.to_string(), "FORCE", "JUSTID",
+        ].into_iter().map(Arc::from).collect());
+    }
+    if new_entries && !entries.is_empty() {
+        let entries_read = entries_read.map_or("-1".to_string(), |read| read.to_string());
+        client_state.push_effect(["XGROUP", "SETID", key, group, &last_id.to_string(), "ENTRIESREAD", &entries_read]
+            .into_iter().map(Arc::from).collect());
+    }
+}
+
+fn parse_millis(s: &str) -> Option<u128> {
+    s.parse::<i64>().ok().map(|ms| ms.max(0) as u128)
+}
+
+struct StreamReadOptions {
+    group: Option<(Arc<str>, Arc<str>)>,
+    count: Option<usize>,
+    block: Option<u64>,
+    noack: bool,
+    keys: Vec<Arc<str>>,
+    ids: Vec<Arc<str>>,
+}
+
+impl StreamReadOptions {
+    // Parses `[GROUP group consumer] [COUNT count] [BLOCK ms] [NOACK] STREAMS key [key ...] id [id ...]`
+    fn parse(args: &[Arc<str>], xreadgroup: bool) -> Result<Self, String> {
+        let mut options = StreamReadOptions { group: None, count: None, block: None, noack: false, keys: Vec::new(), ids: Vec::new() };
+        let mut i = 0;
+        while i < args.len() {
+            let has_arg = i + 1 < args.len();
+            match args[i].to_uppercase().as_str() {
+                "STREAMS" => {
+                    let streams = &args[i + 1..];
+                    if streams.is_empty() || !streams.len().is_multiple_of(2) {
+                        let (command, special) = if xreadgroup { ("xreadgroup", "'>'") } else { ("xread", "'$'") };
+                        return Err(format!("Unbalanced '{}' list of streams: for each stream key an ID or {} must be specified.", command, special));
+                    }
+                    let (keys, ids) = streams.split_at(streams.len() / 2);
+                    options.keys = keys.to_vec();
+                    options.ids = ids.to_vec();
+                    break;
+                }
+                "COUNT" if has_arg => {
+                    let count = args[i + 1].parse::<i64>().map_err(|_| "value is not an integer or out of range".to_string())?;
+                    options.count = (count > 0).then_some(count as usize);
+                    i += 1;
+                }
+                "BLOCK" if has_arg => {
+                    match args[i + 1].parse::<i64>() {
+                        Ok(ms) if ms < 0 => return Err("timeout is negative".to_string()),
+                        Ok(ms) => options.block = Some(ms as u64),
+                        Err(_) => return Err("timeout is not an integer or out of range".to_string()),
+                    }
+                    i += 1;
+                }
+                "GROUP" if i + 2 < args.len() => {
+                    if !xreadgroup {
+                        return Err("The GROUP option is only supported by XREADGROUP. You called XREAD instead.".to_string());
+                    }
+                    options.group = Some((Arc::clone(&args[i + 1]), Arc::clone(&args[i + 2])));
+                    i += 2;
+                }
+                "NOACK" if xreadgroup => options.noack = true,
+                _ => return Err("syntax error".to_string()),
+            }
+            i += 1;
+        }
+
+        if options.keys.is_empty() {
+            return Err("syntax error".to_string());
+        }
+        if xreadgroup && options.group.is_none() {
+            return Err("Missing GROUP option for XREADGROUP".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// False once the deadline passes without a wake up
+async fn wait_for_stream_waiter(mut receiver: Receiver<()>, deadline: Option<tokio::time::Instant>) -> bool {
+    match deadline {
+        Some(deadline) => tokio::select! {
+            _ = receiver.recv() => true,
+            _ = tokio::time::sleep_until(deadline) => false,
+        },
+        None => {
+            receiver.recv().await;
+            true
+        }
+    }
+}
+
+// Parses `MAXLEN|MINID [=|~] threshold [LIMIT count]`, returning the options and
+// how many arguments they took
+fn parse_trim_options(args: &[Arc<str>]) -> Result<(TrimOptions, usize), String> {
+    let maxlen = match args.first().map(|arg| arg.to_uppercase()) {
+        Some(arg) if arg == "MAXLEN" => true,
+        Some(arg) if arg == "MINID" => false,
+        _ => return Err("syntax error".to_string()),
+    };
+    let mut i = 1;
+    let approx = match args.get(i).map(|arg| arg.as_ref()) {
+        Some("~") => true,
+        Some("=") => false,
+        _ => {
+            i -= 1;
+            false
+        }
+    };
+    i += 1;
+
+    let threshold = args.get(i).ok_or("syntax error")?;
+    let strategy = if maxlen {
+        match threshold.parse::<i64>() {
+            Ok(max_len) if max_len >= 0 => TrimStrategy::MaxLen(max_len as usize),
+            Ok(_) => return Err("The MAXLEN argument must be >= 0.".to_string()),
+            Err(_) => return Err("value is not an integer or out of range".to_string()),
+        }
+    } else {
+        TrimStrategy::MinId(parse_strict_stream_id(threshold)?)
+    };
+    i += 1;
+
+    let mut limit = None;
+    if args.get(i).is_some_and(|arg| arg.eq_ignore_ascii_case("LIMIT")) {
+        let count = args.get(i + 1).ok_or("syntax error")?;
+        match count.parse::<i64>() {
+            Ok(count) if count >= 0 => limit = Some(count as usize),
+            Ok(_) => return Err("The LIMIT argument must be >= 0.".to_string()),
+            Err(_) => return Err("value is not an integer or out of range".to_string()),
+        }
+        if !approx {
+            return Err("syntax error, LIMIT cannot be used without the special ~ option".to_string());
+        }
+        i += 2;
+    }
+    Ok((TrimOptions { strategy, approx, limit }, i))
+}
+
+// Parses `[IDLE ms] [TIME ms] [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID id]`
+fn parse_claim_options(args: &[Arc<str>], options: &mut ClaimOptions) -> Result<(), String> {
+    let mut i = 0;
+    while i < args.len() {
+        let option = args[i].to_uppercase();
+        let value = args.get(i + 1);
+        match (option.as_str(), value) {
+            ("FORCE", _) => options.force = true,
+            ("JUSTID", _) => options.justid = true,
+            ("IDLE", Some(value)) => {
+                options.idle = Some(parse_millis(value).ok_or("Invalid IDLE option argument for XCLAIM")?);
+                i += 1;
+            }
+            ("TIME", Some(value)) => {
+                options.time = Some(parse_millis(value).ok_or("Invalid TIME option argument for XCLAIM")?);
+                i += 1;
+            }
+            ("RETRYCOUNT", Some(value)) => {
+                let retry_count = value.parse::<u64>().map_err(|_| "Invalid RETRYCOUNT option argument for XCLAIM")?;
+                options.retry_count = Some(retry_count);
+                i += 1;
+            }
+            ("LASTID", Some(value)) => {
+                options.last_id = Some(parse_strict_stream_id(value)?);
+                i += 1;
+            }
+            _ => return Err(format!("Unrecognized XCLAIM option '{}'", args[i])),
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct UserState<K>{
+    users: Arc<RwLock<HashMap<K, AclUser<K>>>>
+}
+
+#[derive(Clone)]
+pub struct AclUser<K>{
+    properties: IndexMap<K, RedisValue>,
+}
+
+impl AclUser<Arc<str>>{
+    fn default() -> Self {
+        let mut properties = IndexMap::new();
+        let mut flags = HashSet::new();
+        flags.insert(Arc::from("nopass"));
+        properties.insert(Arc::from("flags"), RedisValue::Flags(flags));
+        properties.insert(Arc::from("passwords"), RedisValue::Array(Arc::from(vec![])));
+        Self { properties }
+    }
+
+    fn update_password(&mut self, password: String) -> RedisResult<()>{
+        if let Some(passwords) = self.properties.get_mut(&Arc::from("passwords")){
+            *passwords = RedisValue::Array(Arc::from(vec![RedisValue::String(Arc::from(password))]))
+        }
+        if let Some(RedisValue::Flags(flags)) = self.properties.get_mut(&Arc::from("flags")){
+            flags.remove(&Arc::from("nopass"));
+        }
+        Ok(())
+    }
+
+    fn check_password(&self, password: &Arc<str>) -> RedisResult<bool>{
+        let hashed_password = format!("{:x}", Sha256::digest(password.as_bytes()));
+        match self.properties.get(&Arc::from("passwords")){
+            Some(passwords) => passwords.array_contains(hashed_password),
+            None => Err(RedisError::KeyNotFound("passwords".to_string())),
+        }
+    }
+}
+
+impl UserState<Arc<str>>{
+    fn new() -> Self {
+        let mut users = HashMap::new();
+        users.insert(Arc::from("default"), AclUser::default());
+        UserState { users: Arc::new(RwLock::new(users)) }
+    }
+
+    fn getuser(&self, user: &Arc<str>) -> RedisResult<Vec<RedisValue>>{
+        let users = self.users.read()?;
+        match users.get(user){
+            Some(acl_user) => {
+                let properties_vec = acl_user.properties.iter().flat_map(|(k, v)| {
+                    [
+                        RedisValue::String(Arc::clone(k)),
+                        v.clone()
+                    ]
+                }).collect::<Vec<_>>();
+                Ok(properties_vec)
+            },
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn set_password(&mut self, user: &Arc<str>, password: String) -> RedisResult<()>{
+        let mut users = self.users.write()?;
+        if let Some(acl_user) = users.get_mut(user){
+            acl_user.update_password(password)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_password(&self, user: &Arc<str>, password: &Arc<str>) -> RedisResult<bool>{
+        let users = self.users.read()?;
+        match users.get(user){
+            Some(acl_user) => acl_user.check_password(password),
+            None => Err(RedisError::KeyNotFound(user.to_string())),
+        }
+    }
+
+    pub fn default_user_has_nopass(&self) -> bool {
+        let users = self.users.read().expect("users lock poisoned");
+        match users.get(&Arc::from("default")) {
+            Some(acl_user) => {
+                if let Some(RedisValue::Flags(flags)) = acl_user.properties.get(&Arc::from("flags")) {
+                    flags.contains(&Arc::from("nopass"))
+                } else {
+                    false
+                }
+            }
+            None => false,
+        }
+    }
+}
+
+impl RedisState<Arc<str>, RedisValue>{
+    pub fn new() -> Self{
+        let channels_state = ChannelState::new();
+        let map_state = MapState::new();
+        let list_state = ListState::new();
+        let server_state = ServerState::new();
+        let sorted_set_state = SortedSetState::new();
+        let users_state = UserState::new();
+        let watch_state = WatchState::new();
+        let script_state = ScriptState::new();
+        let keyspace_lock = Arc::new(AsyncRwLock::new(()));
+        RedisState { channels_state, map_state, list_state, server_state, sorted_set_state, users_state, watch_state, script_state, keyspace_lock }
+    }
+
+    pub fn load_rdb_data(&mut self, data: HashMap<Arc<str>, RedisValue>) {
+        let mut map_guard = self.map_state().map.write().expect("map lock poisoned");
+        for (key, value) in data {
+            map_guard.insert(key, value);
+        }
+    }
+
+    pub fn psync(&self) -> RedisResult<String> {
+        let repl_id = self.server_state().map().get("master_replid")
+            .ok_or_else(|| RedisError::KeyNotFound("master_replid not found".to_string()))?;
+        let offset = self.server_state().map().get("master_repl_offset")
+            .ok_or_else(|| RedisError::KeyNotFound("master_repl_offset not found".to_string()))?;
+        let fullresync_response = format!("+FULLRESYNC {} {}\r\n", repl_id, offset);
+        Ok(fullresync_response)
+    }
+
+    pub fn set(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let key = Arc::clone(&commands[1]);
+        let value = Arc::clone(&commands[2]);
+        let (redis_val, has_ttl) = match commands.get(3) {
+            Some(str) => {
+                match str.to_uppercase().as_str() {
+                    "PX" => {
+                        let timeout_ms: u64 = commands[4].parse()?;
+                        let timeout = Instant::now() + Duration::from_millis(timeout_ms);
+                        (RedisValue::StringWithTimeout((value, timeout)), true)
+                    }
+                    "EX" => {
+                        let timeout_s: u64 = commands[4].parse()?;
+                        let timeout = Instant::now() + Duration::from_secs(timeout_s);
+                        (RedisValue::StringWithTimeout((value, timeout)), true)
+                    }
+                    _ => return Ok("+OK\r\n".to_string()),
+                }
+            }
+            None => {
+                let redis_val = match value.parse::<u64>(){
+                    Ok(num) => RedisValue::Number(num),
+                    Err(_) => RedisValue::String(value),
+                };
+                (redis_val, false)
+            }
+        };
+
+        let previous = self.map_state().map.write()?.insert(Arc::clone(&key), redis_val);
+        if previous.is_none() {
+            self.notify_keyspace_event(keyspace_events::NEW, "new", &key)?;
+        }
+        self.notify_keyspace_event(keyspace_events::STRING, "set", &key)?;
+        if has_ttl {
+            self.notify_keyspace_event(keyspace_events::GENERIC, "expire", &key)?;
+        }
+
+        Ok("+OK\r\n".to_string())
+    }
+
+    pub fn get(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        self.expire_if_needed(&commands[1])?;
+        let value = self.map_state().map.read()?.get(&commands[1]).cloned();
+        if let Some(value) = value {
+            match value {
+                RedisValue::StringWithTimeout((value, timeout)) => {
+                    if Instant::now() < timeout {
+                        Ok(format!("${}\r\n{}\r\n", value.len(), value))
+                    } else {
+                        Ok("$-1\r\n".to_string())
+                    }
+                }
+                RedisValue::String(val) => {
+                    Ok(format!("${}\r\n{}\r\n", val.len(), val))
+                }
+                RedisValue::Number(val) => {
+                    Ok(format!("${}\r\n{}\r\n", val.to_string().len(), val))
+                }
+                _ => Ok("$-1\r\n".to_string()) // fix error handling
+            }
+        } else {
+            self.notify_keyspace_event(keyspace_events::KEY_MISS, "keymiss", &commands[1])?;
+            Ok("$-1\r\n".to_string())
+        }
+    } 
+
+    pub fn rpush(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let key = &commands[1];
+        let (count, created) = {
+            let mut list_guard = self.list_state().list.lock()?;
+            let created = !list_guard.contains_key(key);
+            let items = commands
+                .iter()
+                .skip(2)
+                .map(|v| RedisValue::String(Arc::clone(v)));
+            list_guard
+                .entry(Arc::clone(key))
+                .or_insert(VecDeque::new())
+                .extend(items);
+            let count = list_guard.get(key)
+                .ok_or_else(|| RedisError::KeyNotFound(format!("Key {} not found", key)))?
+                .len()
+                .to_string();
+            (count, created)
+        };
+        if created {
+            self.notify_keyspace_event(keyspace_events::NEW, "new", key)?;
+        }
+        self.notify_keyspace_event(keyspace_events::LIST, "rpush", key)?;
+
+        let mut served = false;
+        let mut waiters_guard = self.list_state().waiters.lock()?;
+        if let Some(waiting_queue) = waiters_guard.get_mut(key) {
+            let key_arc = Arc::clone(key);
+            while let Some(sender) = waiting_queue.pop_front() {
+                let mut list_guard = self.list_state().list.lock()?;
+                if let Some(deque) = list_guard.get_mut(key) {
+                    if let Some(value) = deque.pop_front() {
+                        drop(list_guard); // Release lock before sending
+                        match sender.try_send((Arc::clone(&key_arc), value)) {
+                            Ok(_) => {
+                                served = true;
+                                break;
+                            }
+                            Err(TrySendError::Full(_)) => return Err(RedisError::TooManyWaiters),
+                            Err(TrySendError::Closed(_)) => continue,
+                        }
+                    }
+                }
+            }
+        }
+        drop(waiters_guard);
+
+        // the element went straight to a blocked BLPOP
+        if served {
+            self.notify_keyspace_event(keyspace_events::LIST, "lpop", key)?;
+            self.remove_list_if_empty(key)?;
+        }
+
+        Ok(format!(":{}\r\n", count))
+    } 
+
+    pub fn lpush(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let key = &commands[1];
+        let (count, created) = {
+            let mut list_guard = self.list_state().list.lock()?;
+            let created = !list_guard.contains_key(key);
+            let items = commands
+                .iter()
+                .skip(2)
+                .map(|v| RedisValue::String(Arc::clone(v)));
+            let deque = list_guard.entry(Arc::clone(key)).or_insert(VecDeque::new());
+
+            for item in items.into_iter() {
+                deque.push_front(item);
+            }
+
+            let count = list_guard.get(key)
+                .ok_or_else(|| RedisError::KeyNotFound(format!("Key {} not found", key)))?
+                .len();
+            (count, created)
+        };
+
+        if created {
+            self.notify_keyspace_event(keyspace_events::NEW, "new", key)?;
+        }
+        self.notify_keyspace_event(keyspace_events::LIST, "lpush", key)?;
+        Ok(format!(":{}\r\n", count.to_string()))
+    } 
+
+    pub fn llen(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let list_guard = self.list_state().list.lock()?;
+        let len = match list_guard.get(&commands[1]) {
+            Some(list) => list.len().to_string(),
+            None => 0.to_string(),
+        };
+
+        Ok(format!(":{}\r\n", len))
+    } 
+
+    pub fn lpop(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let mut popped_any = false;
+        let response = {
+            let mut list_guard = self.list_state().list.lock()?;
+            match list_guard.get_mut(&commands[1]){
+                Some(list) => {
+                    match commands.get(2){
+                        Some(n) => {
+                            let mut popped_list = Vec::new();
+                            let len = n.parse::<usize>()?;
+                            for _ in 0..len{
+                                if let Some(popped) = list.pop_front() {
+                                    popped_any = true;
+                                    if let Some(val) = redis_value_as_string(popped){
+                                        popped_list.push(val);
+                                    }
+                                }
+                            }
+
+                            Ok(encode_resp_array_arc(&popped_list))
+                        },
+
+                        None => {
+                            match list.pop_front(){
+                                Some(popped) => {
+                                    popped_any = true;
+                                    if let Some(val) = popped.as_string(){
+                                        Ok(format!("${}\r\n{}\r\n", val.len(), val))
+                                    } else {
+                                        Ok("$-1\r\n".to_string())
+                                    }
+                                }
+                                None => Ok("$-1\r\n".to_string()),
+                            }
+                        },
+                    }
+                },
+
+                None => Ok("$-1\r\n".to_string())
+            }
+        };
+
+        if popped_any {
+            self.notify_keyspace_event(keyspace_events::LIST, "lpop", &commands[1])?;
+            self.remove_list_if_empty(&commands[1])?;
+        }
+        response
+    } 
+
+    // Shared hold on the keyspace for one step of a blocking command, never kept across
+    // the wait itself. Inside EXEC the transaction already holds it exclusively.
+    async fn keyspace_step(&self, can_block: bool) -> Option<AsyncRwLockReadGuard<'_, ()>> {
+        match can_block {
+            true => Some(self.keyspace_lock.read().await),
+            false => None,
+        }
+    }
+
+    // A blocked client woken by another one waits for it to finish before replying,
+    // so replicas get the other client's write first (see execute_commands).
+    async fn keyspace_barrier(&self) {
+        drop(self.keyspace_lock.write().await);
+    }
+
+    pub async fn blpop(&mut self, client_state: &mut ClientState<Arc<str>, Arc<str>>, commands: &[Arc<str>], can_block: bool) -> RedisResult<String> {
+        let key = &commands[1];
+
+        {
+            let _keyspace_guard = self.keyspace_step(can_block).await;
+            let popped = self.list_state().list.lock()?
+                .get_mut(key)
+                .and_then(|list| list.pop_front());
+            if let Some(data) = popped {
+                self.notify_keyspace_event(keyspace_events::LIST, "lpop", key)?;
+                self.remove_list_if_empty(key)?;
+                client_state.push_effect(vec![Arc::from("LPOP"), Arc::clone(key)]);
+                if let Some(val) = data.as_string(){
+                    return Ok(encode_resp_array_arc(&[Arc::clone(key), Arc::clone(val)]))
+                }
+            }
+        }
+        // inside EXEC a blocking pop behaves like a plain one
+        if !can_block {
+            return Ok("*-1\r\n".to_string());
+        }
+
+        let mut receiver = {
+            let mut waiters_guard = self.list_state().waiters.lock()?;
+            let queue = waiters_guard.entry(Arc::clone(key)).or_insert(VecDeque::new());
+            if queue.len() > 10000 {
+                return Err(RedisError::Other("ERR_TOO_MANY_BLPOP_WAITERS_FOR_THE_KEY".to_string()))
+            }
+
+            let (sender, receiver) = mpsc::channel(1);
+            queue.push_back(sender);
+            receiver
+        };
+
+        // Re-check the list after adding to waiters to avoid race condition
+        // where RPUSH adds item between our first check and adding to waiters
+        // {
+        //     let mut list_guard = self.list_state().list.lock().unwrap();
+        //     if let Some(list) = list_guard.get_mut(key){
+        //         if let Some(data) = list.pop_front(){
+        //             // Remove ourselves from waiters since we got the item directly
+        //             let mut waiters_guard = self.list_state().waiters.lock().unwrap();
+        //             if let Some(queue) = waiters_guard.get_mut(key){
+        //                 // Remove the last sender we just added
+        //                 queue.pop_back();
+        //             }
+
+        //             if let Some(val) = data.as_string(){
+        //                 return encode_resp_array(&vec![key.clone(), val.clone()])
+        //             }
+        //         }
+        //     }
+        // }
+
+        let timeout: f64 = commands.last()
+            .ok_or_else(|| RedisError::InvalidCommand("BLPOP requires timeout argument".to_string()))?
+            .parse()?;
+        let received = if timeout == 0.0 {
+            receiver.recv().await
+        } else {
+            tokio::select! {
+                result = receiver.recv() => result,
+                _ = sleep(Duration::from_secs_f64(timeout)) => None,
+            }
+        };
+        match received {
+            Some((key_arc, value)) => {
+                // RPUSH handed the element over, replicas pop it after the push
+                self.keyspace_barrier().await;
+                client_state.push_effect(vec![Arc::from("LPOP"), Arc::clone(&key_arc)]);
+                if let Some(val) = value.as_string(){
+                    Ok(encode_resp_array_str(&[key_arc.as_ref(), val]))
+                } else {
+                    Ok("*-1\r\n".to_string())
+                }
+            }
+            None => Ok("*-1\r\n".to_string()),
+        }
+    }
+    
+    // Lists are deleted once their last element is popped
+    fn remove_list_if_empty(&self, key: &Arc<str>) -> RedisResult<()> {
+        let removed = {
+            let mut list_guard = self.list_state().list.lock()?;
+            match list_guard.get(key) {
+                Some(list) if list.is_empty() => list_guard.remove(key).is_some(),
+                _ => false,
+            }
+        };
+        if removed {
+            self.notify_keyspace_event(keyspace_events::GENERIC, "del", key)?;
+        }
+        Ok(())
+    }
+
+    pub fn lrange(&self, key: &Arc<str>, start: &Arc<str>, stop: &Arc<str>) -> RedisResult<String> {
+        let list_guard = self.list_state().list.lock()?;
+        let array = match list_guard.get(key){
+            Some(vec) => {
+                let start = parse_wrapback(start.parse::<i64>()?, vec.len())?;
+                let stop = parse_wrapback(stop.parse::<i64>()?, vec.len())?;
+
+                if start >= vec.len(){
+                    VecDeque::new()
+                } else if stop >= vec.len(){
+                    vec.range(start..).cloned().collect()
+                } else if start > stop{
+                    VecDeque::new() // removed stop=start should be covered by last else
+                } else {
+                    vec.range(start..=stop).cloned().collect()
+                }
+            },
+            None => VecDeque::new()
+        };
+
+        Ok(encode_resp_array_arc(&collect_as_strings(array)))
+    } 
+
+    pub fn type_command(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let map_guard = self.map_state().map.read()?;
+        let response = match map_guard.get(&commands[1]){
+            Some(val) => {
+                match val{
+                    RedisValue::Flags(_) => "flags".to_string(),
+                    RedisValue::Array(_) => "list".to_string(),
+                    RedisValue::String(_) => "string".to_string(),
+                    RedisValue::StringWithTimeout(_) => "string".to_string(),
+                    RedisValue::Stream(_) => "stream".to_string(),
+                    RedisValue::Number(_) => "number".to_string(),
+                }
+            },
+            None => "none".to_string()
+        };
+
+        Ok(format!("+{}\r\n", response))
+    } 
+
+    pub fn xadd(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let key = &commands[1];
+
+        // [NOMKSTREAM] [MAXLEN|MINID [=|~] threshold [LIMIT count]] go before the ID
+        let mut nomkstream = false;
+        let mut trim = None;
+        let mut i = 2;
+        while i < commands.len() {
+            match commands[i].to_uppercase().as_str() {
+                "NOMKSTREAM" => {
+                    nomkstream = true;
+                    i += 1;
+                }
+                "MAXLEN" | "MINID" => {
+                    if trim.is_some() {
+                        return Ok("-ERR syntax error, MAXLEN and MINID options at the same time are not compatible\r\n".to_string());
+                    }
+                    match parse_trim_options(&commands[i..]) {
+                        Ok((options, used)) => {
+                            trim = Some(options);
+                            i += used;
+                        }
+                        Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+                    }
+                }
+                _ => break,
+            }
+        }
+        let fields = commands.get(i + 1..).unwrap_or_default();
+        if fields.is_empty() || !fields.len().is_multiple_of(2) {
+            return Ok("-ERR wrong number of arguments for 'xadd' command\r\n".to_string());
+        }
+        let id = &commands[i];
+
+        let pairs_grouped = Arc::new(
+            fields.chunks_exact(2)
+                .map(|chunk| (Arc::clone(&chunk[0]), Arc::clone(&chunk[1])))
+                .collect::<Vec<_>>()
+        );
+
+        let (result, created, trimmed) = {
+            let mut map_guard = self.map_state().map.write()?;
+            let created = !map_guard.contains_key(key);
+            if nomkstream && created {
+                return Ok("$-1\r\n".to_string());
+            }
+            let value = map_guard
+                .entry(Arc::clone(key))
+                .or_insert(RedisValue::Stream(StreamValue::new()));
+            let result = value.update_stream(id, pairs_grouped)?;
+            let mut trimmed = 0;
+            if let (RedisValue::Stream(stream), Some(trim), true) = (value, &trim, result.starts_with('$')) {
+                trimmed = stream.trim(trim);
+            }
+            (result, created, trimmed)
+        };
+
+        if result.starts_with('$') {
+            if created {
+                self.notify_keyspace_event(keyspace_events::NEW, "new", key)?;
+            }
+            self.notify_keyspace_event(keyspace_events::STREAM, "xadd", key)?;
+            if trimmed > 0 {
+                self.notify_keyspace_event(keyspace_events::STREAM, "xtrim", key)?;
+            }
+            self.wake_stream_waiters(key)?;
+        }
+        Ok(result)
+    } 
+
+    pub fn xlen(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() != 2 {
+            return Ok("-ERR wrong number of arguments for 'xlen' command\r\n".to_string());
+        }
+        let map_guard = self.map_state().map.read()?;
+        match map_guard.get(&commands[1]) {
+            Some(RedisValue::Stream(stream)) => Ok(format!(":{}\r\n", stream.len())),
+            Some(_) => Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            None => Ok(":0\r\n".to_string()),
+        }
+    }
+
+    pub fn xdel(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 3 {
+            return Ok("-ERR wrong number of arguments for 'xdel' command\r\n".to_string());
+        }
+        let mut ids = Vec::with_capacity(commands.len() - 2);
+        for id in &commands[2..] {
+            match parse_strict_stream_id(id) {
+                Ok(id) => ids.push(id),
+                Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+            }
+        }
+
+        let deleted = match self.map_state().map.write()?.get_mut(&commands[1]) {
+            Some(RedisValue::Stream(stream)) => stream.delete(&ids),
+            Some(_) => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            None => 0,
+        };
+        if deleted > 0 {
+            self.notify_keyspace_event(keyspace_events::STREAM, "xdel", &commands[1])?;
+        }
+        Ok(format!(":{}\r\n", deleted))
+    }
+
+    pub fn xtrim(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 4 {
+            return Ok("-ERR wrong number of arguments for 'xtrim' command\r\n".to_string());
+        }
+        let options = match parse_trim_options(&commands[2..]) {
+            Ok((options, used)) if used == commands.len() - 2 => options,
+            Ok(_) => return Ok("-ERR syntax error\r\n".to_string()),
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+
+        let trimmed = match self.map_state().map.write()?.get_mut(&commands[1]) {
+            Some(RedisValue::Stream(stream)) => stream.trim(&options),
+            Some(_) => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            None => 0,
+        };
+        if trimmed > 0 {
+            self.notify_keyspace_event(keyspace_events::STREAM, "xtrim", &commands[1])?;
+        }
+        Ok(format!(":{}\r\n", trimmed))
+    }
+
+    pub fn xsetid(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 3 {
+            return Ok("-ERR wrong number of arguments for 'xsetid' command\r\n".to_string());
+        }
+        let last_id = match parse_strict_stream_id(&commands[2]) {
+            Ok(id) => id,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+
+        let mut entries_added = None;
+        let mut max_deleted_id = None;
+        let mut i = 3;
+        while i < commands.len() {
+            let Some(value) = commands.get(i + 1) else {
+                return Ok("-ERR syntax error\r\n".to_string());
+            };
+            match commands[i].to_uppercase().as_str() {
+                "ENTRIESADDED" => match value.parse::<i64>() {
+                    Ok(added) if added >= 0 => entries_added = Some(added as u64),
+                    Ok(_) => return Ok("-ERR entries_added must be positive\r\n".to_string()),
+                    Err(_) => return Ok("-ERR value is not an integer or out of range\r\n".to_string()),
+                },
+                "MAXDELETEDID" => match parse_strict_stream_id(value) {
+                    Ok(id) => max_deleted_id = Some(id),
+                    Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+                },
+                _ => return Ok("-ERR syntax error\r\n".to_string()),
+            }
+            i += 2;
+        }
+
+        let result = match self.map_state().map.write()?.get_mut(&commands[1]) {
+            Some(RedisValue::Stream(stream)) => stream.set_id(last_id, entries_added, max_deleted_id),
+            Some(_) => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            None => return Ok("-ERR no such key\r\n".to_string()),
+        };
+        match result {
+            Ok(()) => {
+                self.notify_keyspace_event(keyspace_events::STREAM, "xsetid", &commands[1])?;
+                Ok("+OK\r\n".to_string())
+            }
+            Err(e) => Ok(format!("-ERR {}\r\n", e)),
+        }
+    }
+
+    pub fn xrange(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        self.xrange_with(commands, false)
+    } 
+
+    pub fn xrevrange(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.xrange_with(commands, true)
+    }
+
+    // XRANGE key start end [COUNT count], XREVRANGE takes end before start
+    fn xrange_with(&self, commands: &[Arc<str>], rev: bool) -> RedisResult<String> {
+        if commands.len() < 4 {
+            let command = if rev { "xrevrange" } else { "xrange" };
+            return Ok(format!("-ERR wrong number of arguments for '{}' command\r\n", command));
+        }
+        let (start, end) = if rev { (&commands[3], &commands[2]) } else { (&commands[2], &commands[3]) };
+        let bounds = parse_stream_bound(start, true).and_then(|start| Ok((start, parse_stream_bound(end, false)?)));
+        let (start, end) = match bounds {
+            Ok(bounds) => bounds,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+
+        let mut count = None;
+        let mut i = 4;
+        while i < commands.len() {
+            match commands.get(i + 1) {
+                Some(value) if commands[i].eq_ignore_ascii_case("COUNT") => match value.parse::<i64>() {
+                    Ok(parsed) => count = Some(parsed.max(0) as usize),
+                    Err(_) => return Ok("-ERR value is not an integer or out of range\r\n".to_string()),
+                },
+                _ => return Ok("-ERR syntax error\r\n".to_string()),
+            }
+            i += 2;
+        }
+
+        let map_guard = self.map_state().map.read()?;
+        let entries = match map_guard.get(&commands[1]) {
+            Some(RedisValue::Stream(_)) if count == Some(0) => return Ok("*-1\r\n".to_string()),
+            Some(RedisValue::Stream(stream)) => stream.range(start, end, count, rev),
+            Some(_) => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            None => Vec::new(),
+        };
+
+        let values = entries.iter().map(|(id, fields)| stream_entry_value(*id, Some(fields))).collect::<Vec<_>>();
+        let mut encoded_array = String::new();
+        encode_resp_value_array(&mut encoded_array, &values);
+        Ok(encoded_array)
+    }
+
+    pub async fn xread(&self, commands: &[Arc<str>], can_block: bool) -> RedisResult<String> {
+        let mut options = match StreamReadOptions::parse(&commands[1..], false) {
+            Ok(options) => options,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+        if !can_block {
+            options.block = None;
+        }
+        let keyspace_guard = self.keyspace_step(can_block).await;
+
+        // `$` and `+` are resolved once, so a blocked read only returns what comes later
+        let afters = {
+            let map_guard = self.map_state().map.read()?;
+            let mut afters = Vec::with_capacity(options.ids.len());
+            for (key, id) in options.keys.iter().zip(&options.ids) {
+                let last_id = match map_guard.get(key) {
+                    Some(RedisValue::Stream(stream)) => stream.last_id(),
+                    Some(_) => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+                    None => StreamId::MIN,
+                };
+                match id.as_ref() {
+                    "$" => afters.push(last_id),
+                    "+" => afters.push(last_id.prev().unwrap_or(StreamId::MIN)),
+                    _ => match parse_strict_stream_id(id) {
+                        Ok(after) => afters.push(after),
+                        Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+                    },
+                }
+            }
+            afters
+        };
+        drop(keyspace_guard);
+
+        let deadline = options.block.filter(|ms| *ms > 0).map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms));
+        loop {
+            // register before reading so an XADD landing in between still wakes us
+            let receiver = match options.block {
+                Some(_) => Some(self.register_stream_waiter(&options.keys)?),
+                None => None,
+            };
+
+            let response = {
+                let _keyspace_guard = self.keyspace_step(can_block).await;
+                self.read_streams(&options.keys, &afters, options.count)?
+            };
+            if let Some(response) = response {
+                return Ok(response);
+            }
+
+            let Some(receiver) = receiver else {
+                return Ok("*-1\r\n".to_string());
+            };
+            if !wait_for_stream_waiter(receiver, deadline).await {
+                return Ok("*-1\r\n".to_string());
+            }
+            // woken by an XADD, replicas deliver after it
+            self.keyspace_barrier().await;
+        }
+    }
+
+    // One XREAD pass, None when no stream has entries past its ID
+    fn read_streams(&self, keys: &[Arc<str>], afters: &[StreamId], count: Option<usize>) -> RedisResult<Option<String>> {
+        let map_guard = self.map_state().map.read()?;
+        let mut key_entries = Vec::new();
+        for (key, after) in keys.iter().zip(afters) {
+            let Some(RedisValue::Stream(stream)) = map_guard.get(key) else {
+                continue;
+            };
+            let entries = after.next()
+                .map(|start| stream.range(start, StreamId::MAX, count, false))
+                .unwrap_or_default();
+            if !entries.is_empty() {
+                let values = entries.iter().map(|(id, fields)| stream_entry_value(*id, Some(fields))).collect::<Vec<_>>();
+                key_entries.push(json!([key.as_ref(), values]));
+            }
+        }
+
+        if key_entries.is_empty() {
+            return Ok(None);
+        }
+        let mut encoded_array = String::new();
+        encode_resp_value_array(&mut encoded_array, &key_entries);
+        Ok(Some(encoded_array))
+    }
+
+    // One receiver woken by the next XADD on any of the keys
+    fn register_stream_waiter(&self, keys: &[Arc<str>]) -> RedisResult<Receiver<()>> {
+        let mut waiters_guard = self.map_state().waiters.lock()?;
+        let (sender, receiver) = mpsc::channel(1);
+        for key in keys {
+            let queue = waiters_guard.entry(Arc::clone(key)).or_insert(VecDeque::new());
+            queue.retain(|waiter| !waiter.is_closed());
+            if queue.len() > 10000 {
+                return Err(RedisError::Other("ERR_TOO_MANY_XREAD_WAITERS_FOR_THE_KEY".to_string()))
+            }
+            queue.push_back(sender.clone());
+        }
+        Ok(receiver)
+    }
+
+    fn wake_stream_waiters(&self, key: &Arc<str>) -> RedisResult<()> {
+        let mut waiters_guard = self.map_state().waiters.lock()?;
+        if let Some(waiters_queue) = waiters_guard.remove(key) {
+            for waiter in waiters_queue {
+                // Full means the client was already woken through another key
+                let _ = waiter.try_send(());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn xgroup(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 2 {
+            return Ok("-ERR wrong number of arguments for 'xgroup' command\r\n".to_string());
+        }
+        let subcommand = commands[1].to_uppercase();
+        let arity_ok = match subcommand.as_str() {
+            "CREATE" => (5..=8).contains(&commands.len()),
+            "SETID" => (5..=7).contains(&commands.len()),
+            "DESTROY" => commands.len() == 4,
+            "CREATECONSUMER" | "DELCONSUMER" => commands.len() == 5,
+            _ => return Ok(format!("-ERR unknown subcommand '{}'. Try XGROUP HELP.\r\n", commands[1])),
+        };
+        if !arity_ok {
+            return Ok(format!("-ERR wrong number of arguments for 'xgroup|{}' command\r\n", subcommand.to_lowercase()));
+        }
+
+        let (key, group) = (&commands[2], &commands[3]);
+        let mut mkstream = false;
+        let mut entries_read = None;
+        let mut id = None;
+        if subcommand == "CREATE" || subcommand == "SETID" {
+            let mut i = 5;
+            while i < commands.len() {
+                match commands[i].to_uppercase().as_str() {
+                    "MKSTREAM" if subcommand == "CREATE" => mkstream = true,
+                    "ENTRIESREAD" if i + 1 < commands.len() => {
+                        match commands[i + 1].parse::<i64>() {
+                            Ok(read) if read >= 0 => entries_read = Some(read as u64),
+                            Ok(-1) => entries_read = None,
+                            Ok(_) => return Ok("-ERR value for ENTRIESREAD must be positive or -1\r\n".to_string()),
+                            Err(_) => return Ok("-ERR value is not an integer or out of range\r\n".to_string()),
+                        }
+                        i += 1;
+                    }
+                    _ => return Ok("-ERR syntax error\r\n".to_string()),
+                }
+                i += 1;
+            }
+            if commands[4].as_ref() != "$" {
+                match parse_strict_stream_id(&commands[4]) {
+                    Ok(parsed) => id = Some(parsed),
+                    Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+                }
+            }
+        }
+
+        let mut map_guard = self.map_state().map.write()?;
+        let created = !map_guard.contains_key(key);
+        if created {
+            if !mkstream {
+                return Ok("-ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.\r\n".to_string());
+            }
+            map_guard.insert(Arc::clone(key), RedisValue::Stream(StreamValue::new()));
+        }
+        let Some(RedisValue::Stream(stream)) = map_guard.get_mut(key) else {
+            return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string());
+        };
+
+        let no_group = format!("-NOGROUP No such consumer group '{}' for key name '{}'\r\n", group, key);
+        let (response, event) = match subcommand.as_str() {
+            "CREATE" if stream.create_group(group, id, entries_read) => ("+OK\r\n".to_string(), Some("xgroup-create")),
+            "CREATE" => ("-BUSYGROUP Consumer Group name already exists\r\n".to_string(), None),
+            "SETID" if stream.set_group_id(group, id, entries_read) => ("+OK\r\n".to_string(), Some("xgroup-setid")),
+            "DESTROY" => {
+                let destroyed = stream.destroy_group(group);
+                (format!(":{}\r\n", destroyed as u8), destroyed.then_some("xgroup-destroy"))
+            }
+            "CREATECONSUMER" => match stream.create_consumer(group, &commands[4]) {
+                Some(created) => (format!(":{}\r\n", created as u8), created.then_some("xgroup-createconsumer")),
+                None => (no_group, None),
+            },
+            "DELCONSUMER" => match stream.delete_consumer(group, &commands[4]) {
+                Some(pending) => (format!(":{}\r\n", pending), Some("xgroup-delconsumer")),
+                None => (no_group, None),
+            },
+            _ => (no_group, None),
+        };
+        drop(map_guard);
+
+        if created {
+            self.notify_keyspace_event(keyspace_events::NEW, "new", key)?;
+        }
+        if let Some(event) = event {
+            self.notify_keyspace_event(keyspace_events::STREAM, event, key)?;
+        }
+        Ok(response)
+    }
+
+    pub async fn xreadgroup(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, commands: &[Arc<str>], can_block: bool) -> RedisResult<String> {
+        let options = match StreamReadOptions::parse(&commands[1..], true) {
+            Ok(options) => options,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+        if options.group.is_none() {
+            return Ok("-ERR Missing GROUP option for XREADGROUP\r\n".to_string());
+        }
+
+        // None stands for `>`, new entries only
+        let mut afters = Vec::with_capacity(options.ids.len());
+        for id in &options.ids {
+            match id.as_ref() {
+                ">" => afters.push(None),
+                "$" => return Ok("-ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.\r\n".to_string()),
+                _ => match parse_strict_stream_id(id) {
+                    Ok(parsed) => afters.push(Some(parsed)),
+                    Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+                },
+            }
+        }
+
+        // history reads are always answered right away
+        let block = options.block.filter(|_| can_block && afters.iter().all(Option::is_none));
+        let deadline = block.filter(|ms| *ms > 0).map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms));
+        loop {
+            // register before trying so an XADD landing in between still wakes us
+            let receiver = match block {
+                Some(_) => Some(self.register_stream_waiter(&options.keys)?),
+                None => None,
+            };
+
+            let response = {
+                let _keyspace_guard = self.keyspace_step(can_block).await;
+                self.read_groups(client_state, &options, &afters)?
+            };
+            if let Some(response) = response {
+                return Ok(response);
+            }
+
+            let Some(receiver) = receiver else {
+                return Ok("*-1\r\n".to_string());
+            };
+            // other consumers may take the new entries first, then we go back to waiting
+            if !wait_for_stream_waiter(receiver, deadline).await {
+                return Ok("*-1\r\n".to_string());
+            }
+            // woken by an XADD, replicas deliver after it
+            self.keyspace_barrier().await;
+        }
+    }
+
+    // One XREADGROUP pass over every stream, None when there is nothing to deliver
+    fn read_groups(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, options: &StreamReadOptions, afters: &[Option<StreamId>]) -> RedisResult<Option<String>> {
+        let Some((group, consumer)) = &options.group else {
+            return Ok(None);
+        };
+        let keys = &options.keys;
+        let mut map_guard = self.map_state().map.write()?;
+        for key in keys {
+            match map_guard.get(key) {
+                Some(RedisValue::Stream(stream)) if stream.has_group(group) => {}
+                Some(RedisValue::Stream(_)) | None => {
+                    return Ok(Some(format!("-NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option\r\n", key, group)));
+                }
+                Some(_) => return Ok(Some("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string())),
+            }
+        }
+
+        let mut key_entries = Vec::new();
+        let mut consumer_created = Vec::new();
+        for (key, after) in keys.iter().zip(afters) {
+            let Some(RedisValue::Stream(stream)) = map_guard.get_mut(key) else {
+                continue;
+            };
+            if stream.create_consumer(group, consumer) == Some(true) {
+                consumer_created.push(Arc::clone(key));
+                client_state.push_effect(vec![Arc::from("XGROUP"), Arc::from("CREATECONSUMER"), Arc::clone(key), Arc::clone(group), Arc::clone(consumer)]);
+            }
+            let entries = stream.read_group(group, consumer, *after, options.count, options.noack).unwrap_or_default();
+            push_read_group_effects(client_state, stream, key, group, consumer, after.is_none(), &entries);
+            if after.is_some() || !entries.is_empty() {
+                let values = entries.iter().map(|(id, fields)| stream_entry_value(*id, fields.as_ref())).collect::<Vec<_>>();
+                key_entries.push(json!([key.as_ref(), values]));
+            }
+        }
+        drop(map_guard);
+        for key in &consumer_created {
+            self.notify_keyspace_event(keyspace_events::STREAM, "xgroup-createconsumer", key)?;
+        }
+
+        if key_entries.is_empty() {
+            return Ok(None);
+        }
+        let mut encoded_array = String::new();
+        encode_resp_value_array(&mut encoded_array, &key_entries);
+        Ok(Some(encoded_array))
+    }
+
+    pub fn xack(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 4 {
+            return Ok("-ERR wrong number of arguments for 'xack' command\r\n".to_string());
+        }
+        let mut ids = Vec::with_capacity(commands.len() - 3);
+        for id in &commands[3..] {
+            match parse_strict_stream_id(id) {
+                Ok(id) => ids.push(id),
+                Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+            }
+        }
+
+        let mut map_guard = self.map_state().map.write()?;
+        match map_guard.get_mut(&commands[1]) {
+            Some(RedisValue::Stream(stream)) => Ok(format!(":{}\r\n", stream.ack(&commands[2], &ids))),
+            Some(_) => Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            None => Ok(":0\r\n".to_string()),
+        }
+    }
+
+    pub fn xpending(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 3 {
+            return Ok("-ERR wrong number of arguments for 'xpending' command\r\n".to_string());
+        }
+        let (key, group) = (&commands[1], &commands[2]);
+
+        // extended form: [IDLE min-idle-time] start end count [consumer]
+        let mut extended = None;
+        if commands.len() > 3 {
+            let (min_idle, args) = match commands[3].to_uppercase().as_str() {
+                "IDLE" if commands.len() > 4 => match parse_millis(&commands[4]) {
+                    Some(min_idle) => (min_idle, &commands[5..]),
+                    None => return Ok("-ERR value is not an integer or out of range\r\n".to_string()),
+                },
+                _ => (0, &commands[3..]),
+            };
+            if args.len() != 3 && args.len() != 4 {
+                return Ok("-ERR syntax error\r\n".to_string());
+            }
+            let bounds = parse_stream_bound(&args[0], true).and_then(|start| Ok((start, parse_stream_bound(&args[1], false)?)));
+            let (start, end) = match bounds {
+                Ok(bounds) => bounds,
+                Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+            };
+            let count = match args[2].parse::<i64>() {
+                Ok(count) => count.max(0) as usize,
+                Err(_) => return Ok("-ERR value is not an integer or out of range\r\n".to_string()),
+            };
+            extended = Some((start, end, count, args.get(3), min_idle));
+        }
+
+        let map_guard = self.map_state().map.read()?;
+        let stream = match map_guard.get(key) {
+            Some(RedisValue::Stream(stream)) if stream.has_group(group) => stream,
+            Some(RedisValue::Stream(_)) | None => return Ok(format!("-NOGROUP No such key '{}' or consumer group '{}'\r\n", key, group)),
+            Some(_) => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+        };
+
+        match extended {
+            Some((start, end, count, consumer, min_idle)) => {
+                let details = stream.pending_range(group, start, end, count, consumer.map(|c| c.as_ref()), min_idle).unwrap_or_default();
+                let mut resp = format!("*{}\r\n", details.len());
+                for detail in details {
+                    let id = detail.id.to_string();
+                    resp.push_str(&format!("*4\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n:{}\r\n",
+                        id.len(), id, detail.consumer.len(), detail.consumer, detail.idle, detail.delivery_count));
+                }
+                Ok(resp)
             }
             None => {
-                let redis_val = match value.parse::<u64>(){
-                    Ok(num) => RedisValue::Number(num),
-                    Err(_) => RedisValue::String(value),
+                let Some(summary) = stream.pending_summary(group) else {
+                    return Ok(format!("-NOGROUP No such key '{}' or consumer group '{}'\r\n", key, group));
                 };
+                let Some((first, last)) = summary.bounds else {
+                    return Ok("*4\r\n:0\r\n$-1\r\n$-1\r\n*-1\r\n".to_string());
+                };
+                let (first, last) = (first.to_string(), last.to_string());
+                let mut resp = format!("*4\r\n:{}\r\n${}\r\n{}\r\n${}\r\n{}\r\n*{}\r\n",
+                    summary.count, first.len(), first, last.len(), last, summary.consumers.len());
+                for (consumer, count) in summary.consumers {
+                    let count = count.to_string();
+                    resp.push_str(&format!("*2\r\n${}\r\n{}\r\n${}\r\n{}\r\n", consumer.len(), consumer, count.len(), count));
+                }
+                Ok(resp)
+            }
+        }
+    }
+
+    pub fn xclaim(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 6 {
+            return Ok("-ERR wrong number of arguments for 'xclaim' command\r\n".to_string());
+        }
+        let (key, group, consumer) = (&commands[1], &commands[2], &commands[3]);
+        let mut options = ClaimOptions::default();
+        match parse_millis(&commands[4]) {
+            Some(min_idle) => options.min_idle = min_idle,
+            None => return Ok("-ERR Invalid min-idle-time argument for XCLAIM\r\n".to_string()),
+        }
 
-                self.map_state().map.write()?.insert(key, redis_val);
+        // IDs run until the first token that isn't one, the options follow
+        let ids = commands[5..].iter().map_while(|id| StreamId::parse(id, 0)).collect::<Vec<_>>();
+        if ids.is_empty() {
+            return Ok(format!("-ERR {}\r\n", INVALID_STREAM_ID));
+        }
+        if let Err(e) = parse_claim_options(&commands[5 + ids.len()..], &mut options) {
+            return Ok(format!("-ERR {}\r\n", e));
+        }
+
+        let mut consumer_created = false;
+        let claimed = match self.map_state().map.write()?.get_mut(key) {
+            Some(RedisValue::Stream(stream)) => {
+                consumer_created = stream.create_consumer(group, consumer) == Some(true);
+                stream.claim(group, consumer, &ids, &options)
             }
+            Some(_) => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            None => None,
+        };
+        let Some(claimed) = claimed else {
+            return Ok(format!("-NOGROUP No such key '{}' or consumer group '{}'\r\n", key, group));
+        };
+        if consumer_created {
+            self.notify_keyspace_event(keyspace_events::STREAM, "xgroup-createconsumer", key)?;
         }
 
-        Ok("+OK\r\n".to_string())
+        if options.justid {
+            let ids = claimed.iter().map(|(id, _)| Arc::from(id.to_string())).collect::<Vec<_>>();
+            return Ok(encode_resp_array_arc(&ids));
+        }
+        let values = claimed.iter().map(|(id, fields)| stream_entry_value(*id, Some(fields))).collect::<Vec<_>>();
+        let mut encoded_array = String::new();
+        encode_resp_value_array(&mut encoded_array, &values);
+        Ok(encoded_array)
     }
 
-    pub fn get(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let value = self.map_state().map.read()?.get(&commands[1]).cloned();
-        if let Some(value) = value {
-            match value {
-                RedisValue::StringWithTimeout((value, timeout)) => {
-                    if Instant::now() < timeout {
-                        Ok(format!("${}\r\n{}\r\n", value.len(), value))
-                    } else {
-                        Ok("$-1\r\n".to_string())
+    pub fn xautoclaim(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 6 {
+            return Ok("-ERR wrong number of arguments for 'xautoclaim' command\r\n".to_string());
+        }
+        let (key, group, consumer) = (&commands[1], &commands[2], &commands[3]);
+        let Some(min_idle) = parse_millis(&commands[4]) else {
+            return Ok("-ERR Invalid min-idle-time argument for XAUTOCLAIM\r\n".to_string());
+        };
+        let start = match parse_stream_bound(&commands[5], true) {
+            Ok(start) => start,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+
+        let mut count = 100;
+        let mut justid = false;
+        let mut i = 6;
+        while i < commands.len() {
+            match commands[i].to_uppercase().as_str() {
+                "JUSTID" => justid = true,
+                "COUNT" if i + 1 < commands.len() => {
+                    match commands[i + 1].parse::<i64>() {
+                        Ok(parsed) if parsed > 0 && parsed <= i64::MAX / 10 => count = parsed as usize,
+                        Ok(_) => return Ok("-ERR COUNT must be > 0\r\n".to_string()),
+                        Err(_) => return Ok("-ERR value is not an integer or out of range\r\n".to_string()),
                     }
+                    i += 1;
                 }
-                RedisValue::String(val) => {
-                    Ok(format!("${}\r\n{}\r\n", val.len(), val))
+                _ => return Ok("-ERR syntax error\r\n".to_string()),
+            }
+            i += 1;
+        }
+
+        let mut consumer_created = false;
+        let result = match self.map_state().map.write()?.get_mut(key) {
+            Some(RedisValue::Stream(stream)) => {
+                consumer_created = stream.create_consumer(group, consumer) == Some(true);
+                stream.auto_claim(group, consumer, min_idle, start, count, justid)
+            }
+            Some(_) => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            None => None,
+        };
+        let Some(result) = result else {
+            return Ok(format!("-NOGROUP No such key '{}' or consumer group '{}'\r\n", key, group));
+        };
+        if consumer_created {
+            self.notify_keyspace_event(keyspace_events::STREAM, "xgroup-createconsumer", key)?;
+        }
+
+        let next = result.next.to_string();
+        let mut resp = format!("*3\r\n${}\r\n{}\r\n", next.len(), next);
+        if justid {
+            let ids = result.claimed.iter().map(|(id, _)| Arc::from(id.to_string())).collect::<Vec<_>>();
+            resp.push_str(&encode_resp_array_arc(&ids));
+        } else {
+            let values = result.claimed.iter().map(|(id, fields)| stream_entry_value(*id, Some(fields))).collect::<Vec<_>>();
+            encode_resp_value_array(&mut resp, &values);
+        }
+        let deleted = result.deleted.iter().map(|id| Arc::from(id.to_string())).collect::<Vec<_>>();
+        resp.push_str(&encode_resp_array_arc(&deleted));
+        Ok(resp)
+    }
+
+    pub fn xinfo(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        let Some(subcommand) = commands.get(1).map(|s| s.to_uppercase()) else {
+            return Ok("-ERR wrong number of arguments for 'xinfo' command\r\n".to_string());
+        };
+        let expected_len = match subcommand.as_str() {
+            "STREAM" | "GROUPS" => 3,
+            "CONSUMERS" => 4,
+            _ => return Ok(format!("-ERR unknown subcommand '{}'. Try XINFO HELP.\r\n", commands[1])),
+        };
+        if commands.len() < expected_len || (subcommand != "STREAM" && commands.len() > expected_len) {
+            return Ok(format!("-ERR wrong number of arguments for 'xinfo|{}' command\r\n", subcommand.to_lowercase()));
+        }
+        let key = &commands[2];
+
+        // STREAM key [FULL [COUNT count]], COUNT 0 lists everything
+        let mut full = None;
+        if subcommand == "STREAM" && commands.len() > 3 {
+            full = match &commands[3..] {
+                [f] if f.eq_ignore_ascii_case("FULL") => Some(10),
+                [f, c, count] if f.eq_ignore_ascii_case("FULL") && c.eq_ignore_ascii_case("COUNT") => match count.parse::<i64>() {
+                    Ok(count) if count <= 0 => Some(usize::MAX),
+                    Ok(count) => Some(count as usize),
+                    Err(_) => return Ok("-ERR value is not an integer or out of range\r\n".to_string()),
+                },
+                _ => return Ok("-ERR syntax error\r\n".to_string()),
+            };
+        }
+
+        let map_guard = self.map_state().map.read()?;
+        let stream = match map_guard.get(key) {
+            Some(RedisValue::Stream(stream)) => stream,
+            Some(_) => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            None => return Ok("-ERR no such key\r\n".to_string()),
+        };
+
+        let reply = match subcommand.as_str() {
+            "STREAM" => {
+                let info = stream.info(full);
+                let mut reply = vec![
+                    json!("length"), json!(info.length),
+                    json!("radix-tree-keys"), json!(info.radix_tree_keys),
+                    json!("radix-tree-nodes"), json!(info.radix_tree_nodes),
+                    json!("last-generated-id"), json!(info.last_generated_id.to_string()),
+                    json!("max-deleted-entry-id"), json!(info.max_deleted_entry_id.to_string()),
+                    json!("entries-added"), json!(info.entries_added),
+                    json!("recorded-first-entry-id"), json!(info.recorded_first_entry_id.to_string()),
+                ];
+                match full {
+                    Some(count) => {
+                        let entries = info.entries.iter().map(|(id, fields)| stream_entry_value(*id, Some(fields))).collect::<Vec<_>>();
+                        let groups = stream.groups_detail(count).into_iter().map(|detail| {
+                            let pending = detail.pending.iter()
+                                .map(|p| json!([p.id.to_string(), p.consumer.as_ref(), p.delivery_time as u64, p.delivery_count]))
+                                .collect::<Vec<_>>();
+                            let consumers = detail.consumers.iter().map(|(consumer, pending)| {
+                                let pending = pending.iter()
+                                    .map(|p| json!([p.id.to_string(), p.delivery_time as u64, p.delivery_count]))
+                                    .collect::<Vec<_>>();
+                                json!([
+                                    "name", consumer.name.as_ref(),
+                                    "seen-time", consumer.seen_time as u64,
+                                    "active-time", consumer.active_time.map_or(-1, |time| time as i64),
+                                    "pel-count", consumer.pending,
+                                    "pending", pending,
+                                ])
+                            }).collect::<Vec<_>>();
+                            json!([
+                                "name", detail.info.name.as_ref(),
+                                "last-delivered-id", detail.info.last_delivered_id.to_string(),
+                                "entries-read", detail.info.entries_read,
+                                "lag", detail.info.lag,
+                                "pel-count", detail.info.pending,
+                                "pending", pending,
+                                "consumers", consumers,
+                            ])
+                        }).collect::<Vec<_>>();
+                        reply.extend([json!("entries"), json!(entries), json!("groups"), json!(groups)]);
+                    }
+                    None => {
+                        let first = info.first_entry.map_or(Value::Null, |(id, fields)| stream_entry_value(id, Some(&fields)));
+                        let last = info.last_entry.map_or(Value::Null, |(id, fields)| stream_entry_value(id, Some(&fields)));
+                        reply.extend([json!("groups"), json!(info.groups), json!("first-entry"), first, json!("last-entry"), last]);
+                    }
                 }
-                RedisValue::Number(val) => {
-                    Ok(format!("${}\r\n{}\r\n", val.to_string().len(), val))
+                reply
+            }
+            "GROUPS" => stream.groups_info().into_iter().map(|group| json!([
+                "name", group.name.as_ref(),
+                "consumers", group.consumers,
+                "pending", group.pending,
+                "last-delivered-id", group.last_delivered_id.to_string(),
+                "entries-read", group.entries_read,
+                "lag", group.lag,
+            ])).collect(),
+            _ => {
+                let group = &commands[3];
+                let Some(consumers) = stream.consumers_info(group) else {
+                    return Ok(format!("-NOGROUP No such consumer group '{}' for key name '{}'\r\n", group, key));
+                };
+                consumers.into_iter().map(|consumer| json!([
+                    "name", consumer.name.as_ref(),
+                    "pending", consumer.pending,
+                    "idle", consumer.idle as u64,
+                    "inactive", consumer.inactive.map_or(-1, |inactive| inactive as i64),
+                ])).collect()
+            }
+        };
+
+        let mut encoded_array = String::new();
+        encode_resp_value_array(&mut encoded_array, &reply);
+        Ok(encoded_array)
+    }
+
+    pub fn incr(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let (created, value) = {
+            let mut map_guard = self.map_state().map.write()?;
+            let created = !map_guard.contains_key(&commands[1]);
+            let val = map_guard.entry(Arc::clone(&commands[1])).or_insert(RedisValue::Number(0));
+            match val{
+                RedisValue::Number(n) => {
+                    *n += 1;
+                    (created, *n)
+                },
+                _ => return Ok("-ERR value is not an integer or out of range\r\n".to_string()), //tester expects this format
+            }
+        };
+
+        if created {
+            self.notify_keyspace_event(keyspace_events::NEW, "new", &commands[1])?;
+        }
+        self.notify_keyspace_event(keyspace_events::STRING, "incrby", &commands[1])?;
+        Ok(format!(":{}\r\n", value))
+    } 
+
+    pub fn multi(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>) -> RedisResult<String> {
+        client_state.set_multi_queue_mode(true);
+        Ok(format!("+OK\r\n"))
+    } 
+
+    pub fn info(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        match commands[1].to_uppercase().as_str(){
+            "REPLICATION" => {
+                let mut lines = Vec::new();
+                for (key, value) in self.server_state().map().iter() {
+                    lines.push(format!("{}:{}", key, value));
                 }
-                _ => Ok("$-1\r\n".to_string()) // fix error handling
+                let content = lines.join("\r\n");
+                Ok(format!("${}\r\n{}\r\n", content.len(), content))
+            }
+            "STATS" => {
+                let disconnections = self.channels_state().output_limit_disconnections.load(Ordering::SeqCst);
+                let content = format!("# Stats\r\nclient_output_buffer_limit_disconnections:{}", disconnections);
+                Ok(format!("${}\r\n{}\r\n", content.len(), content))
             }
-        } else { Ok("$-1\r\n".to_string())}
+            _ => Err(RedisError::InvalidCommand(format!("INFO subcommand '{}' not supported", commands[1]))),
+        }
     } 
 
-    pub fn rpush(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let key = &commands[1];
-        let count = {
-            let mut list_guard = self.list_state().list.lock()?;
-            let items = commands
-                .iter()
-                .skip(2)
-                .map(|v| RedisValue::String(Arc::clone(v)));
-            list_guard
-                .entry(Arc::clone(key))
-                .or_insert(VecDeque::new())
-                .extend(items);
-            list_guard.get(key)
-                .ok_or_else(|| RedisError::KeyNotFound(format!("Key {} not found", key)))?
-                .len()
-                .to_string()
-        };
+    pub fn zadd(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 4 {
+            return Ok("-ERR wrong number of arguments for 'zadd' command\r\n".to_string());
+        }
 
-        let mut waiters_guard = self.list_state().waiters.lock()?;
-        if let Some(waiting_queue) = waiters_guard.get_mut(key) {
-            let key_arc = Arc::clone(key);
-            while let Some(sender) = waiting_queue.pop_front() {
-                let mut list_guard = self.list_state().list.lock()?;
-                if let Some(deque) = list_guard.get_mut(key) {
-                    if let Some(value) = deque.pop_front() {
-                        drop(list_guard); // Release lock before sending
-                        match sender.try_send((Arc::clone(&key_arc), value)) {
-                            Ok(_) => break,
-                            Err(TrySendError::Full(_)) => return Err(RedisError::TooManyWaiters),
-                            Err(TrySendError::Closed(_)) => continue,
-                        }
+        let mut flags = ZAddFlags::default();
+        let mut ch = false;
+        let mut i = 2;
+        while i < commands.len() {
+            match commands[i].to_uppercase().as_str() {
+                "NX" => flags.nx = true,
+                "XX" => flags.xx = true,
+                "GT" => flags.gt = true,
+                "LT" => flags.lt = true,
+                "CH" => ch = true,
+                "INCR" => flags.incr = true,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        let args = &commands[i..];
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return Ok("-ERR syntax error\r\n".to_string());
+        }
+        if flags.nx && flags.xx {
+            return Ok("-ERR XX and NX options at the same time are not compatible\r\n".to_string());
+        }
+        if (flags.nx && (flags.gt || flags.lt)) || (flags.gt && flags.lt) {
+            return Ok("-ERR GT, LT, and/or NX options at the same time are not compatible\r\n".to_string());
+        }
+        if flags.incr && args.len() > 2 {
+            return Ok("-ERR INCR option supports a single increment-element pair\r\n".to_string());
+        }
+
+        // validate every score before touching the set
+        let mut pairs = Vec::with_capacity(args.len() / 2);
+        for pair in args.chunks_exact(2) {
+            match parse_score(&pair[0]) {
+                Some(score) => pairs.push((score, &pair[1])),
+                None => return Ok("-ERR value is not a valid float\r\n".to_string()),
+            }
+        }
+
+        let key = &commands[1];
+        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+        let existed = sorted_state_guard.contains_key(key);
+        let sorted_state = sorted_state_guard.entry(Arc::clone(key)).or_insert_with(SortedSet::new);
+
+        let (mut added, mut updated) = (0, 0);
+        let mut incr_result = None;
+        for (score, member) in pairs {
+            let outcome = match sorted_state.add(member, score, &flags) {
+                Ok(outcome) => outcome,
+                Err(()) => {
+                    if sorted_state.scores.len() == 0 {
+                        sorted_state_guard.remove(key);
                     }
+                    return Ok("-ERR resulting score is not a number (NaN)\r\n".to_string());
                 }
+            };
+            incr_result = match outcome {
+                ZAddOutcome::Added(score) => { added += 1; Some(score) },
+                ZAddOutcome::Updated(score) => { updated += 1; Some(score) },
+                ZAddOutcome::Unchanged(score) => Some(score),
+                ZAddOutcome::Skipped => None,
+            };
+        }
+
+        if sorted_state.scores.len() == 0 {
+            sorted_state_guard.remove(key);
+        }
+        drop(sorted_state_guard);
+        if added + updated > 0 {
+            if !existed {
+                self.notify_keyspace_event(keyspace_events::NEW, "new", key)?;
             }
+            self.notify_keyspace_event(keyspace_events::ZSET, if flags.incr { "zincr" } else { "zadd" }, key)?;
+        }
+        if added > 0 {
+            self.wake_sorted_set_waiters(key)?;
         }
 
-        Ok(format!(":{}\r\n", count))
-    } 
+        if flags.incr {
+            match incr_result {
+                Some(score) => {
+                    let score_str = score.to_string();
+                    Ok(format!("${}\r\n{}\r\n", score_str.len(), score_str))
+                }
+                None => Ok("$-1\r\n".to_string()),
+            }
+        } else if ch {
+            Ok(format!(":{}\r\n", added + updated))
+        } else {
+            Ok(format!(":{}\r\n", added))
+        }
+    }
 
-    pub fn lpush(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let mut list_guard = self.list_state().list.lock()?;
-        let items = commands
-            .iter()
-            .skip(2)
-            .map(|v| RedisValue::String(Arc::clone(v)));
+    pub fn zincrby(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() != 4 {
+            return Ok("-ERR wrong number of arguments for 'zincrby' command\r\n".to_string());
+        }
+        let zadd_commands = [
+            Arc::from("ZADD"),
+            Arc::clone(&commands[1]),
+            Arc::from("INCR"),
+            Arc::clone(&commands[2]),
+            Arc::clone(&commands[3]),
+        ];
+        self.zadd(&zadd_commands)
+    }
+
+    pub fn zrank(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        self.zrank_with(commands, false)
+    }
+
+    pub fn zrevrank(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zrank_with(commands, true)
+    }
+
+    fn zrank_with(&self, commands: &[Arc<str>], rev: bool) -> RedisResult<String> {
+        if commands.len() < 3 || commands.len() > 4 {
+            return Ok(format!("-ERR wrong number of arguments for '{}' command\r\n", commands[0].to_lowercase()));
+        }
         let key = &commands[1];
-        let deque = list_guard.entry(Arc::clone(key)).or_insert(VecDeque::new());
+        let memeber_name = &commands[2];
+        let withscore = match commands.get(3) {
+            Some(arg) if arg.to_uppercase() == "WITHSCORE" => true,
+            Some(_) => return Ok("-ERR syntax error\r\n".to_string()),
+            None => false,
+        };
+        let nil = if withscore { "*-1\r\n" } else { "$-1\r\n" };
 
-        for item in items.into_iter() {
-            deque.push_front(item);
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        let Some(sorted_state) = sorted_state_guard.get(key) else {
+            return Ok(nil.to_string());
+        };
+        match sorted_state.rank(memeber_name) {
+            Some(rank) => {
+                let rank = if rev { sorted_state.scores.len() - 1 - rank } else { rank };
+                if withscore {
+                    let score_str = sorted_state.members[memeber_name].to_string();
+                    Ok(format!("*2\r\n:{}\r\n${}\r\n{}\r\n", rank, score_str.len(), score_str))
+                } else {
+                    Ok(format!(":{}\r\n", rank))
+                }
+            }
+            None => Ok(nil.to_string()),
         }
+    }
 
-        let count = list_guard.get(key)
-            .ok_or_else(|| RedisError::KeyNotFound(format!("Key {} not found", key)))?
-            .len();
-        Ok(format!(":{}\r\n", count.to_string()))
-    } 
+    pub fn zrange(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        self.zrange_with(commands, ZRangeBy::Rank, false, false)
+    }
 
-    pub fn llen(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let list_guard = self.list_state().list.lock()?;
-        let len = match list_guard.get(&commands[1]) {
-            Some(list) => list.len().to_string(),
-            None => 0.to_string(),
+    pub fn zrevrange(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zrange_with(commands, ZRangeBy::Rank, true, true)
+    }
+
+    pub fn zrangebyscore(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zrange_with(commands, ZRangeBy::Score, false, true)
+    }
+
+    pub fn zrevrangebyscore(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zrange_with(commands, ZRangeBy::Score, true, true)
+    }
+
+    pub fn zrangebylex(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zrange_with(commands, ZRangeBy::Lex, false, true)
+    }
+
+    pub fn zrevrangebylex(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zrange_with(commands, ZRangeBy::Lex, true, true)
+    }
+
+    fn zrange_with(&self, commands: &[Arc<str>], by: ZRangeBy, rev: bool, legacy: bool) -> RedisResult<String> {
+        if commands.len() < 4 {
+            return Ok(format!("-ERR wrong number of arguments for '{}' command\r\n", commands[0].to_lowercase()));
+        }
+        let options = match ZRangeOptions::parse(&commands[4..], by, rev, legacy) {
+            Ok(options) => options,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
         };
 
-        Ok(format!(":{}\r\n", len))
-    } 
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        let empty = SortedSet::new();
+        let sorted_state = sorted_state_guard.get(&commands[1]).unwrap_or(&empty);
+        let members = match sorted_state.range_by(&commands[2], &commands[3], &options) {
+            Ok(members) => members,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
 
-    pub fn lpop(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let mut list_guard = self.list_state().list.lock()?;
-        match list_guard.get_mut(&commands[1]){
-            Some(list) => {
-                match commands.iter().skip(2).next(){
-                    Some(n) => {
-                        let mut popped_list = Vec::new();
-                        let len = n.parse::<usize>()?;
-                        for _ in 0..len{
-                            match list.pop_front(){
-                                Some(popped) => {
-                                    if let Some(val) = redis_value_as_string(popped){
-                                        popped_list.push(val);
-                                    }
-                                }
-                                None => (),
-                            }
-                        }
+        let mut elements = Vec::with_capacity(members.len() * if options.withscores { 2 } else { 1 });
+        for (score, member) in members {
+            elements.push(member);
+            if options.withscores {
+                elements.push(Arc::from(score.to_string()));
+            }
+        }
+        Ok(encode_resp_array_arc(&elements))
+    }
 
-                        Ok(encode_resp_array_arc(&popped_list))
-                    },
+    pub fn zrangestore(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 5 {
+            return Ok("-ERR wrong number of arguments for 'zrangestore' command\r\n".to_string());
+        }
+        let options = match ZRangeOptions::parse(&commands[5..], ZRangeBy::Rank, false, false) {
+            Ok(options) if options.withscores => return Ok("-ERR syntax error\r\n".to_string()),
+            Ok(options) => options,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
 
-                    None => {
-                        match list.pop_front(){
-                            Some(popped) => {
-                                if let Some(val) = popped.as_string(){
-                                    Ok(format!("${}\r\n{}\r\n", val.len(), val))
-                                } else {
-                                    Ok(format!("$-1\r\n"))
-                                }
-                            }
-                            None => Ok(format!("$-1\r\n")),
-                        }
+        let (destination, source) = (&commands[1], &commands[2]);
+        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+        let members = {
+            let empty = SortedSet::new();
+            let sorted_state = sorted_state_guard.get(source).unwrap_or(&empty);
+            match sorted_state.range_by(&commands[3], &commands[4], &options) {
+                Ok(members) => members,
+                Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+            }
+        };
+
+        let count = members.len();
+        let existed = self.remove_other_types(destination)? | sorted_state_guard.contains_key(destination);
+        if members.is_empty() {
+            sorted_state_guard.remove(destination);
+        } else {
+            let mut stored = SortedSet::new();
+            for (score, member) in members {
+                stored.insert(&member, score);
+            }
+            sorted_state_guard.insert(Arc::clone(destination), stored);
+        }
+        drop(sorted_state_guard);
+
+        self.notify_store("zrangestore", destination, count > 0, existed)?;
+        if count > 0 {
+            self.wake_sorted_set_waiters(destination)?;
+        }
+        Ok(format!(":{}\r\n", count))
+    }
+
+    pub fn zcard(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let key = &commands[1];
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        match sorted_state_guard.get(key){
+            Some(sorted_state) => Ok(format!(":{}\r\n", sorted_state.scores.len())),
+            None => Ok(format!(":0\r\n"))
+        }
+    }
+
+    pub fn zscore(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+        let (key, member) = (&commands[1], &commands[2]);
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        match sorted_state_guard.get(key){
+            Some(sorted_state) => {
+                match sorted_state.members.get(member){
+                    Some(score) => {
+                        let score_str = score.to_string();
+                        Ok(format!("${}\r\n{}\r\n", score_str.len(), score_str))
                     },
+                    None => Ok(format!(":-1\r\n")),
                 }
             },
-
-            None => Ok(format!("$-1\r\n"))
+            None => Ok(format!(":-1\r\n"))
         }
-    } 
+    }
 
-    pub async fn blpop(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+    pub fn zrem(&self, commands: &[Arc<str>]) -> RedisResult<String> {
         let key = &commands[1];
-
-        {
-            let mut list_guard = self.list_state().list.lock()?;
-            if let Some(list) = list_guard.get_mut(key){
-                if let Some(data) = list.pop_front(){
-                    if let Some(val) = data.as_string(){
-                        return Ok(encode_resp_array_arc(&[Arc::clone(key), Arc::clone(val)]))
+        let (removed, deleted) = {
+            let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+            match sorted_state_guard.get_mut(key){
+                Some(sorted_state) => {
+                    let removed = commands[2..].iter()
+                        .filter(|member| sorted_state.remove(member).is_some())
+                        .count();
+                    let deleted = sorted_state.scores.len() == 0;
+                    if deleted {
+                        sorted_state_guard.remove(key);
                     }
+                    (removed, deleted)
+                },
+                None => return Ok(":0\r\n".to_string())
+            }
+        };
+
+        if removed > 0 {
+            self.notify_keyspace_event(keyspace_events::ZSET, "zrem", key)?;
+        }
+        if deleted {
+            self.notify_keyspace_event(keyspace_events::GENERIC, "del", key)?;
+        }
+        Ok(format!(":{}\r\n", removed))
+    }
+
+    pub fn zmscore(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 3 {
+            return Ok("-ERR wrong number of arguments for 'zmscore' command\r\n".to_string());
+        }
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        let sorted_state = sorted_state_guard.get(&commands[1]);
+        let mut response = format!("*{}\r\n", commands.len() - 2);
+        for member in &commands[2..] {
+            match sorted_state.and_then(|sorted_state| sorted_state.members.get(member)) {
+                Some(score) => {
+                    let score_str = score.to_string();
+                    response.push_str(&format!("${}\r\n{}\r\n", score_str.len(), score_str));
                 }
+                None => response.push_str("$-1\r\n"),
             }
         }
+        Ok(response)
+    }
 
-        let mut receiver = {
-            let mut waiters_guard = self.list_state().waiters.lock()?;
-            let queue = waiters_guard.entry(Arc::clone(key)).or_insert(VecDeque::new());
-            if queue.len() > 10000 {
-                return Err(RedisError::Other("ERR_TOO_MANY_BLPOP_WAITERS_FOR_THE_KEY".to_string()))
+    pub fn zcount(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() != 4 {
+            return Ok("-ERR wrong number of arguments for 'zcount' command\r\n".to_string());
+        }
+        let (min, max) = match (ScoreBound::parse(&commands[2]), ScoreBound::parse(&commands[3])) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return Ok("-ERR min or max is not a float\r\n".to_string()),
+        };
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        match sorted_state_guard.get(&commands[1]) {
+            Some(sorted_state) => {
+                let (start, end) = sorted_state.score_ranks(min, max);
+                Ok(format!(":{}\r\n", end - start))
             }
+            None => Ok(":0\r\n".to_string()),
+        }
+    }
 
-            let (sender, receiver) = mpsc::channel(1);
-            queue.push_back(sender);
-            receiver
+    pub fn zlexcount(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() != 4 {
+            return Ok("-ERR wrong number of arguments for 'zlexcount' command\r\n".to_string());
+        }
+        let (min, max) = match (LexBound::parse(&commands[2]), LexBound::parse(&commands[3])) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return Ok("-ERR min or max not valid string range item\r\n".to_string()),
         };
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        match sorted_state_guard.get(&commands[1]) {
+            Some(sorted_state) => {
+                let (start, end) = sorted_state.lex_ranks(&min, &max);
+                Ok(format!(":{}\r\n", end - start))
+            }
+            None => Ok(":0\r\n".to_string()),
+        }
+    }
 
-        // Re-check the list after adding to waiters to avoid race condition
-        // where RPUSH adds item between our first check and adding to waiters
-        // {
-        //     let mut list_guard = self.list_state().list.lock().unwrap();
-        //     if let Some(list) = list_guard.get_mut(key){
-        //         if let Some(data) = list.pop_front(){
-        //             // Remove ourselves from waiters since we got the item directly
-        //             let mut waiters_guard = self.list_state().waiters.lock().unwrap();
-        //             if let Some(queue) = waiters_guard.get_mut(key){
-        //                 // Remove the last sender we just added
-        //                 queue.pop_back();
-        //             }
+    pub fn zrandmember(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 2 {
+            return Ok("-ERR wrong number of arguments for 'zrandmember' command\r\n".to_string());
+        }
+        let count = match commands.get(2).map(|count| count.parse::<i64>()) {
+            Some(Ok(count)) if count < 0 && count.unsigned_abs() > ZRANDMEMBER_MAX_REPEATED => {
+                return Ok("-ERR value is out of range\r\n".to_string());
+            }
+            Some(Ok(count)) => Some(count),
+            Some(Err(_)) => return Ok("-ERR value is not an integer or out of range\r\n".to_string()),
+            None => None,
+        };
+        let withscores = match commands.get(3) {
+            Some(arg) if arg.to_uppercase() == "WITHSCORES" && commands.len() == 4 => true,
+            Some(_) => return Ok("-ERR syntax error\r\n".to_string()),
+            None => false,
+        };
 
-        //             if let Some(val) = data.as_string(){
-        //                 return encode_resp_array(&vec![key.clone(), val.clone()])
-        //             }
-        //         }
-        //     }
-        // }
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        let Some(sorted_state) = sorted_state_guard.get(&commands[1]) else {
+            return Ok(if count.is_some() { "*0\r\n" } else { "$-1\r\n" }.to_string());
+        };
+        let len = sorted_state.scores.len();
 
-        let timeout: f64 = commands.last()
-            .ok_or_else(|| RedisError::InvalidCommand("BLPOP requires timeout argument".to_string()))?
-            .parse()?;
-        if timeout == 0.0 {
-            match receiver.recv().await{
-                Some((key_arc, value)) => {
-                    if let Some(val) = value.as_string(){
-                        Ok(encode_resp_array_str(&[key_arc.as_ref(), val]))
-                    } else {
-                        Ok(format!("*-1\r\n"))
-                    }
-                }
-                None => Ok(format!("*-1\r\n")),
-            }
+        let Some(count) = count else {
+            let (_, member) = sorted_state.slice(random_below(len), len, false, 0, Some(1)).remove(0);
+            return Ok(format!("${}\r\n{}\r\n", member.len(), member));
+        };
+
+        // a negative count may repeat members, a positive one returns distinct members
+        let ranks: Vec<usize> = if count < 0 {
+            (0..count.unsigned_abs()).map(|_| random_below(len)).collect()
+        } else if count as usize >= len {
+            (0..len).collect()
         } else {
-            tokio::select! {
-                result = receiver.recv() => {
-                    if let Some((key_arc, value)) = result{
-                        if let Some(val) = value.as_string(){
-                            Ok(encode_resp_array_str(&[key_arc.as_ref(), val]))
-                        } else {
-                            Ok(format!("*-1\r\n"))
-                        }
-                    } else {
-                        Ok(format!("*-1\r\n"))
-                    }
-                }
+            let mut picked = HashSet::new();
+            while picked.len() < count as usize {
+                picked.insert(random_below(len));
+            }
+            picked.into_iter().collect()
+        };
 
-                _ = sleep(Duration::from_secs_f64(timeout)) => {
-                    Ok(format!("*-1\r\n"))
-                }
+        let mut elements = Vec::with_capacity(ranks.len() * if withscores { 2 } else { 1 });
+        for rank in ranks {
+            let (score, member) = sorted_state.slice(rank, rank + 1, false, 0, None).remove(0);
+            elements.push(member);
+            if withscores {
+                elements.push(Arc::from(score.to_string()));
             }
         }
+        Ok(encode_resp_array_arc(&elements))
     }
-    
-    pub fn lrange(&self, key: &Arc<str>, start: &Arc<str>, stop: &Arc<str>) -> RedisResult<String> {
-        let list_guard = self.list_state().list.lock()?;
-        let array = match list_guard.get(key){
-            Some(vec) => {
-                let start = parse_wrapback(start.parse::<i64>()?, vec.len())?;
-                let stop = parse_wrapback(stop.parse::<i64>()?, vec.len())?;
-
-                if start >= vec.len(){
-                    VecDeque::new()
-                } else if stop >= vec.len(){
-                    vec.range(start..).cloned().collect()
-                } else if start > stop{
-                    VecDeque::new() // removed stop=start should be covered by last else
-                } else {
-                    vec.range(start..=stop).cloned().collect()
-                }
-            },
-            None => VecDeque::new()
-        };
 
-        Ok(encode_resp_array_arc(&collect_as_strings(array)))
-    } 
+    pub fn zremrangebyrank(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zremrange_with(commands, ZRangeBy::Rank)
+    }
 
-    pub fn type_command(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let map_guard = self.map_state().map.read()?;
-        let response = match map_guard.get(&commands[1]){
-            Some(val) => {
-                match val{
-                    RedisValue::Flags(_) => "flags".to_string(),
-                    RedisValue::Array(_) => "list".to_string(),
-                    RedisValue::String(_) => "string".to_string(),
-                    RedisValue::StringWithTimeout(_) => "string".to_string(),
-                    RedisValue::Stream(_) => "stream".to_string(),
-                    RedisValue::Number(_) => "number".to_string(),
-                }
-            },
-            None => "none".to_string()
-        };
+    pub fn zremrangebyscore(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zremrange_with(commands, ZRangeBy::Score)
+    }
 
-        Ok(format!("+{}\r\n", response))
-    } 
+    pub fn zremrangebylex(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zremrange_with(commands, ZRangeBy::Lex)
+    }
 
-    pub fn xadd(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+    fn zremrange_with(&self, commands: &[Arc<str>], by: ZRangeBy) -> RedisResult<String> {
+        if commands.len() != 4 {
+            return Ok(format!("-ERR wrong number of arguments for '{}' command\r\n", commands[0].to_lowercase()));
+        }
+        let event = match by {
+            ZRangeBy::Rank => "zremrangebyrank",
+            ZRangeBy::Score => "zremrangebyscore",
+            ZRangeBy::Lex => "zremrangebylex",
+        };
+        let options = ZRangeOptions { by, rev: false, limit: None, withscores: false };
         let key = &commands[1];
-
-        let has_waiters = {
-            let map_waiters_guard = self.map_state().waiters.lock()?;
-            map_waiters_guard.get(key).map_or(false, |q| !q.is_empty())
+        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+        let Some(sorted_state) = sorted_state_guard.get_mut(key) else {
+            return Ok(":0\r\n".to_string());
         };
 
-        let pairs_grouped = Arc::new(
-            commands[3..].chunks_exact(2)
-                .map(|chunk| (Arc::clone(&chunk[0]), Arc::clone(&chunk[1])))
-                .collect::<Vec<_>>()
-        );
+        let members = match sorted_state.range_by(&commands[2], &commands[3], &options) {
+            Ok(members) => members,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+        for (_, member) in &members {
+            sorted_state.remove(member);
+        }
+        let deleted = sorted_state.scores.len() == 0;
+        if deleted {
+            sorted_state_guard.remove(key);
+        }
+        drop(sorted_state_guard);
 
-        let result = {
-            let mut map_guard = self.map_state().map.write()?;
-            map_guard
-                .entry(Arc::clone(key))
-                .or_insert(RedisValue::Stream(StreamValue::new()))
-                .update_stream(&commands[2], Arc::clone(&pairs_grouped))?
-        };
-
-        if has_waiters {
-            let mut map_waiters_guard = self.map_state().waiters.lock()?;
-            if let Some(waiters_queue) = map_waiters_guard.get_mut(key) {
-                let stream_value = StreamValue::new_blocked(Arc::clone(&commands[2]), pairs_grouped);
-                while let Some(waiter) = waiters_queue.pop_front() {
-                    match waiter.try_send((Arc::clone(key), RedisValue::Stream(stream_value.clone()))) {
-                        Ok(_) => break,
-                        Err(TrySendError::Full(_)) => return Err(RedisError::TooManyWaiters),
-                        Err(TrySendError::Closed(_)) => continue,
-                    }
-                }
-            }
+        if !members.is_empty() {
+            self.notify_keyspace_event(keyspace_events::ZSET, event, key)?;
+        }
+        if deleted {
+            self.notify_keyspace_event(keyspace_events::GENERIC, "del", key)?;
         }
+        Ok(format!(":{}\r\n", members.len()))
+    }
 
-        Ok(result)
-    } 
+    pub fn zunionstore(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zsetop_store(commands, ZSetOp::Union)
+    }
 
-    pub fn xrange(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let mut encoded_array = String::new();
-        let map_guard = self.map_state().map.read()?;
-        let stream_value = map_guard.get(&commands[1])
-            .ok_or_else(|| RedisError::KeyNotFound(format!("Key {} not found", commands[1])))?;
-        let values = stream_value.get_stream_range(&commands[2], Some(&commands[3]))?;
-        if !values.is_empty() {
-            encode_resp_value_array(&mut encoded_array, &values);
-            Ok(encoded_array)
-        } else {
-            Ok(format!("*-1\r\n"))
-        }
-    } 
+    pub fn zinterstore(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zsetop_store(commands, ZSetOp::Inter)
+    }
 
-    pub async fn xread(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        match commands[1].as_ref(){
-            "streams" => {
-                let map_guard = self.map_state().map.read()?;
-                let key_tokens = commands.iter().skip(2)
-                .filter(|token| map_guard.get(*token).is_some())
-                .collect::<Vec<_>>();
+    pub fn zdiffstore(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zsetop_store(commands, ZSetOp::Diff)
+    }
 
-                let id_tokens = commands.iter().skip(2 + key_tokens.len()).collect::<Vec<_>>();
-                let mut encoded_array = String::new();
-                let mut key_entries = Vec::new();
+    pub fn zunion(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zsetop(commands, ZSetOp::Union)
+    }
 
-                for (key, id) in key_tokens.iter().zip(id_tokens){
-                    let values = map_guard
-                    .get(*key)
-                    .ok_or_else(|| RedisError::KeyNotFound(format!("Key {} not found", key.as_ref())))?
-                    .get_stream_range(id, None)?;
-                    if !values.is_empty() { key_entries.push(json!([key.as_ref(), values]));}
-                }
-        
-                if !key_entries.is_empty() {
-                    encode_resp_value_array(&mut encoded_array, &key_entries);
-                    Ok(encoded_array)
-                } else {
-                    Ok(format!("*-1\r\n"))
-                }
-            },
+    pub fn zinter(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zsetop(commands, ZSetOp::Inter)
+    }
 
-            "block" => {
-                let timeout = commands[2].parse::<u64>()?;
-                let key = &commands[4];
+    pub fn zdiff(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zsetop(commands, ZSetOp::Diff)
+    }
 
-                let mut receiver = {
-                    let mut waiters_guard = self.map_state().waiters.lock()?;
-                    let queue = waiters_guard.entry(Arc::clone(key)).or_insert(VecDeque::new());
-                    if queue.len() > 10000{
-                        return Err(RedisError::Other("ERR_TOO_MANY_XREAD_WAITERS_FOR_THE_KEY".to_string()))
-                    }
+    fn zsetop_store(&self, commands: &[Arc<str>], op: ZSetOp) -> RedisResult<String> {
+        let command = commands[0].to_lowercase();
+        if commands.len() < 3 {
+            return Ok(format!("-ERR wrong number of arguments for '{}' command\r\n", command));
+        }
+        let options = match ZSetOpOptions::parse(&commands[2..], &command, op, false) {
+            Ok(options) => options,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
 
-                    let (sender, receiver) = mpsc::channel(1);
-                    queue.push_back(sender);
-                    receiver
-                };
+        // sorted sets are always locked before the main map
+        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+        let result = {
+            let map_guard = self.map_state().map.read()?;
+            match ZSetInput::collect(&options.keys, &sorted_state_guard, &map_guard) {
+                Some(inputs) => zset_combine(op, &inputs, &options.weights, options.aggregate),
+                None => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+            }
+        };
 
-                if timeout == 0 {
-                    match receiver.recv().await{
-                        Some((key_arc, redis_val)) => {
-                            if let Some(value_array) = redis_val.get_blocked_result(){
-                                let mut encoded_array = String::new();
-                                encode_resp_value_array(&mut encoded_array, &vec![json!([key_arc.as_ref(), value_array])]);
-                                return Ok(encoded_array)
-                            }
-                            Ok(format!("*-1\r\n"))
-                        },
-                        None => Ok(format!("*-1\r\n"))
-                    }
-                } else {
-                    tokio::select! {
-                        result = receiver.recv() => {
-                            if let Some((key_arc, redis_val)) = result {
-                                if let Some(value_array) = redis_val.get_blocked_result(){
-                                    let mut encoded_array = String::new();
-                                    encode_resp_value_array(&mut encoded_array, &vec![json!([key_arc.as_ref(), value_array])]);
-                                    return Ok(encoded_array)
-                                }
-                                Ok(format!("*-1\r\n"))
-                            } else {
-                                Ok(format!("*-1\r\n"))
-                            }
-                        },
+        let count = result.scores.len();
+        let existed = self.remove_other_types(&commands[1])? | sorted_state_guard.contains_key(&commands[1]);
+        if count == 0 {
+            sorted_state_guard.remove(&commands[1]);
+        } else {
+            sorted_state_guard.insert(Arc::clone(&commands[1]), result);
+        }
+        drop(sorted_state_guard);
+        self.notify_store(&command, &commands[1], count > 0, existed)?;
+        if count > 0 {
+            self.wake_sorted_set_waiters(&commands[1])?;
+        }
+        Ok(format!(":{}\r\n", count))
+    }
 
-                        _ = sleep(Duration::from_millis(timeout)) => {
-                            Ok(format!("*-1\r\n"))
-                        },
-                    }
-                }
-            },
+    fn zsetop(&self, commands: &[Arc<str>], op: ZSetOp) -> RedisResult<String> {
+        let command = commands[0].to_lowercase();
+        let options = match ZSetOpOptions::parse(&commands[1..], &command, op, true) {
+            Ok(options) => options,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        let map_guard = self.map_state().map.read()?;
+        let result = match ZSetInput::collect(&options.keys, &sorted_state_guard, &map_guard) {
+            Some(inputs) => zset_combine(op, &inputs, &options.weights, options.aggregate),
+            None => return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+        };
 
-            _ => Err(RedisError::InvalidCommand("Invalid XREAD subcommand".to_string()))
+        let mut elements = Vec::new();
+        for (score, member) in result.scores.range(0, result.scores.len()) {
+            elements.push(Arc::clone(member));
+            if options.withscores {
+                elements.push(Arc::from(score.to_string()));
+            }
         }
+        Ok(encode_resp_array_arc(&elements))
     }
 
-    pub fn incr(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let mut map_guard = self.map_state().map.write()?;
-        let val = map_guard.entry(Arc::clone(&commands[1])).or_insert(RedisValue::Number(0));
-        match val{
-            RedisValue::Number(n) => {
-                *n += 1;
-                Ok(format!(":{}\r\n", n))
-            },
-            _ => Ok(format!("-ERR value is not an integer or out of range\r\n")), //tester expects this format
+    pub fn zintercard(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        let numkeys = match commands.get(1).map(|numkeys| numkeys.parse::<i64>()) {
+            Some(Ok(numkeys)) if numkeys > 0 => numkeys as usize,
+            Some(_) => return Ok("-ERR numkeys should be greater than 0\r\n".to_string()),
+            None => return Ok("-ERR wrong number of arguments for 'zintercard' command\r\n".to_string()),
+        };
+        if commands.len() < 2 + numkeys {
+            return Ok("-ERR Number of keys can't be greater than number of args\r\n".to_string());
         }
-    } 
+        let keys = &commands[2..2 + numkeys];
+        let limit = match &commands[2 + numkeys..] {
+            [] => 0,
+            [option, limit] if option.to_uppercase() == "LIMIT" => match limit.parse::<i64>() {
+                Ok(limit) if limit >= 0 => limit as usize,
+                _ => return Ok("-ERR LIMIT can't be negative\r\n".to_string()),
+            },
+            _ => return Ok("-ERR syntax error\r\n".to_string()),
+        };
 
-    pub fn multi(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>) -> RedisResult<String> {
-        client_state.set_multi_queue_mode(true);
-        Ok(format!("+OK\r\n"))
-    } 
+        let sorted_state_guard = self.sorted_set_state.set.read()?;
+        let map_guard = self.map_state().map.read()?;
+        let Some(inputs) = ZSetInput::collect(keys, &sorted_state_guard, &map_guard) else {
+            return Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string());
+        };
+        let Some(smallest) = inputs.iter().min_by_key(|input| input.len()) else {
+            return Ok(":0\r\n".to_string());
+        };
 
-    pub fn info(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        match commands[1].to_uppercase().as_str(){
-            "REPLICATION" => {
-                let mut lines = Vec::new();
-                for (key, value) in self.server_state().map().iter() {
-                    lines.push(format!("{}:{}", key, value));
+        // LIMIT 0 means no limit
+        let mut count = 0;
+        for (member, _) in smallest.iter() {
+            if inputs.iter().all(|input| input.score(member).is_some()) {
+                count += 1;
+                if count == limit {
+                    break;
                 }
-                let content = lines.join("\r\n");
-                Ok(format!("${}\r\n{}\r\n", content.len(), content))
             }
-            _ => Err(RedisError::InvalidCommand(format!("INFO subcommand '{}' not supported", commands[1]))),
         }
-    } 
+        Ok(format!(":{}\r\n", count))
+    }
 
-    pub fn zadd(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let key = Arc::clone(&commands[1]);
-        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
-        let sorted_state = sorted_state_guard.entry(key).or_insert_with(|| SortedSet::new());
+    pub fn zpopmin(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zpop_with(commands, false)
+    }
+
+    pub fn zpopmax(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        self.zpop_with(commands, true)
+    }
+
+    fn zpop_with(&self, commands: &[Arc<str>], max: bool) -> RedisResult<String> {
+        let count = match commands.get(2).map(|count| count.parse::<i64>()) {
+            Some(Ok(count)) if count >= 0 => count as usize,
+            Some(_) => return Ok("-ERR value is out of range, must be positive\r\n".to_string()),
+            None => 1,
+        };
+        match self.zpop_first(&commands[1..2], max, count)? {
+            Some((_, popped)) => Ok(encode_resp_array_arc(&encode_popped(popped))),
+            None => Ok("*0\r\n".to_string()),
+        }
+    }
+
+    pub fn zmpop(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        let (keys, max, count) = match parse_zmpop(&commands[1..]) {
+            Ok(args) => args,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+        match self.zpop_first(&keys, max, count)? {
+            Some((key, popped)) => Ok(encode_mpopped(&key, popped)),
+            None => Ok("*-1\r\n".to_string()),
+        }
+    }
+
+    pub async fn bzpopmin(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, commands: &[Arc<str>], can_block: bool) -> RedisResult<String> {
+        self.bzpop_with(client_state, commands, false, can_block).await
+    }
+
+    pub async fn bzpopmax(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, commands: &[Arc<str>], can_block: bool) -> RedisResult<String> {
+        self.bzpop_with(client_state, commands, true, can_block).await
+    }
 
-        let mut new_members = 0;
-        let args = &commands[2..];
-        for i in (0..args.len()).step_by(2){
-            let score = args[i].parse::<f64>()?;
-            let member = &args[i+1];
-            if let Some(old_score) = sorted_state.members.get_mut(member){
-                sorted_state.scores.remove(&(OrderedFloat::from(*old_score), Arc::clone(member)));
-                *old_score = score;
-            } else {
-                sorted_state.members.insert(Arc::clone(&member), score);
-                new_members += 1;
+    async fn bzpop_with(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, commands: &[Arc<str>], max: bool, can_block: bool) -> RedisResult<String> {
+        if commands.len() < 3 {
+            return Ok(format!("-ERR wrong number of arguments for '{}' command\r\n", commands[0].to_lowercase()));
+        }
+        let timeout = match parse_block_timeout(&commands[commands.len() - 1]) {
+            Ok(timeout) => timeout,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+        match self.zpop_blocking(&commands[1..commands.len() - 1], max, 1, timeout, can_block).await? {
+            Some((key, popped)) => {
+                client_state.push_effect(zpop_effect(&key, max, popped.len()));
+                let mut elements = vec![key];
+                elements.extend(encode_popped(popped));
+                Ok(encode_resp_array_arc(&elements))
             }
+            None => Ok("*-1\r\n".to_string()),
+        }
+    }
 
-            sorted_state.scores.insert((OrderedFloat::from(score), Arc::clone(&member)));
+    pub async fn bzmpop(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, commands: &[Arc<str>], can_block: bool) -> RedisResult<String> {
+        if commands.len() < 2 {
+            return Ok("-ERR wrong number of arguments for 'bzmpop' command\r\n".to_string());
+        }
+        let timeout = match parse_block_timeout(&commands[1]) {
+            Ok(timeout) => timeout,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+        let (keys, max, count) = match parse_zmpop(&commands[2..]) {
+            Ok(args) => args,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+        match self.zpop_blocking(&keys, max, count, timeout, can_block).await? {
+            Some((key, popped)) => {
+                client_state.push_effect(zpop_effect(&key, max, popped.len()));
+                Ok(encode_mpopped(&key, popped))
+            }
+            None => Ok("*-1\r\n".to_string()),
         }
+    }
 
-        Ok(format!(":{}\r\n", new_members))
-    } 
+    // Pops from the first key holding a sorted set, deleting it once empty
+    fn zpop_first(&self, keys: &[Arc<str>], max: bool, count: usize) -> RedisResult<Option<ZPopped>> {
+        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+        for key in keys {
+            if let Some(sorted_state) = sorted_state_guard.get_mut(key) {
+                let popped = sorted_state.pop(max, count);
+                let deleted = sorted_state.scores.len() == 0;
+                if deleted {
+                    sorted_state_guard.remove(key);
+                }
+                drop(sorted_state_guard);
 
-    pub fn zrank(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let key = &commands[1];
-        let memeber_name = &commands[2];
-        let sorted_state_guard = self.sorted_set_state.set.read()?;
-        match sorted_state_guard.get(key){
-            Some(sorted_state) => {
-                match sorted_state.scores.iter().position(|(_, member)| member == memeber_name){
-                    Some(rank) => Ok(format!(":{}\r\n", rank)),
-                    None => Ok(format!("$-1\r\n"))
+                if !popped.is_empty() {
+                    self.notify_keyspace_event(keyspace_events::ZSET, if max { "zpopmax" } else { "zpopmin" }, key)?;
+                }
+                if deleted {
+                    self.notify_keyspace_event(keyspace_events::GENERIC, "del", key)?;
                 }
+                return Ok(Some((Arc::clone(key), popped)));
             }
-            None => Ok(format!("$-1\r\n"))
         }
+        Ok(None)
     }
 
-    pub fn zrange(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let key = &commands[1];
-        let empty_array = format!("*0\r\n");
-        let sorted_state_guard = self.sorted_set_state.set.read()?;
-        match sorted_state_guard.get(key){
-            Some(sorted_state) => {
-                let set_len = sorted_state.scores.len();
-                let start = parse_wrapback(commands[2].parse::<i64>()?, set_len)?;
-                let stop = parse_wrapback(commands[3].parse::<i64>()?, set_len)?;
-
-                if start >= sorted_state.scores.len(){
-                    Ok(empty_array)
-                } else if start > stop {
-                    Ok(empty_array)
-                } else if stop >= sorted_state.scores.len(){
-                    let elements = sorted_state.scores.iter().skip(start).take(set_len - start).map(|(_score, name)| name).collect::<Vec<_>>();
-                    Ok(encode_resp_ref_array_arc(&elements))
-                } else {
-                    let elements = sorted_state.scores.iter().skip(start).take(stop - start + 1).map(|(_score, name)| name).collect::<Vec<_>>();
-                    Ok(encode_resp_ref_array_arc(&elements))
+    async fn zpop_blocking(&self, keys: &[Arc<str>], max: bool, count: usize, timeout: Duration, can_block: bool) -> RedisResult<Option<ZPopped>> {
+        if !can_block {
+            return self.zpop_first(keys, max, count);
+        }
+        // a deadline past what Instant can hold is as good as waiting forever
+        let deadline = match timeout.is_zero() {
+            true => None,
+            false => tokio::time::Instant::now().checked_add(timeout),
+        };
+
+        let keys: Arc<[Arc<str>]> = Arc::from(keys);
+        let (sender, mut receiver) = mpsc::channel(1);
+        {
+            let _keyspace_guard = self.keyspace_step(can_block).await;
+            let mut waiters_guard = self.sorted_set_state.waiters.lock()?;
+            // tried under the waiters lock, so members added after this are handed to us
+            if let Some(popped) = self.zpop_first(&keys, max, count)? {
+                return Ok(Some(popped));
+            }
+            for key in keys.iter() {
+                let queue = waiters_guard.entry(Arc::clone(key)).or_insert(VecDeque::new());
+                queue.retain(|waiter| !waiter.sender.is_closed());
+                if queue.len() > 10000 {
+                    return Err(RedisError::Other("ERR_TOO_MANY_BZPOP_WAITERS_FOR_THE_KEY".to_string()))
                 }
+                queue.push_back(SortedSetWaiter { keys: Arc::clone(&keys), max, count, sender: sender.clone() });
             }
-            None => Ok(empty_array)
         }
-    }
 
-    pub fn zcard(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let key = &commands[1];
-        let sorted_state_guard = self.sorted_set_state.set.read()?;
-        match sorted_state_guard.get(key){
-            Some(sorted_state) => Ok(format!(":{}\r\n", sorted_state.scores.len())),
-            None => Ok(format!(":0\r\n"))
+        let mut popped = match deadline {
+            Some(deadline) => tokio::select! {
+                popped = receiver.recv() => popped,
+                _ = tokio::time::sleep_until(deadline) => None,
+            },
+            None => receiver.recv().await,
+        };
+        if popped.is_none() {
+            // timed out, leave the queues but keep members handed over just before
+            let mut waiters_guard = self.sorted_set_state.waiters.lock()?;
+            remove_sorted_set_waiter(&mut waiters_guard, &keys, &sender);
+            popped = receiver.try_recv().ok();
         }
+        // handed over by another client, replicas pop after its write
+        if popped.is_some() {
+            self.keyspace_barrier().await;
+        }
+        Ok(popped)
     }
 
-    pub fn zscore(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let (key, member) = (&commands[1], &commands[2]);
-        let sorted_state_guard = self.sorted_set_state.set.read()?;
-        match sorted_state_guard.get(key){
-            Some(sorted_state) => {
-                match sorted_state.members.get(member){
-                    Some(score) => {
-                        let score_str = score.to_string();
-                        Ok(format!("${}\r\n{}\r\n", score_str.len(), score_str))
-                    },
-                    None => Ok(format!(":-1\r\n")),
+    // Hands the members to blocked clients in the order they blocked, each taking
+    // what it asked for, until the set runs out
+    fn wake_sorted_set_waiters(&self, key: &Arc<str>) -> RedisResult<()> {
+        let mut waiters_guard = self.sorted_set_state.waiters.lock()?;
+        while let Some(waiter) = waiters_guard.get_mut(key).and_then(|queue| queue.pop_front()) {
+            if waiter.sender.is_closed() {
+                continue;
+            }
+            let Some(popped) = self.zpop_first(std::slice::from_ref(key), waiter.max, waiter.count)? else {
+                waiters_guard.entry(Arc::clone(key)).or_insert(VecDeque::new()).push_front(waiter);
+                break;
+            };
+            // served once, so it gives up its place on its other keys
+            remove_sorted_set_waiter(&mut waiters_guard, &waiter.keys, &waiter.sender);
+            if let Err(TrySendError::Closed((_, popped)) | TrySendError::Full((_, popped))) = waiter.sender.try_send(popped) {
+                // the client went away in between, the members go back, announced like
+                // a ZADD since the pop was already notified
+                let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+                let created = !sorted_state_guard.contains_key(key);
+                let sorted_state = sorted_state_guard.entry(Arc::clone(key)).or_insert_with(SortedSet::new);
+                for (score, member) in popped {
+                    sorted_state.insert(&member, score);
                 }
-            },
-            None => Ok(format!(":-1\r\n"))
+                drop(sorted_state_guard);
+                if created {
+                    self.notify_keyspace_event(keyspace_events::NEW, "new", key)?;
+                }
+                self.notify_keyspace_event(keyspace_events::ZSET, "zadd", key)?;
+            }
+        }
+        if waiters_guard.get(key).is_some_and(|queue| queue.is_empty()) {
+            waiters_guard.remove(key);
         }
+        Ok(())
     }
 
-    pub fn zrem(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let (key, member) = (&commands[1], &commands[2]);
-        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
-        match sorted_state_guard.get_mut(key){
-            Some(sorted_state) => {
-                if let Some(score) = sorted_state.members.remove(member){
-                    sorted_state.scores.remove(&(OrderedFloat::from(score), Arc::clone(member)));
-                    Ok(format!(":1\r\n"))
-                } else { Ok(format!(":0\r\n")) }
-            },
-            None => Ok(format!(":0\r\n"))
+    pub fn geoadd(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 5 {
+            return Ok("-ERR wrong number of arguments for 'geoadd' command\r\n".to_string());
         }
-    }
 
-    pub fn geoadd(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let (key, longitude_str, latitude_str, member) = (&commands[1], &commands[2], &commands[3], &commands[4]);
-        let (longitude, latitude) = (longitude_str.parse::<f64>()?, latitude_str.parse::<f64>()?);
-        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+        let mut flags = ZAddFlags::default();
+        let mut ch = false;
+        let mut i = 2;
+        while i < commands.len() {
+            match commands[i].to_uppercase().as_str() {
+                "NX" => flags.nx = true,
+                "XX" => flags.xx = true,
+                "CH" => ch = true,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        let args = &commands[i..];
+        if args.is_empty() || !args.len().is_multiple_of(3) || (flags.nx && flags.xx) {
+            return Ok("-ERR syntax error\r\n".to_string());
+        }
 
-        if -180.0 >= longitude || longitude >= 180.0 {
-            Ok(format!("-ERR longitude is invlaid\r\n"))
-        } else if -85.05112878 >= latitude || latitude >= 85.05112878 {
-            Ok(format!("-ERR latitude is invlaid\r\n"))
-        } else { 
-            let sorted_state = sorted_state_guard.entry(Arc::clone(key)).or_insert_with(|| SortedSet::new());
-            let score = encode_coordinates_to_score(latitude, longitude) as f64;
-            sorted_state.members.insert(Arc::clone(&member), score);
-            sorted_state.scores.insert((OrderedFloat::from(0), Arc::clone(&member)));
+        // validate every triple before touching the set
+        let mut points = Vec::with_capacity(args.len() / 3);
+        for triple in args.chunks_exact(3) {
+            match parse_lonlat(&triple[0], &triple[1]) {
+                Ok((longitude, latitude)) => points.push((encode_coordinates_to_score(latitude, longitude) as f64, &triple[2])),
+                Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+            }
+        }
 
-            Ok(format!(":1\r\n"))
+        let key = &commands[1];
+        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+        let existed = sorted_state_guard.contains_key(key);
+        let sorted_state = sorted_state_guard.entry(Arc::clone(key)).or_insert_with(SortedSet::new);
+
+        let (mut added, mut updated) = (0, 0);
+        for (score, member) in points {
+            match sorted_state.add(member, score, &flags) {
+                Ok(ZAddOutcome::Added(_)) => added += 1,
+                Ok(ZAddOutcome::Updated(_)) => updated += 1,
+                _ => {}
+            }
+        }
+        if sorted_state.scores.len() == 0 {
+            sorted_state_guard.remove(key);
+        }
+        drop(sorted_state_guard);
+        // GEOADD is a ZADD underneath, and reports as one
+        if added + updated > 0 {
+            if !existed {
+                self.notify_keyspace_event(keyspace_events::NEW, "new", key)?;
+            }
+            self.notify_keyspace_event(keyspace_events::ZSET, "zadd", key)?;
         }
+        if added > 0 {
+            self.wake_sorted_set_waiters(key)?;
+        }
+
+        Ok(format!(":{}\r\n", if ch { added + updated } else { added }))
     }
 
     pub fn geopos(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
@@ -1214,43 +4232,167 @@ impl RedisState<Arc<str>, RedisValue>{
         }
     }
 
-    pub fn geodist(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
+    pub fn geodist(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() != 4 && commands.len() != 5 {
+            return Ok("-ERR wrong number of arguments for 'geodist' command\r\n".to_string());
+        }
+        let unit = match commands.get(4).map(|unit| parse_geo_unit(unit)).unwrap_or(Ok(1.0)) {
+            Ok(unit) => unit,
+            Err(e) => return Ok(format!("-ERR {}\r\n", e)),
+        };
+
         let (key, from, to) = (&commands[1], &commands[2], &commands[3]);
         let sorted_state_guard = self.sorted_set_state.set.read()?;
-        match sorted_state_guard.get(key){
-            Some(sorted_state) => {
-                let from_score = sorted_state.members.get(from).ok_or(RedisError::KeyNotFound(from.to_string()))?;
-                let to_score = sorted_state.members.get(to).ok_or(RedisError::KeyNotFound(to.to_string()))?;
-                let from_coord = decode_score_to_coordinates(*from_score as u64);
-                let to_coord = decode_score_to_coordinates(*to_score as u64);
-                let distance = haversine_distance(&from_coord, &to_coord).to_string();
+        let scores = sorted_state_guard.get(key)
+            .and_then(|sorted_state| Some((*sorted_state.members.get(from)?, *sorted_state.members.get(to)?)));
+        match scores {
+            Some((from_score, to_score)) => {
+                let from_coord = decode_score_to_coordinates(from_score as u64);
+                let to_coord = decode_score_to_coordinates(to_score as u64);
+                let distance = format!("{:.4}", haversine_distance(&from_coord, &to_coord) / unit);
                 Ok(format!("${}\r\n{}\r\n", distance.len(), distance))
             }
             None => Ok("$-1\r\n".to_string())
         }
-
     }
 
-    pub fn geosearch(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
-        let (key, lon_str, lat_str, radius_str) = (&commands[1], &commands[3], &commands[4], &commands[6]);
+    pub fn geohash(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 2 {
+            return Ok("-ERR wrong number of arguments for 'geohash' command\r\n".to_string());
+        }
+        let (key, members) = (&commands[1], &commands[2..]);
         let sorted_state_guard = self.sorted_set_state.set.read()?;
-        let from_coord = coord_from_str(lon_str, lat_str)?;
-        let radius = radius_str.parse::<f64>()?;
-        let mut within_radius = Vec::new();
-        match sorted_state_guard.get(key){
-            Some(sorted_state) => {
-                for (member, score) in sorted_state.members.iter(){
-                    let to_coord = decode_score_to_coordinates(*score as u64);
-                    if radius > haversine_distance(&from_coord, &to_coord){
-                        within_radius.push(member.as_ref());
-                    }
-                }
+        let hashes = members.iter()
+            .map(|member| {
+                sorted_state_guard.get(key)
+                    .and_then(|sorted_state| sorted_state.members.get(member))
+                    .map(|score| geohash_string(&decode_score_to_coordinates(*score as u64)))
+            })
+            .collect::<Vec<_>>();
 
-                Ok(encode_resp_array_str(&within_radius))
+        let mut resp = format!("*{}\r\n", hashes.len());
+        for hash in hashes {
+            match hash {
+                Some(hash) => resp.push_str(&format!("${}\r\n{}\r\n", hash.len(), hash)),
+                None => resp.push_str("$-1\r\n"),
             }
-            None => Ok("$-1\r\n".to_string())
+        }
+        Ok(resp)
+    }
+
+    pub fn geosearch(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 2 {
+            return Ok("-ERR wrong number of arguments for 'geosearch' command\r\n".to_string());
+        }
+        match GeoSearchOptions::parse(&commands[2..], GeoCommand::Search, None, None, 1.0) {
+            Ok(options) => self.geo_search_with(&commands[1], options),
+            Err(e) => Ok(format!("-ERR {}\r\n", e)),
+        }
+    }
+
+    pub fn geosearchstore(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 3 {
+            return Ok("-ERR wrong number of arguments for 'geosearchstore' command\r\n".to_string());
+        }
+        match GeoSearchOptions::parse(&commands[3..], GeoCommand::SearchStore, None, None, 1.0) {
+            Ok(mut options) => {
+                let storedist = options.store.is_some();
+                options.store = Some((Arc::clone(&commands[1]), storedist));
+                self.geo_search_with(&commands[2], options)
+            }
+            Err(e) => Ok(format!("-ERR {}\r\n", e)),
+        }
+    }
+
+    pub fn georadius(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 6 {
+            return Ok("-ERR wrong number of arguments for 'georadius' command\r\n".to_string());
+        }
+        let parsed = parse_lonlat(&commands[2], &commands[3]).and_then(|(lon, lat)| {
+            let radius = parse_geo_distance(&commands[4])?;
+            let unit = parse_geo_unit(&commands[5])?;
+            let shape = GeoShape::Radius(radius * unit);
+            GeoSearchOptions::parse(&commands[6..], GeoCommand::Radius, Some(GeoFrom::LonLat(lon, lat)), Some(shape), unit)
+        });
+        match parsed {
+            Ok(options) => self.geo_search_with(&commands[1], options),
+            Err(e) => Ok(format!("-ERR {}\r\n", e)),
+        }
+    }
+
+    pub fn georadiusbymember(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 5 {
+            return Ok("-ERR wrong number of arguments for 'georadiusbymember' command\r\n".to_string());
+        }
+        let parsed = parse_geo_distance(&commands[3]).and_then(|radius| {
+            let unit = parse_geo_unit(&commands[4])?;
+            let shape = GeoShape::Radius(radius * unit);
+            GeoSearchOptions::parse(&commands[5..], GeoCommand::Radius, Some(GeoFrom::Member(Arc::clone(&commands[2]))), Some(shape), unit)
+        });
+        match parsed {
+            Ok(options) => self.geo_search_with(&commands[1], options),
+            Err(e) => Ok(format!("-ERR {}\r\n", e)),
+        }
+    }
+
+    fn geo_search_with(&self, key: &Arc<str>, options: GeoSearchOptions) -> RedisResult<String> {
+        let mut matches = {
+            let sorted_state_guard = self.sorted_set_state.set.read()?;
+            match sorted_state_guard.get(key) {
+                Some(sorted_state) => {
+                    let center = match &options.from {
+                        Some(GeoFrom::Member(member)) => match sorted_state.members.get(member) {
+                            Some(score) => decode_score_to_coordinates(*score as u64),
+                            None => return Ok("-ERR could not decode requested zset member\r\n".to_string()),
+                        },
+                        Some(GeoFrom::LonLat(lon, lat)) => Coordinates::new(*lon, *lat),
+                        None => unreachable!("GEO options are validated while parsing"),
+                    };
+                    let shape = options.shape.expect("GEO options are validated while parsing");
+                    let limit = if options.any { options.count } else { None };
+                    geo_matches(sorted_state, &center, shape, limit)
+                }
+                None => Vec::new(),
+            }
+        };
+
+        match options.sort {
+            GeoSort::Asc => matches.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+            GeoSort::Desc => matches.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+            GeoSort::Unsorted => (),
+        }
+        if let Some(count) = options.count {
+            matches.truncate(count);
         }
 
+        match &options.store {
+            Some((destination, storedist)) => {
+                let count = matches.len();
+                let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+                let existed = self.remove_other_types(destination)? | sorted_state_guard.contains_key(destination);
+                if matches.is_empty() {
+                    sorted_state_guard.remove(destination);
+                } else {
+                    let mut stored = SortedSet::new();
+                    for found in matches {
+                        let score = if *storedist { found.distance / options.unit } else { found.score as f64 };
+                        stored.insert(&found.member, score);
+                    }
+                    sorted_state_guard.insert(Arc::clone(destination), stored);
+                }
+                drop(sorted_state_guard);
+                let event = match options.command {
+                    GeoCommand::Radius => "georadiusstore",
+                    _ => "geosearchstore",
+                };
+                self.notify_store(event, destination, count > 0, existed)?;
+                if count > 0 {
+                    self.wake_sorted_set_waiters(destination)?;
+                }
+                Ok(format!(":{}\r\n", count))
+            }
+            None => Ok(options.encode(&matches)),
+        }
     }
 
     pub fn acl(&mut self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
@@ -1293,8 +4435,137 @@ impl RedisState<Arc<str>, RedisValue>{
         }
     }
 
+    /// HELLO [protover [AUTH username password] [SETNAME clientname]]
+    pub fn hello(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, commands: &[Arc<str>]) -> RedisResult<String> {
+        let mut protocol = client_state.protocol();
+        if let Some(version) = commands.get(1) {
+            protocol = match version.parse::<i64>() {
+                Ok(2) => 2,
+                Ok(3) => 3,
+                Ok(_) => return Ok("-NOPROTO unsupported protocol version\r\n".to_string()),
+                Err(_) => return Ok("-ERR Protocol version is not an integer or out of range\r\n".to_string()),
+            };
+        }
+
+        let mut i = 2;
+        while i < commands.len() {
+            match commands[i].to_uppercase().as_str() {
+                "AUTH" if i + 2 < commands.len() => {
+                    match self.users_state.check_password(&commands[i + 1], &commands[i + 2]) {
+                        Ok(true) => client_state.set_authenticated(true),
+                        Ok(false) => return Ok("-WRONGPASS invalid username-password pair or user is disabled.\r\n".to_string()),
+                        Err(e) => return Err(e),
+                    }
+                    i += 3;
+                }
+                // connections have no name to report back yet, so it is only validated
+                "SETNAME" if i + 1 < commands.len() => {
+                    if commands[i + 1].contains(|c: char| !c.is_ascii_graphic()) {
+                        return Ok("-ERR Client names cannot contain spaces, newlines or special characters.\r\n".to_string());
+                    }
+                    i += 2;
+                }
+                _ => return Ok(format!("-ERR Syntax error in HELLO option '{}'\r\n", commands[i])),
+            }
+        }
+        if !client_state.is_authenticated() {
+            return Ok("-NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time\r\n".to_string());
+        }
+        client_state.set_protocol(protocol);
+
+        let role = match self.server_state().map().get("role") {
+            Some(RedisValue::String(role)) if role.as_ref() == "slave" => "replica",
+            _ => "master",
+        };
+        let bulk = |s: &str| format!("${}\r\n{}\r\n", s.len(), s);
+        let fields = [
+            ("server", bulk("redis")),
+            ("version", bulk(env!("CARGO_PKG_VERSION"))),
+            ("proto", format!(":{}\r\n", protocol)),
+            ("mode", bulk("standalone")),
+            ("role", bulk(role)),
+            ("modules", "*0\r\n".to_string()),
+        ];
+        let mut response = match protocol {
+            3 => format!("%{}\r\n", fields.len()),
+            _ => format!("*{}\r\n", fields.len() * 2),
+        };
+        for (field, value) in fields {
+            response.push_str(&bulk(field));
+            response.push_str(&value);
+        }
+        Ok(response)
+    }
+
+    /// RESET drops subscriptions, watches and any open transaction, then goes back to RESP2
+    /// with the authentication a new connection would get.
+    pub fn reset(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &str) -> RedisResult<String> {
+        self.clear_subscriptions(client_state, client)?;
+        self.unwatch(client_state)?;
+        client_state.clear_commands();
+        client_state.set_multi_queue_mode(false);
+        client_state.set_protocol(2);
+        client_state.set_authenticated(self.users_state().default_user_has_nopass());
+        Ok("+RESET\r\n".to_string())
+    }
+
     pub fn config(&self, commands: &Vec<Arc<str>>) -> RedisResult<String> {
         match commands[1].to_uppercase().as_str() {
+            "GET" if commands[2].eq_ignore_ascii_case("client-output-buffer-limit") => {
+                let limit = *self.channels_state().output_limit.read()?;
+                let value = format!("normal 0 0 0 replica 0 0 0 pubsub {} {} {}", limit.hard, limit.soft, limit.soft_seconds);
+                Ok(encode_resp_array_str(&["client-output-buffer-limit", &value]))
+            }
+            // only the pubsub class is enforced, the others are accepted and ignored
+            "SET" if commands.len() == 4 && commands[2].eq_ignore_ascii_case("client-output-buffer-limit") => {
+                let args = commands[3].split_whitespace().collect::<Vec<_>>();
+                if args.is_empty() || !args.len().is_multiple_of(4) {
+                    return Ok("-ERR Wrong number of arguments in buffer limit configuration.\r\n".to_string());
+                }
+                let mut pubsub = None;
+                for class in args.chunks_exact(4) {
+                    let (hard, soft, soft_seconds) = match (parse_memory(class[1]), parse_memory(class[2]), class[3].parse::<u64>()) {
+                        (Some(hard), Some(soft), Ok(soft_seconds)) => (hard, soft, soft_seconds),
+                        _ => return Ok("-ERR Error in hard, soft or soft_seconds setting in buffer limit configuration.\r\n".to_string()),
+                    };
+                    match class[0].to_lowercase().as_str() {
+                        "pubsub" => pubsub = Some(OutputBufferLimit { hard, soft, soft_seconds }),
+                        "normal" | "replica" | "slave" => {}
+                        _ => return Ok("-ERR Invalid client class specified in buffer limit configuration.\r\n".to_string()),
+                    }
+                }
+                if let Some(pubsub) = pubsub {
+                    *self.channels_state().output_limit.write()? = pubsub;
+                }
+                Ok("+OK\r\n".to_string())
+            }
+            "GET" if commands[2].eq_ignore_ascii_case("notify-keyspace-events") => {
+                let flags = self.channels_state().keyspace_events.load(Ordering::Relaxed);
+                Ok(encode_resp_array_str(&["notify-keyspace-events", &keyspace_events::flags_to_string(flags)]))
+            }
+            "SET" if commands.len() == 4 && commands[2].eq_ignore_ascii_case("notify-keyspace-events") => {
+                match keyspace_events::parse_flags(&commands[3]) {
+                    Some(flags) => {
+                        self.channels_state().keyspace_events.store(flags, Ordering::Relaxed);
+                        Ok("+OK\r\n".to_string())
+                    }
+                    None => Ok("-ERR Invalid argument 'notify-keyspace-events' for CONFIG SET\r\n".to_string()),
+                }
+            }
+            "GET" if commands[2].eq_ignore_ascii_case("busy-reply-threshold") || commands[2].eq_ignore_ascii_case("lua-time-limit") => {
+                let threshold = self.script_state().busy_reply_threshold().to_string();
+                Ok(encode_resp_array_str(&[&commands[2].to_lowercase(), &threshold]))
+            }
+            "SET" if commands.len() == 4 && (commands[2].eq_ignore_ascii_case("busy-reply-threshold") || commands[2].eq_ignore_ascii_case("lua-time-limit")) => {
+                match commands[3].parse::<u64>() {
+                    Ok(ms) => {
+                        self.script_state().set_busy_reply_threshold(ms);
+                        Ok("+OK\r\n".to_string())
+                    }
+                    Err(_) => Ok(format!("-ERR Invalid argument '{}' for CONFIG SET '{}'\r\n", commands[3], commands[2].to_lowercase())),
+                }
+            }
+            "SET" => Ok(format!("-ERR Unknown option or number of arguments for CONFIG SET - '{}'\r\n", commands.get(2).map_or("", |s| s.as_ref()))),
             "GET" => {
                 let param_name = commands[2].to_lowercase();
                 match self.server_state().map().get(&Arc::from(param_name.as_str())) {
@@ -1308,79 +4579,477 @@ impl RedisState<Arc<str>, RedisValue>{
         }
     }
 
+    /// SAVE, dumping the function libraries and the string keys (the types the
+    /// RDB loader reads back) into dir/dbfilename.
+    pub fn save(&self) -> RedisResult<String> {
+        let config = |name: &str, default: &str| match self.server_state().map().get(name) {
+            Some(RedisValue::String(value)) => value.to_string(),
+            _ => default.to_string(),
+        };
+        let functions = self.script_state().libraries()?.iter().map(|library| Arc::clone(&library.code)).collect::<Vec<_>>();
+
+        // the encoder only writes strings, refusing beats a dump that silently loses keys
+        let unsupported = |kind: &str| Ok(format!("-ERR SAVE can only write string keys, the dataset holds {} keys\r\n", kind));
+        if !self.list_state().list.lock()?.is_empty() {
+            return unsupported("list");
+        }
+        if !self.sorted_set_state.set.read()?.is_empty() {
+            return unsupported("zset");
+        }
+
+        let now = Instant::now();
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_millis() as u64;
+        let mut keys = Vec::new();
+        for (key, value) in self.map_state().map.read()?.iter() {
+            match value {
+                RedisValue::String(value) => keys.push((Arc::clone(key), Arc::clone(value), None)),
+                RedisValue::Number(value) => keys.push((Arc::clone(key), Arc::from(value.to_string()), None)),
+                RedisValue::StringWithTimeout((value, expiry)) if *expiry > now => {
+                    keys.push((Arc::clone(key), Arc::clone(value), Some(now_ms + (*expiry - now).as_millis() as u64)));
+                }
+                RedisValue::StringWithTimeout(_) => {}
+                RedisValue::Stream(_) => return unsupported("stream"),
+                RedisValue::Array(_) => return unsupported("list"),
+                RedisValue::Flags(_) => return unsupported("set"),
+            }
+        }
+
+        let rdb = rdb::encode_rdb(&functions, &keys);
+        rdb::save_rdb_file(&config("dir", "."), &config("dbfilename", "dump.rdb"), &rdb)?;
+        Ok("+OK\r\n".to_string())
+    }
+
+    /// SHUTDOWN [NOSAVE|SAVE], exits the process without replying. There are no
+    /// save points to honour, so only SAVE writes the dataset first.
+    pub async fn shutdown(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        let save = match commands.get(1).map(|option| option.to_uppercase()).as_deref() {
+            None | Some("NOSAVE") if commands.len() <= 2 => false,
+            Some("SAVE") if commands.len() == 2 => true,
+            _ => return Ok("-ERR syntax error\r\n".to_string()),
+        };
+        if save {
+            // SHUTDOWN runs unguarded so NOSAVE gets past a busy script, saving waits for a consistent keyspace
+            let _keyspace_guard = self.keyspace_lock.read().await;
+            let saved = self.save()?;
+            if let Some(error) = saved.strip_prefix('-') {
+                eprintln!("Failed to save before SHUTDOWN: {}", error.trim_end());
+                return Ok("-ERR Errors trying to SHUTDOWN. Check logs.\r\n".to_string());
+            }
+        }
+        std::process::exit(0)
+    }
+
     pub fn keys(&self, _commands: &Vec<Arc<str>>) -> RedisResult<String> {
         let map_guard = self.map_state().map.read()?;
         let keys: Vec<&Arc<str>> = map_guard.keys().collect();
         Ok(encode_resp_ref_array_arc(&keys))
     }
 
-    pub fn subscribe(&mut self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &Arc<str>, commands: &Vec<Arc<str>>) -> RedisResult<String>{
-        client_state.set_subscribe_mode(true);
-        let subs_count = if client_state.get_subscriptions().1.contains(&commands[1]){
-            client_state.get_subscriptions().0
-        } else {
-            let mut channel_guard = self.channels_state().channels_map.write()?;
-            let (count, client_set) = channel_guard.entry(Arc::clone(&commands[1])).or_insert((0, HashSet::new()));
-            *count += 1;
-            client_set.insert(Arc::clone(client));
+    pub fn subscribe(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &Arc<str>, commands: &[Arc<str>]) -> RedisResult<String>{
+        if commands.len() < 2 {
+            return Ok("-ERR wrong number of arguments for 'subscribe' command\r\n".to_string());
+        }
+        let sender = client_state.ensure_sub_channel(client);
+        let channels = self.channels_state();
+
+        let mut response = String::new();
+        for channel in &commands[1..] {
+            if client_state.get_subscriptions_mut().1.insert(Arc::clone(channel)) {
+                add_subscriber(&channels.channels_map, &channels.subscribers, channel, client, &sender)?;
+                client_state.get_subscriptions_mut().0 += 1;
+            }
+            response.push_str(&encode_subscription_reply(client_state.is_resp3(), "subscribe", Some(channel), client_state.get_subscriptions().0));
+        }
+        Ok(response)
+    }
 
-            let subscriptions = client_state.get_subscriptions_mut();
-            subscriptions.1.insert(Arc::clone(&commands[1]));
-            subscriptions.0 += 1;
-            subscriptions.0
+    pub fn publish(&self, commands: &Vec<Arc<str>>) -> RedisResult<String>{
+        let subs = self.publish_frame(Arc::new(commands.iter().skip(1).cloned().collect::<Vec<_>>()))?;
+        Ok(format!(":{}\r\n", subs))
+    }
+
+    // Delivers [channel, message...] to the channel and matching pattern subscribers,
+    // returning how many clients got it
+    fn publish_frame(&self, messages: Arc<Vec<Arc<str>>>) -> RedisResult<usize> {
+        let channel_name = &messages[0];
+        let mut subs = self.channels_state().channels_map.read()?
+            .get(channel_name)
+            .map_or(0, |(count, _)| *count);
+        
+        self.channels_state().deliver(&self.channels_state().subscribers, channel_name, "message", &messages)?;
+
+        let matching_patterns = {
+            let patterns_guard = self.channels_state().patterns_map.read()?;
+            patterns_guard.iter()
+                .filter(|(pattern, _)| glob_match(pattern.as_bytes(), channel_name.as_bytes(), false))
+                .map(|(pattern, (count, _))| {
+                    subs += count;
+                    Arc::clone(pattern)
+                })
+                .collect::<Vec<_>>()
         };
 
-        Ok(format!("*3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n", commands[0].len(), commands[0].to_lowercase(), commands[1].len(), commands[1], subs_count))
+        for pattern in matching_patterns {
+            let mut frame = vec![Arc::clone(&pattern)];
+            frame.extend(messages.iter().cloned());
+            self.channels_state().deliver(&self.channels_state().pattern_subscribers, &pattern, "pmessage", &Arc::new(frame))?;
+        }
+
+        Ok(subs)
+    }
+
+    /// Publishes `event` for `key` on the keyspace and keyevent channels enabled by
+    /// notify-keyspace-events, if its class is one of those selected. Every write path
+    /// reports here, so this is also where WATCHes on the key are invalidated.
+    fn notify_keyspace_event(&self, class: u32, event: &str, key: &Arc<str>) -> RedisResult<()> {
+        if class != keyspace_events::KEY_MISS {
+            self.touch_watched_key(key)?;
+        }
+        let flags = self.channels_state().keyspace_events.load(Ordering::Relaxed);
+        if flags & class == 0 {
+            return Ok(());
+        }
+        let event: Arc<str> = Arc::from(event);
+        if flags & keyspace_events::KEYSPACE != 0 {
+            let channel = Arc::from(format!("__keyspace@0__:{}", key));
+            self.publish_frame(Arc::new(vec![channel, Arc::clone(&event)]))?;
+        }
+        if flags & keyspace_events::KEYEVENT != 0 {
+            let channel = Arc::from(format!("__keyevent@0__:{}", event));
+            self.publish_frame(Arc::new(vec![channel, Arc::clone(key)]))?;
+        }
+        Ok(())
+    }
+
+    // A *STORE destination is overwritten whatever it held, so the string, stream
+    // or list under `key` goes first. Callers hold the sorted sets, which keeps the
+    // lock order. True when something was removed.
+    fn remove_other_types(&self, key: &Arc<str>) -> RedisResult<bool> {
+        let in_map = self.map_state().map.write()?.remove(key).is_some();
+        let in_list = self.list_state().list.lock()?.remove(key).is_some();
+        Ok(in_map || in_list)
     }
 
-    pub async fn handle_subscriber(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, commands: &Vec<Arc<str>>) -> RedisResult<()>{
-        if client_state.has_receiver() {
-            let (sender, receiver) = mpsc::channel(1000);
-            client_state.set_channel(sender, receiver);
+    // Events for a *STORE command writing `destination`, `deleted` when an empty
+    // result removed a key that was there before
+    fn notify_store(&self, event: &str, destination: &Arc<str>, stored: bool, existed: bool) -> RedisResult<()> {
+        if stored {
+            if !existed {
+                self.notify_keyspace_event(keyspace_events::NEW, "new", destination)?;
+            }
+            self.notify_keyspace_event(keyspace_events::ZSET, event, destination)
+        } else if existed {
+            self.notify_keyspace_event(keyspace_events::GENERIC, "del", destination)
+        } else {
+            Ok(())
         }
+    }
 
-        if let Some(sender) = client_state.get_sub_sender() {
-            let mut subs_guard = self.channels_state().subscribers.lock()?;
-            subs_guard.entry(Arc::clone(&commands[1])).or_insert(Vec::new()).push(sender.clone());
+    // Flags every client WATCHing `key`, their next EXEC aborts
+    fn touch_watched_key(&self, key: &Arc<str>) -> RedisResult<()> {
+        if let Some(flags) = self.watch_state.watchers.lock()?.get(key) {
+            for flag in flags {
+                flag.store(true, Ordering::SeqCst);
+            }
         }
         Ok(())
     }
 
-    pub fn publish(&self, commands: &Vec<Arc<str>>) -> RedisResult<String>{
-        let channel_guard = self.channels_state().channels_map.read()?;
-        let subs = channel_guard.get(&commands[1])
-            .map(|(count, _)| *count)
-            .unwrap_or(0); // todo cehck this
-        let channel_name = &commands[1];
-        let messages = Arc::new(commands.iter().skip(2).cloned().collect::<Vec<_>>());
-        drop(channel_guard);
-        
-        let mut subs_guard = self.channels_state().subscribers.lock()?;
-        if let Some(subs) = subs_guard.get_mut(&commands[1]){
-            subs.retain(|sender|
-                match sender.try_send((Arc::clone(channel_name), Arc::clone(&messages))){
-                    Ok(_) => true,
-                    Err(TrySendError::Full(_)) => true,
-                    Err(TrySendError::Closed(_)) => false,
-            })
+    pub fn watch(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, commands: &[Arc<str>]) -> RedisResult<String> {
+        if commands.len() < 2 {
+            return Ok("-ERR wrong number of arguments for 'watch' command\r\n".to_string());
+        }
+        if client_state.is_multi_queue_mode() {
+            return Ok("-ERR WATCH inside MULTI is not allowed\r\n".to_string());
+        }
+        let dirty = Arc::clone(client_state.watch_dirty());
+        let mut watchers_guard = self.watch_state.watchers.lock()?;
+        for key in &commands[1..] {
+            if client_state.get_watched_keys_mut().insert(Arc::clone(key)) {
+                watchers_guard.entry(Arc::clone(key)).or_insert(Vec::new()).push(Arc::clone(&dirty));
+            }
+        }
+        Ok("+OK\r\n".to_string())
+    }
+
+    /// UNWATCH, also run after EXEC and DISCARD and when the client goes away.
+    pub fn unwatch(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>) -> RedisResult<String> {
+        let dirty = Arc::clone(client_state.watch_dirty());
+        let mut watchers_guard = self.watch_state.watchers.lock()?;
+        for key in client_state.get_watched_keys_mut().drain() {
+            if let Some(flags) = watchers_guard.get_mut(&key) {
+                flags.retain(|flag| !Arc::ptr_eq(flag, &dirty));
+                if flags.is_empty() {
+                    watchers_guard.remove(&key);
+                }
+            }
+        }
+        dirty.store(false, Ordering::SeqCst);
+        Ok("+OK\r\n".to_string())
+    }
+
+    /// FLUSHALL and FLUSHDB, the same thing with a single database. Watchers of keys
+    /// that existed are flagged like for any other change.
+    pub fn flushall(&self, commands: &[Arc<str>]) -> RedisResult<String> {
+        match commands.get(1).map(|mode| mode.to_uppercase()) {
+            None => {}
+            Some(mode) if commands.len() == 2 && (mode == "SYNC" || mode == "ASYNC") => {}
+            Some(_) => return Ok("-ERR syntax error\r\n".to_string()),
+        }
+
+        // sorted sets are always locked before the main map
+        let mut sorted_state_guard = self.sorted_set_state.set.write()?;
+        let mut map_guard = self.map_state().map.write()?;
+        let mut list_guard = self.list_state().list.lock()?;
+        // like redis, no keyspace event names the flushed keys, so WATCHers are flagged here
+        for (key, flags) in self.watch_state.watchers.lock()?.iter() {
+            if map_guard.contains_key(key) || list_guard.contains_key(key) || sorted_state_guard.contains_key(key) {
+                for flag in flags {
+                    flag.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+        sorted_state_guard.clear();
+        map_guard.clear();
+        list_guard.clear();
+        Ok("+OK\r\n".to_string())
+    }
+
+    /// Deletes the string keys whose TTL has passed, firing `expired` for each.
+    pub fn expire_keys(&self) -> RedisResult<()> {
+        let now = Instant::now();
+        let expired = self.map_state().map.read()?.iter()
+            .filter(|(_, value)| matches!(value, RedisValue::StringWithTimeout((_, timeout)) if *timeout <= now))
+            .map(|(key, _)| Arc::clone(key))
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.expire_if_needed(&key)?;
+        }
+        Ok(())
+    }
+
+    // Removes `key` if it expired, true when it did
+    fn expire_if_needed(&self, key: &Arc<str>) -> RedisResult<bool> {
+        let removed = {
+            let mut map_guard = self.map_state().map.write()?;
+            match map_guard.get(key) {
+                Some(RedisValue::StringWithTimeout((_, timeout))) if *timeout <= Instant::now() => map_guard.remove(key).is_some(),
+                _ => false,
+            }
+        };
+        if removed {
+            self.notify_keyspace_event(keyspace_events::EXPIRED, "expired", key)?;
         }
+        Ok(removed)
+    }
 
+    // Shard channels are their own namespace, patterns never see them
+    pub fn spublish(&self, commands: &[Arc<str>]) -> RedisResult<String>{
+        if commands.len() != 3 {
+            return Ok("-ERR wrong number of arguments for 'spublish' command\r\n".to_string());
+        }
+        let channel_name = &commands[1];
+        let subs = self.channels_state().shard_channels_map.read()?
+            .get(channel_name)
+            .map_or(0, |(count, _)| *count);
+        let messages = Arc::new(commands[1..].to_vec());
+        self.channels_state().deliver(&self.channels_state().shard_subscribers, channel_name, "smessage", &messages)?;
         Ok(format!(":{}\r\n", subs))
     }
 
-    pub fn unsubscribe(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &str, commands: &Vec<Arc<str>>) -> RedisResult<String>{
-        let mut channel_guard = self.channels_state().channels_map.write()?;
-        if let Some((count, client_set)) = channel_guard.get_mut(&commands[1]){
-            *count -= 1;
-            client_set.remove(client);
+    pub fn pubsub(&self, commands: &[Arc<str>]) -> RedisResult<String>{
+        let Some(subcommand) = commands.get(1).map(|s| s.to_uppercase()) else {
+            return Ok("-ERR wrong number of arguments for 'pubsub' command\r\n".to_string());
+        };
+        let channels = self.channels_state();
+        match subcommand.as_str() {
+            "CHANNELS" | "SHARDCHANNELS" if commands.len() <= 3 => {
+                let counts = match subcommand.as_str() {
+                    "CHANNELS" => &channels.channels_map,
+                    _ => &channels.shard_channels_map,
+                };
+                let active = counts.read()?.keys().cloned().collect::<Vec<_>>();
+                let matching = active.into_iter()
+                    .filter(|channel| commands.get(2).is_none_or(|pattern| glob_match(pattern.as_bytes(), channel.as_bytes(), false)))
+                    .collect::<Vec<_>>();
+                Ok(encode_resp_array_arc(&matching))
+            }
+            "NUMSUB" | "SHARDNUMSUB" => {
+                let counts = match subcommand.as_str() {
+                    "NUMSUB" => &channels.channels_map,
+                    _ => &channels.shard_channels_map,
+                };
+                let channels_guard = counts.read()?;
+                let mut response = format!("*{}\r\n", (commands.len() - 2) * 2);
+                for channel in &commands[2..] {
+                    let count = channels_guard.get(channel).map_or(0, |(count, _)| *count);
+                    response.push_str(&format!("${}\r\n{}\r\n:{}\r\n", channel.len(), channel, count));
+                }
+                Ok(response)
+            }
+            "NUMPAT" if commands.len() == 2 => Ok(format!(":{}\r\n", channels.patterns_map.read()?.len())),
+            "CHANNELS" | "SHARDCHANNELS" | "NUMPAT" => Ok(format!("-ERR wrong number of arguments for 'pubsub|{}' command\r\n", subcommand.to_lowercase())),
+            _ => Ok(format!("-ERR unknown subcommand '{}'. Try PUBSUB HELP.\r\n", commands[1])),
+        }
+    }
+
+    pub fn psubscribe(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &Arc<str>, commands: &[Arc<str>]) -> RedisResult<String>{
+        if commands.len() < 2 {
+            return Ok("-ERR wrong number of arguments for 'psubscribe' command\r\n".to_string());
+        }
+        let sender = client_state.ensure_sub_channel(client);
+        let channels = self.channels_state();
+
+        let mut response = String::new();
+        for pattern in &commands[1..] {
+            if client_state.get_pattern_subscriptions_mut().insert(Arc::clone(pattern)) {
+                add_subscriber(&channels.patterns_map, &channels.pattern_subscribers, pattern, client, &sender)?;
+                client_state.get_subscriptions_mut().0 += 1;
+            }
+            response.push_str(&encode_subscription_reply(client_state.is_resp3(), "psubscribe", Some(pattern), client_state.get_subscriptions().0));
+        }
+        Ok(response)
+    }
+
+    // Without patterns every pattern subscription of the client is dropped
+    pub fn punsubscribe(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &str, commands: &[Arc<str>]) -> RedisResult<String>{
+        let patterns = match commands.len() {
+            1 => client_state.get_pattern_subscriptions().iter().cloned().collect::<Vec<_>>(),
+            _ => commands[1..].to_vec(),
+        };
+        if patterns.is_empty() {
+            return Ok(encode_subscription_reply(client_state.is_resp3(), "punsubscribe", None, client_state.get_subscriptions().0));
+        }
+        let channels = self.channels_state();
+
+        let mut response = String::new();
+        for pattern in patterns {
+            if client_state.get_pattern_subscriptions_mut().remove(&pattern) {
+                remove_subscriber(&channels.patterns_map, &channels.pattern_subscribers, &pattern, client, client_state.get_sub_sender().as_ref())?;
+                client_state.get_subscriptions_mut().0 -= 1;
+            }
+            response.push_str(&encode_subscription_reply(client_state.is_resp3(), "punsubscribe", Some(&pattern), client_state.get_subscriptions().0));
+        }
+        client_state.leave_subscribe_mode_if_idle();
+        Ok(response)
+    }
+
+    // Without channels every channel subscription of the client is dropped
+    pub fn unsubscribe(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &str, commands: &[Arc<str>]) -> RedisResult<String>{
+        let channel_names = match commands.len() {
+            1 => client_state.get_subscriptions().1.iter().cloned().collect::<Vec<_>>(),
+            _ => commands[1..].to_vec(),
+        };
+        if channel_names.is_empty() {
+            return Ok(encode_subscription_reply(client_state.is_resp3(), "unsubscribe", None, client_state.get_subscriptions().0));
+        }
+        let channels = self.channels_state();
+
+        let mut response = String::new();
+        for channel in channel_names {
+            if client_state.get_subscriptions_mut().1.remove(&channel) {
+                remove_subscriber(&channels.channels_map, &channels.subscribers, &channel, client, client_state.get_sub_sender().as_ref())?;
+                client_state.get_subscriptions_mut().0 -= 1;
+            }
+            response.push_str(&encode_subscription_reply(client_state.is_resp3(), "unsubscribe", Some(&channel), client_state.get_subscriptions().0));
+        }
+        client_state.leave_subscribe_mode_if_idle();
+        Ok(response)
+    }
+
+    // Replies count shard channels only, like redis does
+    pub fn ssubscribe(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &Arc<str>, commands: &[Arc<str>]) -> RedisResult<String>{
+        if commands.len() < 2 {
+            return Ok("-ERR wrong number of arguments for 'ssubscribe' command\r\n".to_string());
+        }
+        let sender = client_state.ensure_sub_channel(client);
+        let channels = self.channels_state();
+
+        let mut response = String::new();
+        for channel in &commands[1..] {
+            if client_state.get_shard_subscriptions_mut().insert(Arc::clone(channel)) {
+                add_subscriber(&channels.shard_channels_map, &channels.shard_subscribers, channel, client, &sender)?;
+            }
+            response.push_str(&encode_subscription_reply(client_state.is_resp3(), "ssubscribe", Some(channel), client_state.get_shard_subscriptions().len()));
+        }
+        Ok(response)
+    }
+
+    // Without channels every shard channel subscription of the client is dropped
+    pub fn sunsubscribe(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &str, commands: &[Arc<str>]) -> RedisResult<String>{
+        let channel_names = match commands.len() {
+            1 => client_state.get_shard_subscriptions().iter().cloned().collect::<Vec<_>>(),
+            _ => commands[1..].to_vec(),
+        };
+        if channel_names.is_empty() {
+            return Ok(encode_subscription_reply(client_state.is_resp3(), "sunsubscribe", None, 0));
         }
+        let channels = self.channels_state();
 
-        let subscriptions = client_state.get_subscriptions_mut();
-        subscriptions.1.remove(&commands[1]);
-        subscriptions.0 -= 1;
-        let subs_count = subscriptions.0;
+        let mut response = String::new();
+        for channel in channel_names {
+            if client_state.get_shard_subscriptions_mut().remove(&channel) {
+                remove_subscriber(&channels.shard_channels_map, &channels.shard_subscribers, &channel, client, client_state.get_sub_sender().as_ref())?;
+            }
+            response.push_str(&encode_subscription_reply(client_state.is_resp3(), "sunsubscribe", Some(&channel), client_state.get_shard_subscriptions().len()));
+        }
+        client_state.leave_subscribe_mode_if_idle();
+        Ok(response)
+    }
+
+    /// Drops every subscription of a disconnecting client so PUBLISH stops counting it.
+    pub fn clear_subscriptions(&self, client_state: &mut ClientState<Arc<str>, Arc<str>>, client: &str) -> RedisResult<()>{
+        let channels = self.channels_state();
+        let sender = client_state.get_sub_sender().clone();
+        for channel in client_state.get_subscriptions_mut().1.drain() {
+            remove_subscriber(&channels.channels_map, &channels.subscribers, &channel, client, sender.as_ref())?;
+        }
+        for pattern in client_state.get_pattern_subscriptions_mut().drain() {
+            remove_subscriber(&channels.patterns_map, &channels.pattern_subscribers, &pattern, client, sender.as_ref())?;
+        }
+        for channel in client_state.get_shard_subscriptions_mut().drain() {
+            remove_subscriber(&channels.shard_channels_map, &channels.shard_subscribers, &channel, client, sender.as_ref())?;
+        }
+        client_state.get_subscriptions_mut().0 = 0;
+        client_state.leave_subscribe_mode_if_idle();
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(format!("*3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n", commands[0].len(), commands[0].to_lowercase(), commands[1].len(), commands[1], subs_count))
+    fn command(line: &str) -> Vec<Arc<str>> {
+        line.split_whitespace().map(Arc::from).collect()
     }
 
-}
\ No newline at end of file
+    // GEOSEARCH and GEORADIUS range-scan the score index, which GEOADD has to keep in step
+    #[test]
+    fn geo_searches_find_added_members() {
+        let state = RedisState::new();
+        state.geoadd(&command("GEOADD Sicily 13.361389 38.115556 Palermo")).unwrap();
+        state.geoadd(&command("GEOADD Sicily 15.087269 37.502669 Catania")).unwrap();
+
+        let both = encode_resp_array_str(&["Catania", "Palermo"]);
+        assert_eq!(state.geosearch(&command("GEOSEARCH Sicily FROMLONLAT 15 37 BYRADIUS 200 km ASC")).unwrap(), both);
+        assert_eq!(state.georadius(&command("GEORADIUS Sicily 15 37 200 km ASC")).unwrap(), both);
+        assert_eq!(state.geosearch(&command("GEOSEARCH Sicily FROMMEMBER Palermo BYBOX 10 10 km")).unwrap(), encode_resp_array_str(&["Palermo"]));
+    }
+
+    // a STORE destination of another type is replaced, not shadowed by the new sorted set
+    #[test]
+    fn store_commands_overwrite_other_types() {
+        let mut state = RedisState::new();
+        state.zadd(&command("ZADD src 1 a 2 b")).unwrap();
+        state.set(&command("SET str x")).unwrap();
+        state.rpush(&command("RPUSH list x")).unwrap();
+
+        assert_eq!(state.zunionstore(&command("ZUNIONSTORE str 1 src")).unwrap(), ":2\r\n");
+        assert_eq!(state.get(&command("GET str")).unwrap(), "$-1\r\n");
+        assert_eq!(state.zrangestore(&command("ZRANGESTORE list src 0 0")).unwrap(), ":1\r\n");
+        assert_eq!(state.llen(&command("LLEN list")).unwrap(), ":0\r\n");
+        assert_eq!(state.zrange(&command("ZRANGE list 0 -1")).unwrap(), encode_resp_array_str(&["a"]));
+    }
+}
@@ -0,0 +1,457 @@
+use std::{collections::BTreeMap, sync::Arc};
+use super::value::{StreamFields, StreamId};
+
+// Node limits, the redis defaults for stream-node-max-entries and stream-node-max-bytes
+pub const STREAM_NODE_MAX_ENTRIES: usize = 100;
+const STREAM_NODE_MAX_BYTES: usize = 4096;
+
+const FLAG_DELETED: u8 = 1;
+const FLAG_SAME_FIELDS: u8 = 2;
+
+// A macro node in the style of the redis listpacks. Every entry is stored as
+//   flags | ms delta | seq | values...
+// with the ID relative to the node's master ID (the seq only becomes a delta when
+// the ms part matches) and, when the entry has the same field names as the master
+// entry, only its values. Other entries carry a field count and field/value pairs.
+// Numbers are LEB128 varints, strings are a varint length followed by the bytes.
+#[derive(Debug, Clone)]
+struct StreamNode {
+    master_fields: Vec<Arc<str>>,
+    data: Vec<u8>,
+    live: usize,
+    deleted: usize,
+}
+
+struct RawEntry {
+    offset: usize, // position of the flags byte
+    flags: u8,
+    id: StreamId,
+    fields_at: usize,
+}
+
+impl RawEntry {
+    fn is_deleted(&self) -> bool {
+        self.flags & FLAG_DELETED != 0
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_varint(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_str(buf: &[u8], pos: &mut usize) -> Arc<str> {
+    let len = read_varint(buf, pos) as usize;
+    let bytes = &buf[*pos..*pos + len];
+    *pos += len;
+    // only ever written from valid strings
+    Arc::from(std::str::from_utf8(bytes).unwrap_or_default())
+}
+
+fn skip_str(buf: &[u8], pos: &mut usize) {
+    let len = read_varint(buf, pos) as usize;
+    *pos += len;
+}
+
+impl StreamNode {
+    fn new(fields: &[(Arc<str>, Arc<str>)]) -> Self {
+        let master_fields = fields.iter().map(|(field, _)| Arc::clone(field)).collect();
+        StreamNode { master_fields, data: Vec::new(), live: 0, deleted: 0 }
+    }
+
+    fn is_full(&self) -> bool {
+        self.live + self.deleted >= STREAM_NODE_MAX_ENTRIES || self.data.len() >= STREAM_NODE_MAX_BYTES
+    }
+
+    fn push(&mut self, master: StreamId, id: StreamId, fields: &[(Arc<str>, Arc<str>)]) {
+        let same_fields = fields.len() == self.master_fields.len()
+            && fields.iter().zip(&self.master_fields).all(|((field, _), master_field)| field == master_field);
+        self.data.push(if same_fields { FLAG_SAME_FIELDS } else { 0 });
+        write_varint(&mut self.data, id.ms - master.ms);
+        write_varint(&mut self.data, if id.ms == master.ms { id.seq - master.seq } else { id.seq });
+        if same_fields {
+            for (_, value) in fields {
+                write_str(&mut self.data, value);
+            }
+        } else {
+            write_varint(&mut self.data, fields.len() as u64);
+            for (field, value) in fields {
+                write_str(&mut self.data, field);
+                write_str(&mut self.data, value);
+            }
+        }
+        self.live += 1;
+    }
+
+    // Walks every entry, deleted ones included, without decoding the fields
+    fn entries(&self, master: StreamId) -> impl Iterator<Item = RawEntry> + '_ {
+        let mut pos = 0;
+        std::iter::from_fn(move || {
+            if pos >= self.data.len() {
+                return None;
+            }
+            let offset = pos;
+            let flags = self.data[pos];
+            pos += 1;
+            let ms = master.ms + read_varint(&self.data, &mut pos);
+            let seq = read_varint(&self.data, &mut pos);
+            let id = StreamId::new(ms, if ms == master.ms { master.seq + seq } else { seq });
+            let fields_at = pos;
+            let strings = match flags & FLAG_SAME_FIELDS {
+                0 => read_varint(&self.data, &mut pos) as usize * 2,
+                _ => self.master_fields.len(),
+            };
+            for _ in 0..strings {
+                skip_str(&self.data, &mut pos);
+            }
+            Some(RawEntry { offset, flags, id, fields_at })
+        })
+    }
+
+    fn fields(&self, entry: &RawEntry) -> StreamFields {
+        let mut pos = entry.fields_at;
+        let fields = match entry.flags & FLAG_SAME_FIELDS {
+            0 => {
+                let len = read_varint(&self.data, &mut pos) as usize;
+                (0..len).map(|_| (read_str(&self.data, &mut pos), read_str(&self.data, &mut pos))).collect()
+            }
+            _ => self.master_fields.iter()
+                .map(|field| (Arc::clone(field), read_str(&self.data, &mut pos)))
+                .collect(),
+        };
+        Arc::new(fields)
+    }
+
+    fn mark_deleted(&mut self, offset: usize) {
+        self.data[offset] |= FLAG_DELETED;
+        self.live -= 1;
+        self.deleted += 1;
+    }
+}
+
+/// Stream entries packed into macro nodes keyed by their master ID, the first ID
+/// added to the node. Deleted entries stay behind flagged until their node empties.
+#[derive(Debug, Clone, Default)]
+pub struct StreamNodes {
+    nodes: BTreeMap<StreamId, StreamNode>,
+    len: usize,
+}
+
+impl StreamNodes {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Appends an entry, `id` must be greater than every ID already stored.
+    pub fn push(&mut self, id: StreamId, fields: &[(Arc<str>, Arc<str>)]) {
+        match self.nodes.last_key_value() {
+            Some((_, node)) if !node.is_full() => {}
+            _ => {
+                self.nodes.insert(id, StreamNode::new(fields));
+            }
+        }
+        if let Some((master, node)) = self.nodes.iter_mut().next_back() {
+            node.push(*master, id, fields);
+            self.len += 1;
+        }
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<StreamFields> {
+        let (master, node) = self.nodes.range(..=id).next_back()?;
+        node.entries(*master)
+            .take_while(|entry| entry.id <= id)
+            .find(|entry| entry.id == id && !entry.is_deleted())
+            .map(|entry| node.fields(&entry))
+    }
+
+    pub fn remove(&mut self, id: StreamId) -> bool {
+        let Some((master, node)) = self.nodes.range_mut(..=id).next_back() else {
+            return false;
+        };
+        let master = *master;
+        let offset = node.entries(master)
+            .take_while(|entry| entry.id <= id)
+            .find(|entry| entry.id == id && !entry.is_deleted())
+            .map(|entry| entry.offset);
+        let Some(offset) = offset else {
+            return false;
+        };
+        node.mark_deleted(offset);
+        if node.live == 0 {
+            self.nodes.remove(&master);
+        }
+        self.len -= 1;
+        true
+    }
+
+    pub fn first(&self) -> Option<(StreamId, StreamFields)> {
+        self.range(StreamId::MIN, StreamId::MAX, 1, false).pop()
+    }
+
+    pub fn last(&self) -> Option<(StreamId, StreamFields)> {
+        self.range(StreamId::MIN, StreamId::MAX, 1, true).pop()
+    }
+
+    /// Up to `count` entries with IDs in `start..=end`, newest first when `rev` is set.
+    pub fn range(&self, start: StreamId, end: StreamId, count: usize, rev: bool) -> Vec<(StreamId, StreamFields)> {
+        let mut result = Vec::new();
+        if start > end || count == 0 {
+            return result;
+        }
+        if rev {
+            for (master, node) in self.nodes.range(..=end).rev() {
+                let entries = node.entries(*master).filter(|entry| !entry.is_deleted()).collect::<Vec<_>>();
+                for entry in entries.iter().rev().skip_while(|entry| entry.id > end) {
+                    if entry.id < start {
+                        return result;
+                    }
+                    result.push((entry.id, node.fields(entry)));
+                    if result.len() == count {
+                        return result;
+                    }
+                }
+            }
+        } else {
+            let first = self.nodes.range(..=start).next_back().map_or(StreamId::MIN, |(master, _)| *master);
+            for (master, node) in self.nodes.range(first..=end) {
+                for entry in node.entries(*master).filter(|entry| !entry.is_deleted() && entry.id >= start) {
+                    if entry.id > end {
+                        return result;
+                    }
+                    result.push((entry.id, node.fields(&entry)));
+                    if result.len() == count {
+                        return result;
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Number of entries with IDs lower than `id`.
+    pub fn count_before(&self, id: StreamId) -> usize {
+        let mut count = 0;
+        let mut nodes = self.nodes.range(..id).peekable();
+        while let Some((master, node)) = nodes.next() {
+            // a node followed by another one still below `id` lies entirely before it
+            count += match nodes.peek() {
+                Some(_) => node.live,
+                None => node.entries(*master).filter(|entry| !entry.is_deleted() && entry.id < id).count(),
+            };
+        }
+        count
+    }
+
+    /// Removes up to `max` of the oldest entries, returning how many went. Approximate
+    /// trimming only drops whole nodes, like redis does.
+    pub fn trim_front(&mut self, max: usize, approx: bool) -> usize {
+        let mut removed = 0;
+        while let Some(mut first) = self.nodes.first_entry() {
+            let master = *first.key();
+            let node = first.get_mut();
+            if removed + node.live <= max {
+                removed += node.live;
+                first.remove();
+                continue;
+            }
+            if !approx {
+                let offsets = node.entries(master)
+                    .filter(|entry| !entry.is_deleted())
+                    .take(max - removed)
+                    .map(|entry| entry.offset)
+                    .collect::<Vec<_>>();
+                removed += offsets.len();
+                for offset in offsets {
+                    node.mark_deleted(offset);
+                }
+            }
+            break;
+        }
+        self.len -= removed;
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(Arc<str>, Arc<str>)> {
+        pairs.iter().map(|(field, value)| (Arc::from(*field), Arc::from(*value))).collect()
+    }
+
+    // Entry i has ID `i-1` and a single small field, so nodes fill up by entry count
+    fn numbered(n: u64) -> StreamNodes {
+        let mut nodes = StreamNodes::default();
+        for i in 0..n {
+            nodes.push(StreamId::new(i, 1), &fields(&[("n", &i.to_string())]));
+        }
+        nodes
+    }
+
+    fn ids(entries: &[(StreamId, StreamFields)]) -> Vec<u64> {
+        entries.iter().map(|(id, _)| id.ms).collect()
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        let values = [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as u64, u64::MAX];
+        let mut buf = Vec::new();
+        for value in values {
+            write_varint(&mut buf, value);
+        }
+        // one byte up to 7 bits, ten for a full u64
+        assert_eq!(buf.len(), 1 + 1 + 1 + 2 + 2 + 3 + 5 + 10);
+        let mut pos = 0;
+        for value in values {
+            assert_eq!(read_varint(&buf, &mut pos), value);
+        }
+        assert_eq!(pos, buf.len());
+    }
+
+    #[test]
+    fn delta_encoded_ids_and_fields_round_trip() {
+        let master = StreamId::new(1000, 5);
+        let mut node = StreamNode::new(&fields(&[("a", "1"), ("b", "2")]));
+        let pushed = [
+            (master, fields(&[("a", "1"), ("b", "2")])),
+            (StreamId::new(1000, 9), fields(&[("a", "x"), ("b", "")])),
+            // a new ms keeps its seq as is, even one below the master's
+            (StreamId::new(1001, 0), fields(&[("b", "2"), ("a", "1")])),
+            (StreamId::new(u64::MAX, u64::MAX), fields(&[("other", "field"), ("c", "3")])),
+        ];
+        for (id, entry_fields) in &pushed {
+            node.push(master, *id, entry_fields);
+        }
+
+        let entries = node.entries(master).collect::<Vec<_>>();
+        assert_eq!(entries.len(), pushed.len());
+        for (entry, (id, entry_fields)) in entries.iter().zip(&pushed) {
+            assert_eq!(entry.id, *id);
+            assert!(!entry.is_deleted());
+            assert_eq!(*node.fields(entry), *entry_fields);
+        }
+        // only entries with the master's field names in the same order drop them
+        let same_fields = entries.iter().map(|entry| entry.flags & FLAG_SAME_FIELDS != 0).collect::<Vec<_>>();
+        assert_eq!(same_fields, [true, true, false, false]);
+    }
+
+    #[test]
+    fn splits_nodes_by_entries_and_bytes() {
+        let nodes = numbered(250);
+        assert_eq!(nodes.len(), 250);
+        assert_eq!(nodes.node_count(), 3);
+
+        let mut nodes = StreamNodes::default();
+        let big = "x".repeat(1000);
+        for i in 0..10 {
+            nodes.push(StreamId::new(i, 0), &fields(&[("v", &big)]));
+        }
+        // five entries of ~1KB go past 4096 bytes long before 100 entries
+        assert_eq!(nodes.node_count(), 2);
+        assert_eq!(ids(&nodes.range(StreamId::new(3, 0), StreamId::new(6, 0), usize::MAX, false)), [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn ranges_across_node_boundaries() {
+        let nodes = numbered(250);
+        let (start, end) = (StreamId::new(95, 0), StreamId::new(205, 0));
+        let forward = nodes.range(start, end, usize::MAX, false);
+        assert_eq!(ids(&forward), (95..205).collect::<Vec<_>>());
+        assert_eq!(*forward[10].1, fields(&[("n", "105")]));
+
+        let backward = nodes.range(start, end, usize::MAX, true);
+        assert_eq!(ids(&backward), (95..205).rev().collect::<Vec<_>>());
+
+        assert_eq!(ids(&nodes.range(start, end, 10, false)), (95..105).collect::<Vec<_>>());
+        assert_eq!(ids(&nodes.range(start, end, 10, true)), (195..205).rev().collect::<Vec<_>>());
+        // a start between two nodes' master IDs still begins in the earlier node
+        assert_eq!(ids(&nodes.range(StreamId::new(99, 2), StreamId::new(101, 1), usize::MAX, false)), [100, 101]);
+        assert_eq!(nodes.first().map(|(id, _)| id.ms), Some(0));
+        assert_eq!(nodes.last().map(|(id, _)| id.ms), Some(249));
+        assert_eq!(nodes.count_before(StreamId::new(150, 1)), 150);
+    }
+
+    #[test]
+    fn tombstones_are_skipped() {
+        let mut nodes = numbered(250);
+        for ms in [99, 100, 101, 150] {
+            assert!(nodes.remove(StreamId::new(ms, 1)));
+        }
+        assert!(!nodes.remove(StreamId::new(100, 1)));
+        assert!(!nodes.remove(StreamId::new(100, 2)));
+        assert_eq!(nodes.len(), 246);
+        assert_eq!(nodes.get(StreamId::new(100, 1)), None);
+        assert_eq!(nodes.get(StreamId::new(102, 1)).map(|fields| fields[0].1.to_string()), Some("102".to_string()));
+
+        let range = nodes.range(StreamId::new(98, 0), StreamId::new(102, 1), usize::MAX, false);
+        assert_eq!(ids(&range), [98, 102]);
+        let range = nodes.range(StreamId::new(98, 0), StreamId::new(102, 1), usize::MAX, true);
+        assert_eq!(ids(&range), [102, 98]);
+        assert_eq!(nodes.count_before(StreamId::new(151, 1)), 147);
+
+        // a node goes once its last live entry does
+        for ms in 200..250 {
+            nodes.remove(StreamId::new(ms, 1));
+        }
+        assert_eq!(nodes.node_count(), 2);
+        assert_eq!(nodes.last().map(|(id, _)| id.ms), Some(199));
+    }
+
+    #[test]
+    fn trim_front_exact_and_approx() {
+        let mut nodes = numbered(250);
+        assert_eq!(nodes.trim_front(150, false), 150);
+        assert_eq!(nodes.len(), 100);
+        assert_eq!(nodes.first().map(|(id, _)| id.ms), Some(150));
+        assert_eq!(nodes.count_before(StreamId::MAX), 100);
+
+        // approximate trimming stops at the first node it can't drop whole
+        let mut nodes = numbered(250);
+        assert_eq!(nodes.trim_front(150, true), 100);
+        assert_eq!(nodes.len(), 150);
+        assert_eq!(nodes.node_count(), 2);
+        assert_eq!(nodes.first().map(|(id, _)| id.ms), Some(100));
+
+        // tombstones don't count towards what gets trimmed
+        let mut nodes = numbered(250);
+        for ms in 0..10 {
+            nodes.remove(StreamId::new(ms, 1));
+        }
+        assert_eq!(nodes.trim_front(95, false), 95);
+        assert_eq!(nodes.first().map(|(id, _)| id.ms), Some(105));
+        assert_eq!(nodes.trim_front(usize::MAX, false), 145);
+        assert!(nodes.is_empty());
+        assert_eq!(nodes.node_count(), 0);
+    }
+}
@@ -1,7 +1,7 @@
-use std::{collections::{BTreeMap, HashMap, HashSet}, fmt, sync::Arc, time::{Instant, SystemTime, UNIX_EPOCH}};
+use std::{cmp::Ordering, collections::{BTreeMap, BTreeSet, HashSet}, fmt, ops::Bound, sync::Arc, time::{Instant, SystemTime, UNIX_EPOCH}};
 use serde::Serialize;
-use serde_json::{Value, json};
 use crate::error::{RedisError, RedisResult};
+use super::stream_nodes::{StreamNodes, STREAM_NODE_MAX_ENTRIES};
 
 #[derive(Debug, Clone)]
 pub enum RedisValue{
@@ -9,47 +9,699 @@ pub enum RedisValue{
     String(Arc<str>),
     Number(u64),
     StringWithTimeout((Arc<str>, Instant)),
-    Stream(StreamValue<Arc<str>, Arc<str>>),
+    Stream(StreamValue),
     Flags(HashSet<Arc<str>>),
 }
 
+/// Stream entry ID, ordered by milliseconds and then sequence number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        StreamId { ms, seq }
+    }
+
+    /// Parses `ms-seq`, or an incomplete `ms` whose sequence defaults to `missing_seq`.
+    pub fn parse(id: &str, missing_seq: u64) -> Option<Self> {
+        match id.split_once('-') {
+            Some((ms, seq)) => Some(StreamId::new(ms.parse().ok()?, seq.parse().ok()?)),
+            None => Some(StreamId::new(id.parse().ok()?, missing_seq)),
+        }
+    }
+
+    /// The smallest ID greater than this one.
+    pub fn next(self) -> Option<Self> {
+        match (self.ms, self.seq) {
+            (ms, seq) if seq < u64::MAX => Some(StreamId::new(ms, seq + 1)),
+            (ms, _) if ms < u64::MAX => Some(StreamId::new(ms + 1, 0)),
+            _ => None,
+        }
+    }
+
+    /// The greatest ID smaller than this one.
+    pub fn prev(self) -> Option<Self> {
+        match (self.ms, self.seq) {
+            (ms, seq) if seq > 0 => Some(StreamId::new(ms, seq - 1)),
+            (ms, _) if ms > 0 => Some(StreamId::new(ms - 1, u64::MAX)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamValue{
+    last_id: StreamId,
+    nodes: StreamNodes,
+    entries_added: u64,
+    max_deleted_id: StreamId,
+    groups: BTreeMap<Arc<str>, ConsumerGroup>,
+}
+
+pub type StreamFields = Arc<Vec<(Arc<str>, Arc<str>)>>;
+// Fields are None for pending entries deleted from the stream
+pub type GroupEntry = (StreamId, Option<StreamFields>);
+
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    last_id: StreamId,
+    entries_read: Option<u64>, // None when the counter can't be known
+    pending: BTreeMap<StreamId, PendingEntry>,
+    consumers: BTreeMap<Arc<str>, Consumer>,
+}
+
 #[derive(Debug, Clone)]
-pub struct StreamValue<K, V>{
-    last_id: Arc<str>,
-    time_map: HashMap<u128, u64>, //time -> last seqquence number
-    map: BTreeMap<Arc<str>, (u128, u64, Arc<Vec<(K, V)>>)>,
-    waiters_value: (Arc<str>, Arc<Vec<(K, V)>>)
+pub struct PendingEntry {
+    consumer: Arc<str>,
+    delivery_time: u128,
+    delivery_count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    seen_time: u128,
+    active_time: Option<u128>,
+    pending: BTreeSet<StreamId>,
+}
+
+// XCLAIM modifiers, times in milliseconds
+#[derive(Default)]
+pub struct ClaimOptions {
+    pub min_idle: u128,
+    pub idle: Option<u128>,
+    pub time: Option<u128>,
+    pub retry_count: Option<u64>,
+    pub force: bool,
+    pub justid: bool,
+    pub last_id: Option<StreamId>,
+}
+
+#[derive(Clone, Copy)]
+pub enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+#[derive(Clone, Copy)]
+pub struct TrimOptions {
+    pub strategy: TrimStrategy,
+    pub approx: bool,
+    pub limit: Option<usize>, // None or 0 means no limit
+}
+
+pub struct PendingSummary {
+    pub count: usize,
+    pub bounds: Option<(StreamId, StreamId)>,
+    pub consumers: Vec<(Arc<str>, usize)>,
+}
+
+pub struct PendingDetail {
+    pub id: StreamId,
+    pub consumer: Arc<str>,
+    pub idle: u128,
+    pub delivery_count: u64,
+}
+
+pub struct AutoClaimed {
+    pub next: StreamId,
+    pub claimed: Vec<(StreamId, StreamFields)>,
+    pub deleted: Vec<StreamId>,
+}
+
+/// XINFO STREAM fields, `entries` is only filled in for the FULL form.
+pub struct StreamInfo {
+    pub length: usize,
+    pub radix_tree_keys: usize,
+    pub radix_tree_nodes: usize,
+    pub last_generated_id: StreamId,
+    pub max_deleted_entry_id: StreamId,
+    pub entries_added: u64,
+    pub recorded_first_entry_id: StreamId,
+    pub groups: usize,
+    pub first_entry: Option<(StreamId, StreamFields)>,
+    pub last_entry: Option<(StreamId, StreamFields)>,
+    pub entries: Vec<(StreamId, StreamFields)>,
+}
+
+pub struct GroupInfo {
+    pub name: Arc<str>,
+    pub consumers: usize,
+    pub pending: usize,
+    pub last_delivered_id: StreamId,
+    pub entries_read: Option<u64>,
+    pub lag: Option<u64>,
+}
+
+pub struct ConsumerInfo {
+    pub name: Arc<str>,
+    pub pending: usize,
+    pub seen_time: u128,
+    pub active_time: Option<u128>,
+    pub idle: u128,
+    pub inactive: Option<u128>,
+}
+
+pub struct PendingDelivery {
+    pub id: StreamId,
+    pub consumer: Arc<str>,
+    pub delivery_time: u128,
+    pub delivery_count: u64,
+}
+
+// XINFO STREAM FULL view of a group, pending lists cut to the requested count
+pub struct GroupDetail {
+    pub info: GroupInfo,
+    pub pending: Vec<PendingDelivery>,
+    pub consumers: Vec<(ConsumerInfo, Vec<PendingDelivery>)>,
+}
+
+fn now_millis() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or_default()
+}
+
+impl ConsumerGroup {
+    fn new(last_id: StreamId, entries_read: Option<u64>) -> Self {
+        ConsumerGroup { last_id, entries_read, pending: BTreeMap::new(), consumers: BTreeMap::new() }
+    }
+
+    // Looks the consumer up, creating it on first use, and marks it as seen
+    fn touch_consumer(&mut self, name: &Arc<str>, now: u128) -> &mut Consumer {
+        let consumer = self.consumers.entry(Arc::clone(name))
+            .or_insert_with(|| Consumer { seen_time: now, active_time: None, pending: BTreeSet::new() });
+        consumer.seen_time = now;
+        consumer
+    }
+
+    // Gives pending entry `id` to `consumer`, taking it away from its previous owner
+    fn assign(&mut self, id: StreamId, consumer: &Arc<str>, delivery_time: u128, delivery_count: u64) {
+        let entry = PendingEntry { consumer: Arc::clone(consumer), delivery_time, delivery_count };
+        if let Some(owner) = self.pending.insert(id, entry).and_then(|previous| self.consumers.get_mut(&previous.consumer)) {
+            owner.pending.remove(&id);
+        }
+        if let Some(owner) = self.consumers.get_mut(consumer) {
+            owner.pending.insert(id);
+        }
+    }
+
+    fn unassign(&mut self, id: StreamId) -> bool {
+        match self.pending.remove(&id) {
+            Some(entry) => {
+                if let Some(owner) = self.consumers.get_mut(&entry.consumer) {
+                    owner.pending.remove(&id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
 }
 
-impl<K: Serialize, V: Serialize> Serialize for StreamValue<K, V> {
+impl Serialize for StreamValue {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("StreamValue", 4)?;
-        state.serialize_field("last_id", self.last_id.as_ref())?;
-        state.serialize_field("time_map", &self.time_map)?;
-        let map_serializable: BTreeMap<&str, (u128, u64, &Vec<(K, V)>)> = self.map.iter()
-            .map(|(k, (time, seq, pairs))| (k.as_ref(), (*time, *seq, pairs.as_ref())))
+        let mut state = serializer.serialize_struct("StreamValue", 2)?;
+        state.serialize_field("last_id", &self.last_id.to_string())?;
+        let entries = self.nodes.range(StreamId::MIN, StreamId::MAX, usize::MAX, false);
+        let map_serializable: Vec<(String, Vec<(&str, &str)>)> = entries.iter()
+            .map(|(id, pairs)| (id.to_string(), pairs.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect()))
             .collect();
         state.serialize_field("map", &map_serializable)?;
-        state.serialize_field("waiters_value", &(self.waiters_value.0.as_ref(), self.waiters_value.1.as_ref()))?;
         state.end()
     }
 }
 
-impl StreamValue<Arc<str>, Arc<str>>{
+impl StreamValue{
     pub fn new() -> Self {
-        StreamValue { last_id: Arc::from(""), time_map: HashMap::new(), map: BTreeMap::new() , waiters_value: (Arc::from(""), Arc::new(Vec::new()))}
+        StreamValue {
+            last_id: StreamId::MIN, nodes: StreamNodes::default(),
+            entries_added: 0, max_deleted_id: StreamId::MIN, groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, id: StreamId, pairs_grouped: Arc<Vec<(Arc<str>, Arc<str>)>>) {
+        self.nodes.push(id, &pairs_grouped);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    fn entry(&self, id: StreamId) -> Option<StreamFields> {
+        self.nodes.get(id)
+    }
+
+    fn first_id(&self) -> Option<StreamId> {
+        self.nodes.first().map(|(id, _)| id)
+    }
+
+    /// Entries with IDs in `start..=end`, newest first when `rev` is set.
+    pub fn range(&self, start: StreamId, end: StreamId, count: Option<usize>, rev: bool) -> Vec<(StreamId, StreamFields)> {
+        self.nodes.range(start, end, count.unwrap_or(usize::MAX), rev)
+    }
+
+    // Entries strictly after `id` in ID order, at most `count` of them
+    fn entries_after(&self, id: StreamId, count: Option<usize>) -> Vec<(StreamId, StreamFields)> {
+        match id.next() {
+            Some(start) => self.range(start, StreamId::MAX, count, false),
+            None => Vec::new(),
+        }
+    }
+
+    // Number of entries added up to and including `id`, when it can be worked out
+    fn estimate_entries_read(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let last_id = self.last_id;
+        if self.nodes.is_empty() && id <= last_id {
+            return Some(self.entries_added);
+        }
+        match id.cmp(&last_id) {
+            Ordering::Equal => return Some(self.entries_added),
+            Ordering::Greater => return None,
+            Ordering::Less => {}
+        }
+        let len = self.nodes.len() as u64;
+        match self.first_id() {
+            // deletions past the first entry make the count unknowable
+            Some(first_id) if self.max_deleted_id >= first_id => None,
+            Some(first_id) if id < first_id => Some(self.entries_added - len),
+            Some(first_id) if id == first_id => Some(self.entries_added - len + 1),
+            _ => None,
+        }
+    }
+
+    // Whether an entry at or after `id` was deleted with XDEL
+    fn has_tombstones_from(&self, id: StreamId) -> bool {
+        !self.nodes.is_empty() && self.max_deleted_id != StreamId::MIN && id <= self.max_deleted_id
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn remove_entry(&mut self, id: StreamId) -> bool {
+        self.nodes.remove(id)
+    }
+
+    /// XDEL, returns how many of the IDs were present.
+    pub fn delete(&mut self, ids: &[StreamId]) -> usize {
+        let mut deleted = 0;
+        for id in ids {
+            if self.remove_entry(*id) {
+                self.max_deleted_id = self.max_deleted_id.max(*id);
+                deleted += 1;
+            }
+        }
+        deleted
+    }
+
+    /// Evicts the oldest entries, returning how many were removed. Approximate
+    /// trimming only removes whole nodes.
+    pub fn trim(&mut self, options: &TrimOptions) -> usize {
+        let mut excess = match options.strategy {
+            TrimStrategy::MaxLen(max_len) => self.nodes.len().saturating_sub(max_len),
+            TrimStrategy::MinId(min_id) => self.nodes.count_before(min_id),
+        };
+        let limit = match options.limit {
+            Some(limit) => limit,
+            None if options.approx => STREAM_NODE_MAX_ENTRIES * 100,
+            None => 0,
+        };
+        if limit > 0 {
+            excess = excess.min(limit);
+        }
+        self.nodes.trim_front(excess, options.approx)
+    }
+
+    /// XSETID: moves the last ID forward and optionally overrides the counters.
+    pub fn set_id(&mut self, last_id: StreamId, entries_added: Option<u64>, max_deleted_id: Option<StreamId>) -> Result<(), String> {
+        if max_deleted_id.is_some_and(|max_deleted_id| last_id < max_deleted_id) {
+            return Err("The ID specified in XSETID is smaller than the provided max_deleted_entry_id".to_string());
+        }
+        if entries_added.is_some_and(|entries_added| entries_added < self.nodes.len() as u64) {
+            return Err("The entries_added specified in XSETID is smaller than the target stream length".to_string());
+        }
+        if !self.nodes.is_empty() && last_id < self.last_id {
+            return Err("The ID specified in XSETID is smaller than the target stream top item".to_string());
+        }
+
+        self.last_id = last_id;
+        if let Some(entries_added) = entries_added {
+            self.entries_added = entries_added;
+        }
+        if let Some(max_deleted_id) = max_deleted_id.filter(|id| *id != StreamId::MIN) {
+            self.max_deleted_id = max_deleted_id;
+        }
+        Ok(())
+    }
+
+    pub fn has_group(&self, group: &str) -> bool {
+        self.groups.contains_key(group)
+    }
+
+    /// Creates a group starting after `id`, or after the last entry when `id` is None.
+    /// Returns false if the group already exists.
+    pub fn create_group(&mut self, group: &Arc<str>, id: Option<StreamId>, entries_read: Option<u64>) -> bool {
+        if self.groups.contains_key(group) {
+            return false;
+        }
+        let last_id = id.unwrap_or(self.last_id);
+        self.groups.insert(Arc::clone(group), ConsumerGroup::new(last_id, entries_read));
+        true
+    }
+
+    pub fn set_group_id(&mut self, group: &str, id: Option<StreamId>, entries_read: Option<u64>) -> bool {
+        let last_id = id.unwrap_or(self.last_id);
+        match self.groups.get_mut(group) {
+            Some(group) => {
+                group.last_id = last_id;
+                group.entries_read = entries_read;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn destroy_group(&mut self, group: &str) -> bool {
+        self.groups.remove(group).is_some()
+    }
+
+    /// Returns whether the consumer was created, None if the group doesn't exist.
+    pub fn create_consumer(&mut self, group: &str, consumer: &Arc<str>) -> Option<bool> {
+        let group = self.groups.get_mut(group)?;
+        if group.consumers.contains_key(consumer) {
+            return Some(false);
+        }
+        group.touch_consumer(consumer, now_millis());
+        Some(true)
+    }
+
+    /// Removes a consumer and its pending entries, returning how many it had.
+    pub fn delete_consumer(&mut self, group: &str, consumer: &str) -> Option<usize> {
+        let group = self.groups.get_mut(group)?;
+        let Some(removed) = group.consumers.remove(consumer) else {
+            return Some(0);
+        };
+        for id in &removed.pending {
+            group.pending.remove(id);
+        }
+        Some(removed.pending.len())
+    }
+
+    /// XREADGROUP on one stream. With `after` set the consumer's own pending entries
+    /// past that ID are served again (deleted ones without fields), otherwise new
+    /// entries are delivered and, unless `noack`, added to the pending lists.
+    pub fn read_group(&mut self, group: &str, consumer: &Arc<str>, after: Option<StreamId>, count: Option<usize>, noack: bool) -> Option<Vec<GroupEntry>> {
+        let now = now_millis();
+        match after {
+            Some(after) => {
+                let ids = self.groups.get(group)?.consumers.get(consumer)
+                    .map(|owner| owner.pending.range((Bound::Excluded(after), Bound::Unbounded))
+                        .take(count.unwrap_or(usize::MAX))
+                        .copied()
+                        .collect::<Vec<_>>())
+                    .unwrap_or_default();
+                let entries = ids.into_iter().map(|id| (id, self.entry(id))).collect::<Vec<_>>();
+
+                let group = self.groups.get_mut(group)?;
+                group.touch_consumer(consumer, now);
+                for (id, fields) in &entries {
+                    if let (Some(pending), Some(_)) = (group.pending.get_mut(id), fields) {
+                        pending.delivery_time = now;
+                        pending.delivery_count += 1;
+                    }
+                }
+                Some(entries)
+            }
+            None => {
+                let last_id = self.groups.get(group)?.last_id;
+                let entries = self.entries_after(last_id, count);
+                let tombstones = entries.first().is_some_and(|(id, _)| self.has_tombstones_from(*id));
+                let estimated = entries.last().map(|(id, _)| self.estimate_entries_read(*id));
+
+                let group = self.groups.get_mut(group)?;
+                let owner = group.touch_consumer(consumer, now);
+                if let Some(((last_delivered, _), estimated)) = entries.last().zip(estimated) {
+                    owner.active_time = Some(now);
+                    group.last_id = *last_delivered;
+                    group.entries_read = match group.entries_read {
+                        Some(read) if !tombstones => Some(read + entries.len() as u64),
+                        _ => estimated,
+                    };
+                }
+                if !noack {
+                    for (id, _) in &entries {
+                        group.assign(*id, consumer, now, 1);
+                    }
+                }
+                Some(entries.into_iter().map(|(id, fields)| (id, Some(fields))).collect())
+            }
+        }
+    }
+
+    /// The group's last delivered ID and entries-read counter, plus the pending
+    /// deliveries among `ids`, None if the group doesn't exist.
+    pub fn group_deliveries(&self, group: &str, ids: &[StreamId]) -> Option<(StreamId, Option<u64>, Vec<PendingDelivery>)> {
+        let group = self.groups.get(group)?;
+        let deliveries = ids.iter()
+            .filter_map(|id| group.pending.get(id).map(|pending| PendingDelivery {
+                id: *id,
+                consumer: Arc::clone(&pending.consumer),
+                delivery_time: pending.delivery_time,
+                delivery_count: pending.delivery_count,
+            }))
+            .collect();
+        Some((group.last_id, group.entries_read, deliveries))
+    }
+
+    /// Removes the IDs from the group's pending entries, returning how many were pending.
+    pub fn ack(&mut self, group: &str, ids: &[StreamId]) -> usize {
+        match self.groups.get_mut(group) {
+            Some(group) => ids.iter().filter(|id| group.unassign(**id)).count(),
+            None => 0,
+        }
+    }
+
+    pub fn pending_summary(&self, group: &str) -> Option<PendingSummary> {
+        let group = self.groups.get(group)?;
+        let bounds = group.pending.keys().next().zip(group.pending.keys().next_back())
+            .map(|(first, last)| (*first, *last));
+        let consumers = group.consumers.iter()
+            .filter(|(_, consumer)| !consumer.pending.is_empty())
+            .map(|(name, consumer)| (Arc::clone(name), consumer.pending.len()))
+            .collect();
+        Some(PendingSummary { count: group.pending.len(), bounds, consumers })
+    }
+
+    pub fn pending_range(&self, group: &str, start: StreamId, end: StreamId, count: usize, consumer: Option<&str>, min_idle: u128) -> Option<Vec<PendingDetail>> {
+        let group = self.groups.get(group)?;
+        if start > end {
+            return Some(Vec::new());
+        }
+        let now = now_millis();
+        let details = group.pending.range(start..=end)
+            .filter(|(_, pending)| consumer.is_none_or(|consumer| pending.consumer.as_ref() == consumer))
+            .map(|(id, pending)| PendingDetail {
+                id: *id,
+                consumer: Arc::clone(&pending.consumer),
+                idle: now.saturating_sub(pending.delivery_time),
+                delivery_count: pending.delivery_count,
+            })
+            .filter(|detail| detail.idle >= min_idle)
+            .take(count)
+            .collect();
+        Some(details)
+    }
+
+    /// XCLAIM: moves the pending entries idle for at least `min_idle` to `consumer`.
+    /// Entries deleted from the stream are dropped from the pending list instead.
+    pub fn claim(&mut self, group: &str, consumer: &Arc<str>, ids: &[StreamId], options: &ClaimOptions) -> Option<Vec<(StreamId, StreamFields)>> {
+        let now = now_millis();
+        let found = ids.iter().map(|id| (*id, self.entry(*id))).collect::<Vec<_>>();
+        let group = self.groups.get_mut(group)?;
+
+        let delivery_time = match (options.idle, options.time) {
+            (Some(idle), _) => now.saturating_sub(idle),
+            (None, Some(time)) => time.min(now),
+            (None, None) => now,
+        };
+        if let Some(last_id) = options.last_id {
+            group.last_id = group.last_id.max(last_id);
+        }
+
+        let mut claimed = Vec::new();
+        for (id, fields) in found {
+            let Some(fields) = fields else {
+                group.unassign(id);
+                continue;
+            };
+            let delivery_count = match group.pending.get(&id) {
+                Some(pending) => {
+                    if now.saturating_sub(pending.delivery_time) < options.min_idle {
+                        continue;
+                    }
+                    pending.delivery_count
+                }
+                None if options.force => 0,
+                None => continue,
+            };
+            let delivery_count = match options.retry_count {
+                Some(retry_count) => retry_count,
+                None if options.justid => delivery_count,
+                None => delivery_count + 1,
+            };
+            group.touch_consumer(consumer, now).active_time = Some(now);
+            group.assign(id, consumer, delivery_time, delivery_count);
+            claimed.push((id, fields));
+        }
+        Some(claimed)
+    }
+
+    /// XAUTOCLAIM: scans the pending list from `start`, claiming up to `count` entries
+    /// idle for at least `min_idle` and looking at no more than ten times that many.
+    pub fn auto_claim(&mut self, group: &str, consumer: &Arc<str>, min_idle: u128, start: StreamId, count: usize, justid: bool) -> Option<AutoClaimed> {
+        let now = now_millis();
+        let attempts = count.saturating_mul(10);
+        let candidates = self.groups.get(group)?.pending.range(start..)
+            .take(attempts.saturating_add(1))
+            .map(|(id, pending)| (*id, pending.delivery_time, pending.delivery_count))
+            .collect::<Vec<_>>();
+        let candidates = candidates.into_iter()
+            .map(|(id, delivery_time, delivery_count)| (id, delivery_time, delivery_count, self.entry(id)))
+            .collect::<Vec<_>>();
+
+        let group = self.groups.get_mut(group)?;
+        group.touch_consumer(consumer, now);
+        let mut claimed = Vec::new();
+        let mut deleted = Vec::new();
+        let mut examined = 0;
+        for (id, delivery_time, delivery_count, fields) in &candidates {
+            if examined == attempts || claimed.len() == count {
+                break;
+            }
+            examined += 1;
+            let Some(fields) = fields else {
+                group.unassign(*id);
+                deleted.push(*id);
+                continue;
+            };
+            if now.saturating_sub(*delivery_time) < min_idle {
+                continue;
+            }
+            let delivery_count = if justid { *delivery_count } else { delivery_count + 1 };
+            group.touch_consumer(consumer, now).active_time = Some(now);
+            group.assign(*id, consumer, now, delivery_count);
+            claimed.push((*id, Arc::clone(fields)));
+        }
+
+        let next = candidates.get(examined).map_or(StreamId::MIN, |(id, ..)| *id);
+        Some(AutoClaimed { next, claimed, deleted })
+    }
+
+    // Entries between the group's last delivered ID and the stream's end, when known
+    fn group_lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        match group.entries_read {
+            Some(read) if !self.has_tombstones_from(group.last_id) => Some(self.entries_added.saturating_sub(read)),
+            _ => self.estimate_entries_read(group.last_id).map(|read| self.entries_added.saturating_sub(read)),
+        }
+    }
+
+    fn group_info(&self, name: &Arc<str>, group: &ConsumerGroup) -> GroupInfo {
+        GroupInfo {
+            name: Arc::clone(name),
+            consumers: group.consumers.len(),
+            pending: group.pending.len(),
+            last_delivered_id: group.last_id,
+            entries_read: group.entries_read,
+            lag: self.group_lag(group),
+        }
+    }
+
+    fn consumer_info(name: &Arc<str>, consumer: &Consumer, now: u128) -> ConsumerInfo {
+        ConsumerInfo {
+            name: Arc::clone(name),
+            pending: consumer.pending.len(),
+            seen_time: consumer.seen_time,
+            active_time: consumer.active_time,
+            idle: now.saturating_sub(consumer.seen_time),
+            inactive: consumer.active_time.map(|active_time| now.saturating_sub(active_time)),
+        }
+    }
+
+    /// XINFO STREAM, with up to `full` entries when the FULL form is asked for.
+    pub fn info(&self, full: Option<usize>) -> StreamInfo {
+        let first_entry = self.nodes.first();
+        let last_entry = self.nodes.last();
+        let radix_tree_keys = self.nodes.node_count();
+        StreamInfo {
+            length: self.nodes.len(),
+            radix_tree_keys,
+            // the nodes sit in a BTreeMap, count the root and one node per key
+            radix_tree_nodes: radix_tree_keys + 1,
+            last_generated_id: self.last_id,
+            max_deleted_entry_id: self.max_deleted_id,
+            entries_added: self.entries_added,
+            recorded_first_entry_id: first_entry.as_ref().map_or(StreamId::MIN, |(id, _)| *id),
+            groups: self.groups.len(),
+            entries: full.map(|count| self.range(StreamId::MIN, StreamId::MAX, Some(count), false)).unwrap_or_default(),
+            first_entry,
+            last_entry,
+        }
+    }
+
+    pub fn groups_info(&self) -> Vec<GroupInfo> {
+        self.groups.iter().map(|(name, group)| self.group_info(name, group)).collect()
     }
 
-    pub fn new_blocked(id: Arc<str>, pairs_grouped: Arc<Vec<(Arc<str>, Arc<str>)>>) -> Self {
-        StreamValue { last_id: Arc::from(""), time_map: HashMap::new(), map: BTreeMap::new() , waiters_value: (id, pairs_grouped)}
+    /// XINFO STREAM FULL groups, each pending list holding at most `count` entries.
+    pub fn groups_detail(&self, count: usize) -> Vec<GroupDetail> {
+        let now = now_millis();
+        let delivery = |id: &StreamId, pending: &PendingEntry| PendingDelivery {
+            id: *id,
+            consumer: Arc::clone(&pending.consumer),
+            delivery_time: pending.delivery_time,
+            delivery_count: pending.delivery_count,
+        };
+        self.groups.iter().map(|(name, group)| {
+            let pending = group.pending.iter().take(count)
+                .map(|(id, pending)| delivery(id, pending))
+                .collect();
+            let consumers = group.consumers.iter().map(|(consumer_name, consumer)| {
+                let pending = consumer.pending.iter().take(count)
+                    .filter_map(|id| group.pending.get(id).map(|pending| delivery(id, pending)))
+                    .collect();
+                (Self::consumer_info(consumer_name, consumer, now), pending)
+            }).collect();
+            GroupDetail { info: self.group_info(name, group), pending, consumers }
+        }).collect()
     }
 
-    pub fn insert(&mut self, id: Arc<str>, id_time: u128, id_seq: u64, pairs_grouped: Arc<Vec<(Arc<str>, Arc<str>)>>) {
-        self.map.insert(id, (id_time, id_seq, pairs_grouped));
+    /// XINFO CONSUMERS, None if the group doesn't exist.
+    pub fn consumers_info(&self, group: &str) -> Option<Vec<ConsumerInfo>> {
+        let now = now_millis();
+        let group = self.groups.get(group)?;
+        Some(group.consumers.iter().map(|(name, consumer)| Self::consumer_info(name, consumer, now)).collect())
     }
 }
 
@@ -104,167 +756,59 @@ impl RedisValue{
         }
     }
 
-    pub fn get_blocked_result(&self) -> Option<Vec<Value>> {
-        match self{
-            RedisValue::String(_) => None,
-            RedisValue::Array(_) => None,
-            RedisValue::Number(_) => None,
-            RedisValue::StringWithTimeout((_, _)) => None,
-            RedisValue::Flags(_) => None,
-            RedisValue::Stream(val) => {
-                let (id, pairs) = &val.waiters_value;
-                let pairs_flattened = pairs.iter().flat_map(|(k, v)| [k.as_ref(), v.as_ref()]).collect::<Vec<&str>>();
-                Some(vec![json!([id.as_ref(), pairs_flattened])])
-            },
-        }
-    }
-
-    pub fn get_stream_range(&self, start_id: &str, stop_id: Option<&str>) -> RedisResult<Vec<Value>>{
-        match self{
-            RedisValue::String(_) => Ok(Vec::new()), // fix error handling,
-            RedisValue::Number(_) => Ok(Vec::new()), // fix error handling,
-            RedisValue::Array(_) => Ok(Vec::new()),
-            RedisValue::StringWithTimeout((_, _)) => Ok(Vec::new()), // fix error handling,
-            RedisValue::Flags(_) => Ok(Vec::new()),
-            RedisValue::Stream(stream) => {
-                let mut entries = Vec::new();
-                if let Some((start_id_pre, start_id_post)) = start_id.split_once("-"){
-                    let stop_time: Option<u128>;
-                    let stop_seq: Option<u64>;
-
-                    match stop_id{
-                        Some(stop_id ) => {
-                            match stop_id{
-                                "+" => {
-                                    stop_time = None;
-                                    stop_seq = None;
-                                },
-                                _ => {
-                                    match stop_id.split_once("-"){
-                                        Some((stop_id_pre, stop_id_post)) => {
-                                            stop_time = Some(stop_id_pre.parse::<u128>()?);
-                                            stop_seq = Some(stop_id_post.parse::<u64>()?);
-                                        },
-
-                                        None => return Err(RedisError::InvalidStreamId("Invalid stop ID format".to_string()))
-                                    } 
-                                },
-                            }
-                        },
-
-                        None => {
-                            stop_time = None;
-                            stop_seq = None;
-                        },
-                    }
-
-                    let start_time: u128;
-                    let start_seq: u64;
-                    if start_id == "-"{
-                        start_time = 0;
-                        start_seq = 0;
-                    } else {
-                        start_time = start_id_pre.parse::<u128>()?;
-                        start_seq = start_id_post.parse::<u64>()?;
-                    }
-
-                    stream.map.iter().for_each(|e| {
-                        let (time, seq, pairs) = e.1;
-
-                        let result = if let (Some(st), Some(ss)) = (stop_time, stop_seq) {
-                            *time >= start_time && *time <= st && *seq >= start_seq && *seq <= ss
-                        } else if stop_time.is_none() && stop_seq.is_none() && stop_id.is_some(){
-                            *time >= start_time && *seq >= start_seq
-                        } else { // xread
-                            *time > start_time || *seq > start_seq
-                        };
-
-                        if result {
-                            let flattened = pairs.iter().flat_map(|(k, v)| [k.as_ref(), v.as_ref()]).collect::<Vec<&str>>();
-                            entries.push(json!([e.0.as_ref(), flattened]));
-                        }
-                    });
-                }
-
-                Ok(entries)
-            },
-        }
-    }
-
-    pub fn update_stream(&mut self, id: &Arc<str>, pairs: Arc<Vec<(Arc<str>, Arc<str>)>>) -> RedisResult<String>{
+    pub fn update_stream(&mut self, id: &str, pairs: Arc<Vec<(Arc<str>, Arc<str>)>>) -> RedisResult<String>{
         match self{
             RedisValue::String(_) | RedisValue::Number(_) | 
             RedisValue::Array(_) | RedisValue::StringWithTimeout(_) | RedisValue::Flags(_) => {
                 Err(RedisError::WrongType("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()))
             },
             RedisValue::Stream(stream) => {
-                let (new_id_time, new_id_seq) = match id.as_ref(){
+                let last_id = stream.last_id;
+                let new_id = match id {
                     "*" => {
                         let millis = SystemTime::now()
                             .duration_since(UNIX_EPOCH)
                             .map_err(|e| RedisError::Other(format!("System time error: {}", e)))?
-                            .as_millis();
-
-                        let id_sequence_num = stream.time_map
-                            .get(&millis)
-                            .map(|&seq| seq + 1)
-                            .unwrap_or(0); //todo check this
+                            .as_millis() as u64;
 
-                        (millis, id_sequence_num)
+                        // the clock may be behind the last ID, e.g. after XSETID
+                        if millis > last_id.ms {
+                            Some(StreamId::new(millis, 0))
+                        } else {
+                            last_id.next()
+                        }
                     },
                     _ => {
-                        let (id_pre, id_post) = match id.split_once("-") {
-                            Some(parts) => parts,
-                            None => return Err(RedisError::InvalidStreamId("Invalid ID format".to_string())),
-                        };
-
-                        if id_pre.is_empty() || id_post.is_empty(){
-                            return Err(RedisError::InvalidStreamId("ID parts cannot be empty".to_string()));
-                        }
-
-                        let id_millisecs = id_pre.parse::<u128>()?;
-                        let id_sequence_num = match id_post{
-                            "*" => {
-                                stream.time_map
-                                    .get(&id_millisecs)
-                                    .map(|&seq| seq + 1)
-                                    .unwrap_or(if id_millisecs == 0 { 1 } else { 0 })
+                        let new_id = match id.split_once('-') {
+                            Some((ms, "*")) => {
+                                let Ok(ms) = ms.parse::<u64>() else {
+                                    return Ok("-ERR Invalid stream ID specified as stream command argument\r\n".to_string())
+                                };
+                                match ms.cmp(&last_id.ms) {
+                                    Ordering::Equal => last_id.next().filter(|next| next.ms == ms),
+                                    Ordering::Greater => Some(StreamId::new(ms, 0)),
+                                    Ordering::Less => None,
+                                }
+                            },
+                            _ => match StreamId::parse(id, 0) {
+                                Some(new_id) => Some(new_id),
+                                None => return Ok("-ERR Invalid stream ID specified as stream command argument\r\n".to_string()),
                             },
-                            _ => id_post.parse::<u64>()?,
                         };
 
-                        if id_millisecs == 0 && id_sequence_num == 0 {
+                        if new_id == Some(StreamId::MIN) {
                             return Ok(format!("-ERR The ID specified in XADD must be greater than 0-0\r\n")) //tester expects this format
                         }
-
-                        if !stream.last_id.is_empty() {
-                            if let Some((last_id_pre, last_id_post)) = stream.last_id.split_once("-"){
-                                if !last_id_pre.is_empty() && !last_id_post.is_empty(){
-                                    let last_id_millisecs = last_id_pre.parse::<u128>()?;
-                                    let last_id_sequence_num = last_id_post.parse::<u64>()?;
-
-                                    if last_id_millisecs > id_millisecs ||
-                                       (last_id_millisecs == id_millisecs && last_id_sequence_num >= id_sequence_num) {
-                                        return Ok(format!("-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n")) //tester expects this format
-                                    }
-                                }
-                            }
-                        }
-
-                        stream.time_map
-                            .entry(id_millisecs)
-                            .and_modify(|seq| *seq = (*seq).max(id_sequence_num))
-                            .or_insert(id_sequence_num);
-
-                        (id_millisecs, id_sequence_num)
+                        new_id.filter(|new_id| *new_id > last_id)
                     },
                 };
 
-                let new_id_string = format!("{}-{}", new_id_time, new_id_seq);
-                let new_id_arc: Arc<str> = Arc::from(new_id_string.as_str());
-                stream.last_id = Arc::clone(&new_id_arc);
-                stream.insert(new_id_arc, new_id_time, new_id_seq, pairs);
+                let Some(new_id) = new_id else {
+                    return Ok("-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n".to_string()) //tester expects this format
+                };
+                stream.insert(new_id, pairs);
 
+                let new_id_string = new_id.to_string();
                 Ok(format!("${}\r\n{}\r\n", new_id_string.len(), new_id_string))
             },
         }
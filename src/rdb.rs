@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufReader, Read};
 use std::path::Path;
 use std::sync::Arc;
@@ -8,6 +8,7 @@ use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use crate::error::{RedisError, RedisResult};
 use crate::protocol::RedisValue;
 
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
 const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
 const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
 const RDB_OPCODE_SELECTDB: u8 = 0xFE;
@@ -17,6 +18,14 @@ const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
 
 const RDB_TYPE_STRING: u8 = 0;
 
+/// What an RDB file holds that the server understands: string keys and the
+/// code of FUNCTION libraries.
+#[derive(Default)]
+pub struct RdbContents {
+    pub keys: HashMap<Arc<str>, RedisValue>,
+    pub functions: Vec<Arc<str>>,
+}
+
 struct RdbParser<R> {
     reader: R,
 }
@@ -83,11 +92,12 @@ impl<R: Read> RdbParser<R> {
         }
     }
 
-    fn parse(&mut self) -> RedisResult<HashMap<Arc<str>, RedisValue>> {
+    fn parse(&mut self) -> RedisResult<RdbContents> {
         // Skip header (REDIS + 4 digit version)
         self.read_bytes(9)?;
 
         let mut data: HashMap<Arc<str>, RedisValue> = HashMap::new();
+        let mut functions = Vec::new();
         let mut current_expiry: Option<Instant> = None;
 
         loop {
@@ -134,6 +144,9 @@ impl<R: Read> RdbParser<R> {
                         Instant::now() - Duration::from_secs(1)
                     });
                 }
+                RDB_OPCODE_FUNCTION2 => {
+                    functions.push(self.read_string()?);
+                }
                 RDB_OPCODE_EOF => break,
                 RDB_TYPE_STRING => {
                     let key = self.read_string()?;
@@ -153,15 +166,15 @@ impl<R: Read> RdbParser<R> {
             }
         }
 
-        Ok(data)
+        Ok(RdbContents { keys: data, functions })
     }
 }
 
-pub fn load_rdb_file(dir: &str, filename: &str) -> RedisResult<HashMap<Arc<str>, RedisValue>> {
+pub fn load_rdb_file(dir: &str, filename: &str) -> RedisResult<RdbContents> {
     let path = Path::new(dir).join(filename);
 
     if !path.exists() {
-        return Ok(HashMap::new());
+        return Ok(RdbContents::default());
     }
 
     let file = File::open(&path)
@@ -169,3 +182,81 @@ pub fn load_rdb_file(dir: &str, filename: &str) -> RedisResult<HashMap<Arc<str>,
 
     RdbParser::new(BufReader::new(file)).parse()
 }
+
+/// Parses an RDB received in memory, the payload of a full resync.
+pub fn parse_rdb(bytes: &[u8]) -> RedisResult<RdbContents> {
+    RdbParser::new(bytes).parse()
+}
+
+fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend([0x40 | (len >> 8) as u8, len as u8]);
+    } else {
+        out.push(0x80);
+        out.extend((len as u32).to_be_bytes());
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_length(out, s.len());
+    out.extend(s.as_bytes());
+}
+
+/// Serializes function libraries as FUNCTION2 entries, the body of an RDB file
+/// before any keys and what FUNCTION DUMP returns.
+pub fn encode_functions(functions: &[Arc<str>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for code in functions {
+        out.push(RDB_OPCODE_FUNCTION2);
+        write_string(&mut out, code);
+    }
+    out
+}
+
+/// Reads back what encode_functions wrote.
+pub fn decode_functions(bytes: &[u8]) -> RedisResult<Vec<Arc<str>>> {
+    let mut parser = RdbParser::new(bytes);
+    let mut functions = Vec::new();
+    while !parser.reader.is_empty() {
+        match parser.read_byte()? {
+            RDB_OPCODE_FUNCTION2 => functions.push(parser.read_string()?),
+            opcode => return Err(RedisError::Other(format!("Unsupported RDB opcode in payload: {}", opcode))),
+        }
+    }
+    Ok(functions)
+}
+
+/// Serializes a whole RDB file. Keys are strings with an optional expiry in unix
+/// milliseconds; the checksum is left zero, which loaders take as disabled.
+pub fn encode_rdb(functions: &[Arc<str>], keys: &[(Arc<str>, Arc<str>, Option<u64>)]) -> Vec<u8> {
+    let mut out = b"REDIS0011".to_vec();
+    out.extend(encode_functions(functions));
+    if !keys.is_empty() {
+        out.push(RDB_OPCODE_SELECTDB);
+        write_length(&mut out, 0);
+        for (key, value, expire_ms) in keys {
+            if let Some(expire_ms) = expire_ms {
+                out.push(RDB_OPCODE_EXPIRETIME_MS);
+                out.extend(expire_ms.to_le_bytes());
+            }
+            out.push(RDB_TYPE_STRING);
+            write_string(&mut out, key);
+            write_string(&mut out, value);
+        }
+    }
+    out.push(RDB_OPCODE_EOF);
+    out.extend([0u8; 8]);
+    out
+}
+
+/// Writes an RDB file through a temporary file, so a crash never leaves a half written dump.
+pub fn save_rdb_file(dir: &str, filename: &str, rdb: &[u8]) -> RedisResult<()> {
+    let path = Path::new(dir).join(filename);
+    let temp_path = Path::new(dir).join(format!("temp-{}.rdb", std::process::id()));
+    fs::write(&temp_path, rdb)
+        .map_err(|e| RedisError::Other(format!("Failed to write RDB: {}", e)))?;
+    fs::rename(&temp_path, &path)
+        .map_err(|e| RedisError::Other(format!("Failed to write RDB: {}", e)))
+}
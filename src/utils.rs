@@ -1,4 +1,5 @@
 use serde_json::{Value, json};
+use rand::Rng;
 use std::str::from_utf8;
 use std::sync::Arc;
 
@@ -62,6 +63,84 @@ pub fn collect_as_strings<I>(iter: I) -> Vec<Arc<str>>
         .collect::<Vec<_>>()
     }
 
+/// Redis glob-style matching: `*`, `?`, `[...]` classes with `^` negation and
+/// `a-z` ranges, and `\` escaping the next character.
+pub fn glob_match(pattern: &[u8], string: &[u8], nocase: bool) -> bool {
+    let fold = |c: u8| if nocase { c.to_ascii_lowercase() } else { c };
+    let (mut p, mut s) = (0, 0);
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while pattern.get(p + 1) == Some(&b'*') {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                return (s..=string.len()).any(|start| glob_match(&pattern[p + 1..], &string[start..], nocase));
+            }
+            b'?' => {
+                if s == string.len() {
+                    return false;
+                }
+                s += 1;
+            }
+            b'[' => {
+                let Some(&c) = string.get(s) else {
+                    return false;
+                };
+                let c = fold(c);
+                p += 1;
+                let negate = pattern.get(p) == Some(&b'^');
+                if negate {
+                    p += 1;
+                }
+                let mut matched = false;
+                loop {
+                    match pattern.get(p) {
+                        // an unterminated class ends the pattern
+                        None => {
+                            p -= 1;
+                            break;
+                        }
+                        Some(b']') => break,
+                        Some(b'\\') if p + 1 < pattern.len() => {
+                            p += 1;
+                            matched |= fold(pattern[p]) == c;
+                        }
+                        Some(&start) if pattern.get(p + 1) == Some(&b'-') && p + 2 < pattern.len() => {
+                            let (start, end) = (fold(start), fold(pattern[p + 2]));
+                            p += 2;
+                            matched |= (start.min(end)..=start.max(end)).contains(&c);
+                        }
+                        Some(&other) => matched |= fold(other) == c,
+                    }
+                    p += 1;
+                }
+                if matched == negate {
+                    return false;
+                }
+                s += 1;
+            }
+            c => {
+                let c = match c {
+                    b'\\' if p + 1 < pattern.len() => {
+                        p += 1;
+                        pattern[p]
+                    }
+                    c => c,
+                };
+                if string.get(s).is_none_or(|&other| fold(other) != fold(c)) {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+    }
+    s == string.len()
+}
+
 pub fn parse_wrapback(idx: i64, len: usize) -> RedisResult<usize> {
         if idx.is_negative() {
             let idx_abs = idx.unsigned_abs() as usize;
@@ -75,6 +154,11 @@ pub fn parse_wrapback(idx: i64, len: usize) -> RedisResult<usize> {
         }
     }
 
+// Uniformly random index in 0..len
+pub fn random_below(len: usize) -> usize {
+        rand::thread_rng().gen_range(0..len)
+}
+
 pub fn encode_resp_array_arc(array: &[Arc<str>]) -> String{
         let mut encoded_array = format!["*{}\r\n", array.len()];
         for item in array {
@@ -132,6 +216,7 @@ pub fn encode_resp_value_array(encoded_array: &mut String, array: &Vec<Value>) {
                     encode_resp_value_array(encoded_array, val);
                 },
                 Value::String(s) => encoded_array.push_str(&format!("${}\r\n{}\r\n", s.len(), s)),
+                Value::Number(n) => encoded_array.push_str(&format!(":{}\r\n", n)),
                 Value::Null => encoded_array.push_str("*-1\r\n"),
                 _ => (), //not supported
             }
@@ -273,6 +358,8 @@ const MAX_LATITUDE: f64 = 85.05112878;
 const MIN_LONGITUDE: f64 = -180.0;
 const MAX_LONGITUDE: f64 = 180.0;
 
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+
 const LATITUDE_RANGE: f64 = MAX_LATITUDE - MIN_LATITUDE;
 const LONGITUDE_RANGE: f64 = MAX_LONGITUDE - MIN_LONGITUDE;
 
@@ -304,6 +391,24 @@ pub fn encode_coordinates_to_score(latitude: f64, longitude: f64) -> u64 {
     interleave(lat_int, lon_int)
 }
 
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Standard 11 character base32 geohash. Scores use the mercator latitude range,
+/// so the point is re-encoded over [-90, 90] first.
+pub fn geohash_string(coordinates: &Coordinates) -> String {
+    let lat_int = (2.0_f64.powi(26) * (coordinates.latitude + 90.0) / 180.0) as u32;
+    let lon_int = (2.0_f64.powi(26) * (coordinates.longitude - MIN_LONGITUDE) / LONGITUDE_RANGE) as u32;
+    let bits = interleave(lat_int, lon_int);
+
+    (0..11)
+        .map(|i| {
+            // only 52 bits are available, the last character is always '0'
+            let idx = if i == 10 { 0 } else { (bits >> (52 - (i + 1) * 5)) & 0x1f };
+            GEOHASH_ALPHABET[idx as usize] as char
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct Coordinates {
     latitude: f64,
@@ -311,16 +416,23 @@ pub struct Coordinates {
 }
 
 impl Coordinates {
+    pub fn new(longitude: f64, latitude: f64) -> Self {
+        Coordinates { latitude, longitude }
+    }
+
+    pub fn longitude(&self) -> f64 {
+        self.longitude
+    }
+
+    pub fn latitude(&self) -> f64 {
+        self.latitude
+    }
+
     pub fn as_value(&self) -> Value {
         json!([ self.longitude.to_string(), self.latitude.to_string()])
     }
 }
 
-pub fn coord_from_str(longitude_str: &Arc<str>, latitude_str: &Arc<str>) -> RedisResult<Coordinates>{
-    let (longitude, latitude) = (longitude_str.parse::<f64>()?, latitude_str.parse::<f64>()?);
-    Ok(Coordinates { latitude, longitude })
-}
-
 fn compact_int64_to_int32(v: u64) -> u32 {
     let mut result = v & 0x5555555555555555;
     result = (result | (result >> 1)) & 0x3333333333333333;
@@ -357,8 +469,6 @@ pub fn decode_score_to_coordinates(geo_code: u64) -> Coordinates {
 }
 
 pub fn haversine_distance(origin: &Coordinates, destination: &Coordinates) -> f64 {
-    const R: f64 = 6372797.560856;
-
     let lat1 = origin.latitude.to_radians();
     let lat2 = destination.latitude.to_radians();
     let d_lat = lat2 - lat1;
@@ -366,5 +476,135 @@ pub fn haversine_distance(origin: &Coordinates, destination: &Coordinates) -> f6
 
     let a = (d_lat / 2.0).sin().powi(2) + (d_lon / 2.0).sin().powi(2) * lat1.cos() * lat2.cos();
     let c = 2.0 * a.sqrt().asin();
-    R * c
-}
\ No newline at end of file
+    EARTH_RADIUS_IN_METERS * c
+}
+
+// Meters per unit accepted by the GEO commands
+pub fn parse_distance_unit(unit: &str) -> Option<f64> {
+    match unit.to_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "mi" => Some(1609.34),
+        "ft" => Some(0.3048),
+        _ => None,
+    }
+}
+
+// Distance to `point` if it lies inside the width x height box (meters) centered on `center`
+pub fn distance_if_in_box(center: &Coordinates, point: &Coordinates, width: f64, height: f64) -> Option<f64> {
+    // the latitude check is cheaper so it goes first, like redis does
+    let lat_distance = EARTH_RADIUS_IN_METERS * (point.latitude.to_radians() - center.latitude.to_radians()).abs();
+    if lat_distance > height / 2.0 {
+        return None;
+    }
+    let same_lat = Coordinates { latitude: point.latitude, longitude: center.longitude };
+    if haversine_distance(&same_lat, point) > width / 2.0 {
+        return None;
+    }
+    Some(haversine_distance(center, point))
+}
+
+const MERCATOR_MAX: f64 = 20037726.37;
+const GEO_STEP_MAX: u32 = 26;
+
+// Geohash precision (bits per coordinate) whose cells are about as wide as the search range
+fn estimate_steps_by_radius(range_meters: f64, latitude: f64) -> u32 {
+    if range_meters == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut range_meters = range_meters;
+    let mut step: i32 = 1;
+    while range_meters < MERCATOR_MAX {
+        range_meters *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+
+    // cells get narrower towards the poles
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP_MAX as i32) as u32
+}
+
+// (min_lon, min_lat, max_lon, max_lat) around `center`, half extents in meters
+fn bounding_box(center: &Coordinates, half_width: f64, half_height: f64) -> (f64, f64, f64, f64) {
+    let lat_delta = (half_height / EARTH_RADIUS_IN_METERS).to_degrees();
+    let lon_delta_top = (half_width / EARTH_RADIUS_IN_METERS / (center.latitude + lat_delta).to_radians().cos()).to_degrees();
+    let lon_delta_bottom = (half_width / EARTH_RADIUS_IN_METERS / (center.latitude - lat_delta).to_radians().cos()).to_degrees();
+    // the widest edge is the one closer to the pole
+    let lon_delta = if center.latitude < 0.0 { lon_delta_bottom } else { lon_delta_top };
+    (center.longitude - lon_delta, center.latitude - lat_delta, center.longitude + lon_delta, center.latitude + lat_delta)
+}
+
+// Cell indexes (latitude, longitude) holding `center` at the given precision
+fn geohash_cell(center: &Coordinates, step: u32) -> (i64, i64) {
+    let cells = (1u64 << step) as f64;
+    let lat_cell = ((center.latitude - MIN_LATITUDE) / LATITUDE_RANGE * cells) as i64;
+    let lon_cell = ((center.longitude - MIN_LONGITUDE) / LONGITUDE_RANGE * cells) as i64;
+    (lat_cell.clamp(0, cells as i64 - 1), lon_cell.clamp(0, cells as i64 - 1))
+}
+
+fn cell_edge(index: i64, step: u32, min: f64, range: f64) -> f64 {
+    min + range * index as f64 / (1u64 << step) as f64
+}
+
+/// Score ranges `[min, max)` of the geohash cells (the center one plus its eight
+/// neighbours) that together cover the search area, so callers can range-scan the
+/// sorted set instead of decoding every member.
+pub fn geohash_search_ranges(center: &Coordinates, half_width: f64, half_height: f64, radius: f64) -> Vec<(u64, u64)> {
+    let (min_lon, min_lat, max_lon, max_lat) = bounding_box(center, half_width, half_height);
+    let mut step = estimate_steps_by_radius(radius, center.latitude);
+    let (mut lat_cell, mut lon_cell) = geohash_cell(center, step);
+
+    // near a cell border the neighbours may still not reach the edge of the area
+    if step > 1 {
+        let north = cell_edge(lat_cell + 2, step, MIN_LATITUDE, LATITUDE_RANGE);
+        let south = cell_edge(lat_cell - 1, step, MIN_LATITUDE, LATITUDE_RANGE);
+        let east = cell_edge(lon_cell + 2, step, MIN_LONGITUDE, LONGITUDE_RANGE);
+        let west = cell_edge(lon_cell - 1, step, MIN_LONGITUDE, LONGITUDE_RANGE);
+        if north < max_lat || south > min_lat || east < max_lon || west > min_lon {
+            step -= 1;
+            (lat_cell, lon_cell) = geohash_cell(center, step);
+        }
+    }
+
+    let cells = 1i64 << step;
+    let area_min_lat = cell_edge(lat_cell, step, MIN_LATITUDE, LATITUDE_RANGE);
+    let area_max_lat = cell_edge(lat_cell + 1, step, MIN_LATITUDE, LATITUDE_RANGE);
+    let area_min_lon = cell_edge(lon_cell, step, MIN_LONGITUDE, LONGITUDE_RANGE);
+    let area_max_lon = cell_edge(lon_cell + 1, step, MIN_LONGITUDE, LONGITUDE_RANGE);
+
+    let mut covered = Vec::with_capacity(9);
+    for lat_offset in -1..=1 {
+        for lon_offset in -1..=1 {
+            // skip neighbours on a side the center cell already spans
+            if step >= 2 && ((lat_offset == -1 && area_min_lat < min_lat)
+                || (lat_offset == 1 && area_max_lat > max_lat)
+                || (lon_offset == -1 && area_min_lon < min_lon)
+                || (lon_offset == 1 && area_max_lon > max_lon)) {
+                continue;
+            }
+            let lat = lat_cell + lat_offset;
+            if lat < 0 || lat >= cells {
+                continue;
+            }
+            let lon = (lon_cell + lon_offset).rem_euclid(cells);
+            if !covered.contains(&(lat, lon)) {
+                covered.push((lat, lon));
+            }
+        }
+    }
+
+    let shift = 2 * (GEO_STEP_MAX - step);
+    covered.into_iter()
+        .map(|(lat, lon)| {
+            let hash = interleave(lat as u32, lon as u32);
+            (hash << shift, (hash + 1) << shift)
+        })
+        .collect()
+}
+